@@ -3,31 +3,35 @@ pub mod trow_proto {
 }
 
 use std::convert::TryInto;
-use std::io::SeekFrom;
+use std::io::{self, SeekFrom};
 
 use anyhow::{anyhow, Result};
 use axum::extract::BodyStream;
+use bytes::Bytes;
 use chrono::TimeZone;
 use futures::StreamExt;
 use k8s_openapi::api::core::v1::Pod;
 use kube::core::admission::{AdmissionRequest, AdmissionResponse};
 use thiserror::Error;
 use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::StreamReader;
 use tonic::{Code, Request};
 use tracing::{event, Level};
 use trow_proto::admission_controller_client::AdmissionControllerClient;
 use trow_proto::registry_client::RegistryClient;
 use trow_proto::{
-    BlobRef, CatalogRequest, CompleteRequest, HealthRequest, ListTagsRequest,
-    ManifestHistoryRequest, ManifestRef, MetricsRequest, ReadinessRequest, UploadRef,
+    BlobRef, CatalogRequest, CompleteRequest, GarbageCollectionRequest, HealthRequest,
+    ListTagsRequest, ManifestHistoryRequest, ManifestReadLocation, ManifestRef, MetricsRequest,
+    MountRequest, ReadinessRequest, ReferrersRequest, RetentionRequest, UploadProgress, UploadRef,
     UploadRequest, VerifyManifestRequest,
 };
 
 use crate::registry_interface::blob_storage::Stored;
-use crate::registry_interface::digest::{self, Digest, DigestAlgorithm};
+use crate::registry_interface::digest::{self, Digest};
 use crate::registry_interface::{
     AdmissionValidation, BlobReader, BlobStorage, CatalogOperations, ContentInfo, ManifestHistory,
-    ManifestReader, ManifestStorage, Metrics, MetricsError, MetricsResponse, StorageDriverError,
+    ManifestInfo, ManifestReader, ManifestStorage, Metrics, MetricsError, MetricsResponse,
+    Referrer, StorageDriverError, StoredManifest,
 };
 use crate::types::{self, *};
 
@@ -64,8 +68,12 @@ fn extract_images(pod: &Pod) -> (Vec<String>, Vec<String>) {
 pub enum RegistryError {
     #[error("Invalid repository or tag")]
     InvalidName,
-    #[error("Invalid manifest")]
-    InvalidManifest,
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+    #[error("Manifest references unknown blob {0}")]
+    ManifestBlobUnknown(String),
+    #[error("Denied: {0}")]
+    Denied(String),
     #[error("Internal registry error")]
     Internal,
 }
@@ -91,38 +99,116 @@ impl ManifestStorage for ClientInterface {
         name: &str,
         tag: &str,
         data: BodyStream,
-    ) -> Result<Digest, StorageDriverError> {
+    ) -> Result<StoredManifest, StorageDriverError> {
         let repo = RepoName(name.to_string());
 
         match self.upload_manifest(&repo, tag, data).await {
-            Ok(vm) => Ok(vm.digest().clone()),
+            Ok(vm) => Ok(StoredManifest {
+                digest: vm.digest().clone(),
+                subject: vm.subject().cloned(),
+            }),
             Err(RegistryError::InvalidName) => {
                 Err(StorageDriverError::InvalidName(format!("{}:{}", name, tag)))
             }
-            Err(RegistryError::InvalidManifest) => Err(StorageDriverError::InvalidManifest),
+            Err(RegistryError::InvalidManifest(reason)) => {
+                Err(StorageDriverError::ManifestRejected(reason))
+            }
+            Err(RegistryError::ManifestBlobUnknown(digest)) => {
+                Err(StorageDriverError::ManifestBlobUnknown(digest))
+            }
+            Err(RegistryError::Denied(reason)) => Err(StorageDriverError::Denied(reason)),
             Err(_) => Err(StorageDriverError::Internal),
         }
     }
 
     async fn delete_manifest(&self, name: &str, digest: &Digest) -> Result<(), StorageDriverError> {
         let repo = RepoName(name.to_string());
-        self.delete_by_manifest(&repo, digest).await.map_err(|e| {
-            let e = e.downcast::<tonic::Status>();
-            if let Ok(ts) = e {
-                match ts.code() {
-                    Code::InvalidArgument => StorageDriverError::Unsupported,
+        self.delete_by_manifest(&repo, &digest.to_string())
+            .await
+            .map_err(|e| {
+                let e = e.downcast::<tonic::Status>();
+                if let Ok(ts) = e {
+                    match ts.code() {
+                        Code::InvalidArgument => StorageDriverError::Unsupported,
+                        Code::NotFound => StorageDriverError::InvalidManifest,
+                        Code::PermissionDenied => {
+                            StorageDriverError::Denied(ts.message().to_string())
+                        }
+                        _ => StorageDriverError::Internal,
+                    }
+                } else {
+                    StorageDriverError::Internal
+                }
+            })?;
+        Ok(())
+    }
+
+    async fn delete_tag(&self, name: &str, tag: &str) -> Result<(), StorageDriverError> {
+        let repo = RepoName(name.to_string());
+        self.delete_by_manifest(&repo, tag).await.map_err(|e| {
+            match e.downcast::<tonic::Status>() {
+                Ok(ts) => match ts.code() {
+                    Code::InvalidArgument => StorageDriverError::InvalidName(tag.to_string()),
                     Code::NotFound => StorageDriverError::InvalidManifest,
+                    Code::PermissionDenied => StorageDriverError::Denied(ts.message().to_string()),
                     _ => StorageDriverError::Internal,
-                }
-            } else {
-                StorageDriverError::Internal
+                },
+                Err(_) => StorageDriverError::Internal,
             }
         })?;
         Ok(())
     }
 
-    async fn has_manifest(&self, _name: &str, _algo: &DigestAlgorithm, _reference: &str) -> bool {
-        todo!()
+    async fn get_referrers(
+        &self,
+        name: &str,
+        digest: &Digest,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Referrer>, StorageDriverError> {
+        self.list_referrers(name, &digest.to_string(), artifact_type.unwrap_or_default())
+            .await
+            .map_err(|e| {
+                event!(Level::ERROR, "Failed to list referrers: {:?}", e);
+                StorageDriverError::Internal
+            })
+    }
+
+    async fn head_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ManifestInfo, StorageDriverError> {
+        let mr = ManifestRef {
+            reference: reference.to_owned(),
+            repo_name: name.to_string(),
+        };
+        let loc = self
+            .connect_registry()
+            .await
+            .map_err(|e| {
+                event!(Level::ERROR, "Failed to connect to registry: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .head_manifest(Request::new(mr))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::InvalidManifest,
+                _ => {
+                    event!(Level::ERROR, "Failed to look up manifest: {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+        let digest = digest::parse(&loc.digest).map_err(|e| {
+            event!(Level::ERROR, "Invalid manifest digest: {}", e);
+            StorageDriverError::Internal
+        })?;
+        // Proxied manifests that haven't been downloaded may not have a known size
+        Ok(ManifestInfo {
+            content_type: loc.content_type,
+            digest,
+            size: Some(loc.size).filter(|&size| size > 0),
+        })
     }
 }
 
@@ -211,9 +297,13 @@ impl BlobStorage for ClientInterface {
             }
         }
 
+        // The data is stored, the recorded progress is only informational
+        if let Err(e) = self.record_upload_progress(name, session_id, total).await {
+            event!(Level::WARN, "Failed to record upload progress {:?}", e);
+        }
+
         Ok(Stored {
             total_stored: total,
-            chunk: chunk_size,
         })
     }
 
@@ -228,6 +318,7 @@ impl BlobStorage for ClientInterface {
             .map_err(|e| match e.downcast::<tonic::Status>() {
                 Ok(ts) => match ts.code() {
                     Code::InvalidArgument => StorageDriverError::InvalidDigest,
+                    Code::ResourceExhausted => StorageDriverError::Denied(ts.message().to_string()),
                     _ => StorageDriverError::Internal,
                 },
                 Err(e) => {
@@ -238,15 +329,35 @@ impl BlobStorage for ClientInterface {
         Ok(())
     }
 
-    async fn start_blob_upload(&self, name: &str) -> Result<String, StorageDriverError> {
-        self.request_upload(name).await.map_err(|e| {
+    async fn mount_blob(
+        &self,
+        name: &str,
+        from: &str,
+        digest: &Digest,
+    ) -> Result<(), StorageDriverError> {
+        self.mount_blob_from(name, from, digest).await.map_err(|e| {
             match e.downcast::<tonic::Status>().map(|s| s.code()) {
                 Ok(Code::InvalidArgument) => StorageDriverError::InvalidName(name.to_string()),
+                Ok(Code::NotFound) => StorageDriverError::InvalidDigest,
                 _ => StorageDriverError::Internal,
             }
         })
     }
 
+    async fn start_blob_upload(&self, name: &str) -> Result<String, StorageDriverError> {
+        self.request_upload(name)
+            .await
+            .map_err(|e| match e.downcast::<tonic::Status>() {
+                Ok(ts) if ts.code() == Code::InvalidArgument => {
+                    StorageDriverError::InvalidName(name.to_string())
+                }
+                Ok(ts) if ts.code() == Code::ResourceExhausted => {
+                    StorageDriverError::Denied(ts.message().to_string())
+                }
+                _ => StorageDriverError::Internal,
+            })
+    }
+
     async fn delete_blob(&self, name: &str, digest: &Digest) -> Result<(), StorageDriverError> {
         event!(
             Level::INFO,
@@ -256,30 +367,64 @@ impl BlobStorage for ClientInterface {
         );
         let rn = RepoName(name.to_string());
 
-        self.delete_blob_local(&rn, digest)
-            .await
-            .map_err(|_| StorageDriverError::InvalidDigest)?;
+        self.delete_blob_local(&rn, digest).await.map_err(|e| {
+            match e.downcast::<tonic::Status>() {
+                Ok(ts) if ts.code() == Code::FailedPrecondition => {
+                    StorageDriverError::BlobReferenced(ts.message().to_string())
+                }
+                _ => StorageDriverError::InvalidDigest,
+            }
+        })?;
         Ok(())
     }
 
     async fn status_blob_upload(
         &self,
-        _name: &str,
-        _session_id: &str,
-    ) -> crate::registry_interface::UploadInfo {
-        todo!()
+        name: &str,
+        session_id: &str,
+    ) -> Result<crate::registry_interface::UploadInfo, StorageDriverError> {
+        let uploaded = self
+            .get_upload_status(name, session_id)
+            .await
+            .map_err(|e| match e.downcast::<tonic::Status>().map(|s| s.code()) {
+                Ok(Code::NotFound) => StorageDriverError::UnknownUpload(session_id.to_string()),
+                _ => StorageDriverError::Internal,
+            })?;
+        Ok(crate::registry_interface::UploadInfo::new(
+            name.to_string(),
+            session_id.to_string(),
+            uploaded,
+        ))
     }
 
     async fn cancel_blob_upload(
         &self,
-        _name: &str,
-        _session_id: &str,
+        name: &str,
+        session_id: &str,
     ) -> Result<(), StorageDriverError> {
-        todo!()
+        self.cancel_upload(name, session_id).await.map_err(|e| {
+            match e.downcast::<tonic::Status>().map(|s| s.code()) {
+                Ok(Code::NotFound) => StorageDriverError::UnknownUpload(session_id.to_string()),
+                _ => StorageDriverError::Internal,
+            }
+        })
     }
 
-    async fn has_blob(&self, _name: &str, _digest: &Digest) -> bool {
-        todo!()
+    async fn head_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<BlobReader, StorageDriverError> {
+        let rn = RepoName(name.to_string());
+        let size = self.get_blob_size(&rn, digest).await.map_err(|e| {
+            event!(Level::WARN, "Error getting blob: {}", e);
+            StorageDriverError::Internal
+        })?;
+        Ok(BlobReader::from_stream(
+            digest.clone(),
+            tokio::io::empty(),
+            size,
+        ))
     }
 }
 
@@ -413,6 +558,90 @@ impl ClientInterface {
         Ok(response.uuid)
     }
 
+    async fn mount_blob_from(
+        &self,
+        repo_name: &str,
+        from_repo: &str,
+        digest: &Digest,
+    ) -> Result<()> {
+        event!(
+            Level::INFO,
+            "Mounting blob {} from {} into {}",
+            digest,
+            from_repo,
+            repo_name
+        );
+        let req = MountRequest {
+            repo_name: repo_name.to_string(),
+            from_repo: from_repo.to_string(),
+            digest: digest.to_string(),
+        };
+
+        self.connect_registry()
+            .await?
+            .mount_blob(Request::new(req))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_upload_status(&self, repo_name: &str, uuid: &str) -> Result<u64> {
+        event!(
+            Level::DEBUG,
+            "Getting status of upload {} in repository {}",
+            uuid,
+            repo_name
+        );
+        let req = UploadRef {
+            repo_name: repo_name.to_string(),
+            uuid: uuid.to_string(),
+        };
+
+        let resp = self
+            .connect_registry()
+            .await?
+            .get_upload_status(Request::new(req))
+            .await?
+            .into_inner();
+
+        Ok(resp.size)
+    }
+
+    async fn record_upload_progress(&self, repo_name: &str, uuid: &str, size: u64) -> Result<()> {
+        let req = UploadProgress {
+            repo_name: repo_name.to_string(),
+            uuid: uuid.to_string(),
+            size,
+        };
+
+        self.connect_registry()
+            .await?
+            .record_upload_progress(Request::new(req))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, repo_name: &str, uuid: &str) -> Result<()> {
+        event!(
+            Level::INFO,
+            "Cancelling upload {} in repository {}",
+            uuid,
+            repo_name
+        );
+        let req = UploadRef {
+            repo_name: repo_name.to_string(),
+            uuid: uuid.to_string(),
+        };
+
+        self.connect_registry()
+            .await?
+            .cancel_upload(Request::new(req))
+            .await?;
+
+        Ok(())
+    }
+
     async fn complete_upload(&self, repo_name: &str, uuid: &str, digest: &Digest) -> Result<()> {
         event!(
             Level::INFO,
@@ -511,7 +740,15 @@ impl ClientInterface {
                 let e = e.downcast::<tonic::Status>();
                 if let Ok(ts) = e {
                     match ts.code() {
-                        Code::InvalidArgument => RegistryError::InvalidManifest,
+                        Code::InvalidArgument => {
+                            RegistryError::InvalidManifest(ts.message().to_string())
+                        }
+                        Code::NotFound => {
+                            RegistryError::ManifestBlobUnknown(ts.message().to_string())
+                        }
+                        Code::PermissionDenied | Code::ResourceExhausted => {
+                            RegistryError::Denied(ts.message().to_string())
+                        }
                         _ => RegistryError::Internal,
                     }
                 } else {
@@ -548,6 +785,7 @@ impl ClientInterface {
         let file = tokio::fs::OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(resp.path)
             .await?;
         Ok((file, resp.uuid))
@@ -564,6 +802,23 @@ impl ClientInterface {
             repo_name,
             reference
         );
+        let resp = self
+            .get_read_location_for_manifest(repo_name, reference)
+            .await?;
+
+        //For the moment we know it's a file location
+        let file = tokio::fs::File::open(resp.path).await?;
+        let digest = digest::parse(&resp.digest)?;
+        let mr = ManifestReader::new(resp.content_type, digest, file).await?;
+        Ok(mr)
+    }
+
+    /// Looks up where a manifest is stored. Proxied manifests are fetched if needed.
+    async fn get_read_location_for_manifest(
+        &self,
+        repo_name: &RepoName,
+        reference: &str,
+    ) -> Result<ManifestReadLocation> {
         let mr = ManifestRef {
             reference: reference.to_owned(),
             repo_name: repo_name.0.clone(),
@@ -574,12 +829,7 @@ impl ClientInterface {
             .get_read_location_for_manifest(Request::new(mr))
             .await?
             .into_inner();
-
-        //For the moment we know it's a file location
-        let file = tokio::fs::File::open(resp.path).await?;
-        let digest = digest::parse(&resp.digest)?;
-        let mr = ManifestReader::new(resp.content_type, digest, file).await?;
-        Ok(mr)
+        Ok(resp)
     }
 
     async fn get_manifest_history(
@@ -630,6 +880,44 @@ impl ClientInterface {
         Ok(history)
     }
 
+    async fn list_referrers(
+        &self,
+        repo_name: &str,
+        digest: &str,
+        artifact_type: &str,
+    ) -> Result<Vec<Referrer>> {
+        event!(
+            Level::INFO,
+            "Getting referrers of {} in {} (artifact type {:?})",
+            digest,
+            repo_name,
+            artifact_type
+        );
+        let req = ReferrersRequest {
+            repo_name: repo_name.to_string(),
+            digest: digest.to_string(),
+            artifact_type: artifact_type.to_string(),
+        };
+        let resp = self
+            .connect_registry()
+            .await?
+            .get_referrers(Request::new(req))
+            .await?
+            .into_inner();
+
+        Ok(resp
+            .referrers
+            .into_iter()
+            .map(|r| Referrer {
+                media_type: r.media_type,
+                digest: r.digest,
+                size: r.size,
+                artifact_type: Some(r.artifact_type).filter(|t| !t.is_empty()),
+                annotations: r.annotations.into_iter().collect(),
+            })
+            .collect())
+    }
+
     async fn get_reader_for_blob(
         &self,
         repo_name: &RepoName,
@@ -653,12 +941,64 @@ impl ClientInterface {
             .await?
             .into_inner();
 
+        if resp.fetch_upstream {
+            return self.stream_proxied_blob(repo_name, digest).await;
+        }
         //For the moment we know it's a file location
         let file = tokio::fs::File::open(resp.path).await?;
         let reader = BlobReader::new(digest.clone(), file).await;
         Ok(reader)
     }
 
+    /// Looks up a blob without reading it, so proxied blobs aren't fetched from upstream.
+    /// Returns the size of the blob, if known.
+    async fn get_blob_size(&self, repo_name: &RepoName, digest: &Digest) -> Result<Option<u64>> {
+        let br = BlobRef {
+            digest: digest.to_string(),
+            repo_name: repo_name.0.clone(),
+        };
+
+        let resp = self
+            .connect_registry()
+            .await?
+            .get_read_location_for_blob(Request::new(br))
+            .await?
+            .into_inner();
+
+        // Proxied blobs that haven't been fetched may not have a known size
+        Ok(Some(resp.size).filter(|&size| size > 0 || !resp.fetch_upstream))
+    }
+
+    /// Reads a blob of a proxied image while the backend fetches it from upstream
+    async fn stream_proxied_blob(
+        &self,
+        repo_name: &RepoName,
+        digest: &Digest,
+    ) -> Result<BlobReader> {
+        let br = BlobRef {
+            digest: digest.to_string(),
+            repo_name: repo_name.0.clone(),
+        };
+        let mut stream = self
+            .connect_registry()
+            .await?
+            .stream_proxied_blob(Request::new(br))
+            .await?
+            .into_inner();
+        // The first chunk only holds the size
+        let size = match stream.message().await? {
+            Some(chunk) if chunk.size > 0 => Some(chunk.size),
+            Some(_) => None,
+            None => return Err(anyhow!("No response streaming blob {}", digest)),
+        };
+        let data = stream.map(|chunk| chunk.map(|c| Bytes::from(c.data)).map_err(io::Error::other));
+        Ok(BlobReader::from_stream(
+            digest.clone(),
+            StreamReader::new(data),
+            size,
+        ))
+    }
+
     async fn delete_blob_local(
         &self,
         repo_name: &RepoName,
@@ -712,23 +1052,29 @@ impl ClientInterface {
             .into_inner();
 
         let digest = digest::parse(&resp.digest)?;
-        let vm = VerifiedManifest::new(None, repo_name.clone(), digest, reference.to_string());
+        let subject = match resp.subject.as_str() {
+            "" => None,
+            s => Some(digest::parse(s)?),
+        };
+        let vm = VerifiedManifest::new(None, repo_name.clone(), digest, reference.to_string())
+            .with_subject(subject);
         Ok(vm)
     }
 
+    /// Deletes by digest or by tag
     async fn delete_by_manifest(
         &self,
         repo_name: &RepoName,
-        digest: &Digest,
+        reference: &str,
     ) -> Result<ManifestDeleted> {
         event!(
             Level::INFO,
             "Attempting to delete manifest {} in {}",
-            digest,
+            reference,
             repo_name
         );
         let mr = ManifestRef {
-            reference: digest.to_string(),
+            reference: reference.to_string(),
             repo_name: repo_name.0.clone(),
         };
 
@@ -976,4 +1322,74 @@ impl ClientInterface {
             metrics: resp.metrics,
         })
     }
+
+    /**
+     Garbage collection.
+
+     Deletes unreferenced blobs (unless `dry_run` is set) and returns what was found.
+    */
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<GarbageCollectionReport> {
+        event!(Level::INFO, "Collecting garbage (dry run: {})", dry_run);
+        let req = Request::new(GarbageCollectionRequest { dry_run });
+        let resp = self
+            .connect_registry()
+            .await?
+            .collect_garbage(req)
+            .await?
+            .into_inner();
+
+        Ok(garbage_collection_report(resp))
+    }
+
+    /**
+     Retention policies.
+
+     Deletes the tags expired by the configured retention policies (unless `dry_run` is set) and
+     returns them.
+    */
+    pub async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport> {
+        event!(
+            Level::INFO,
+            "Enforcing retention policies (dry run: {})",
+            dry_run
+        );
+        let req = Request::new(RetentionRequest { dry_run });
+        let resp = self
+            .connect_registry()
+            .await?
+            .enforce_retention(req)
+            .await?
+            .into_inner();
+
+        Ok(RetentionReport {
+            tags: resp.tags.into_iter().map(expired_tag).collect(),
+            dry_run: resp.dry_run,
+            garbage: resp.garbage.map(garbage_collection_report),
+            protected: resp.protected.into_iter().map(expired_tag).collect(),
+        })
+    }
+}
+
+fn expired_tag(tag: trow_proto::ExpiredTag) -> ExpiredTag {
+    ExpiredTag {
+        repo_name: tag.repo_name,
+        tag: tag.tag,
+        digest: tag.digest,
+        last_pushed: tag.last_pushed,
+    }
+}
+
+fn garbage_collection_report(resp: trow_proto::GarbageCollectionReport) -> GarbageCollectionReport {
+    GarbageCollectionReport {
+        blobs: resp
+            .blobs
+            .into_iter()
+            .map(|b| CollectedBlob {
+                digest: b.digest,
+                size: b.size,
+            })
+            .collect(),
+        total_bytes: resp.total_bytes,
+        dry_run: resp.dry_run,
+    }
 }
@@ -6,12 +6,11 @@ mod routes;
 pub mod types;
 
 mod registry_interface;
-#[cfg(feature = "sqlite")]
-mod users;
 
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{env, fs};
 
 use anyhow::{anyhow, Context, Result};
@@ -21,7 +20,10 @@ use client_interface::ClientInterface;
 use futures::Future;
 use thiserror::Error;
 use tracing::{event, Level};
-use trow_server::{ImageValidationConfig, RegistryProxiesConfig};
+use trow_server::{
+    parse_size, ImageValidationConfig, ImmutableTagsConfig, MediaTypeConfig, Quota, QuotaConfig,
+    RegistryProxiesConfig, RetentionConfig,
+};
 use uuid::Uuid;
 
 //TODO: Make this take a cause or description
@@ -64,6 +66,13 @@ pub struct TrowConfig {
     token_secret: String,
     user: Option<UserConfig>,
     cors: Option<Vec<String>>,
+    upload_ttl: Duration,
+    media_type_config: MediaTypeConfig,
+    immutable_tags: ImmutableTagsConfig,
+    quota_config: QuotaConfig,
+    min_free_space: u64,
+    metrics_repo_depth: usize,
+    retention_config: Option<RetentionConfig>,
 }
 
 #[derive(Clone, Debug)]
@@ -97,7 +106,14 @@ fn init_trow_server(
         config.grpc.listen.parse::<std::net::SocketAddr>()?,
         config.proxy_registry_config,
         config.image_validation_config,
-    );
+    )
+    .with_upload_ttl(config.upload_ttl)
+    .with_media_type_config(config.media_type_config)
+    .with_immutable_tags(config.immutable_tags)
+    .with_quota_config(config.quota_config)
+    .with_min_free_space(config.min_free_space)
+    .with_metrics_repo_depth(config.metrics_repo_depth)
+    .with_retention_config(config.retention_config);
     //TODO: probably shouldn't be reusing this cert
     let ts = if let Some(tls) = config.tls {
         ts.add_tls(fs::read(tls.cert_file)?, fs::read(tls.key_file)?)
@@ -134,6 +150,13 @@ impl TrowBuilder {
             token_secret: Uuid::new_v4().to_string(),
             user: None,
             cors,
+            upload_ttl: trow_server::DEFAULT_UPLOAD_TTL,
+            media_type_config: MediaTypeConfig::default(),
+            immutable_tags: ImmutableTagsConfig::default(),
+            quota_config: QuotaConfig::default(),
+            min_free_space: 0,
+            metrics_repo_depth: 0,
+            retention_config: None,
         };
         TrowBuilder { config }
     }
@@ -158,6 +181,19 @@ impl TrowBuilder {
         Ok(self)
     }
 
+    pub fn with_retention_policies(&mut self, config_file: impl AsRef<str>) -> Result<&mut Self> {
+        let config_file = config_file.as_ref();
+        let config_str = fs::read_to_string(config_file)
+            .with_context(|| format!("Could not read file `{}`", config_file))?;
+        let config = serde_yaml::from_str::<RetentionConfig>(&config_str)
+            .with_context(|| format!("Could not parse file `{}`", config_file))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid retention policies in `{}`", config_file))?;
+        self.config.retention_config = Some(config);
+        Ok(self)
+    }
+
     pub fn with_tls(&mut self, cert_file: String, key_file: String) -> &mut TrowBuilder {
         let cfg = TlsConfig {
             cert_file,
@@ -167,6 +203,51 @@ impl TrowBuilder {
         self
     }
 
+    pub fn with_upload_ttl(&mut self, upload_ttl: Duration) -> &mut TrowBuilder {
+        self.config.upload_ttl = upload_ttl;
+        self
+    }
+
+    /// Only accept config blobs and layers of these media types. An empty list allows any.
+    pub fn with_media_types(
+        &mut self,
+        config: Vec<String>,
+        layers: Vec<String>,
+    ) -> &mut TrowBuilder {
+        self.config.media_type_config = MediaTypeConfig { config, layers };
+        self
+    }
+
+    /// Tags matching these `<repository>:<tag>` patterns can't be moved once pushed
+    pub fn with_immutable_tags(&mut self, patterns: Vec<String>) -> &mut TrowBuilder {
+        self.config.immutable_tags = ImmutableTagsConfig { patterns };
+        self
+    }
+
+    /// Limits the storage used by namespaces, given as `<namespace>=<size>`
+    pub fn with_quotas(&mut self, quotas: Vec<String>) -> Result<&mut Self> {
+        let quotas = quotas
+            .iter()
+            .map(|q| Quota::parse(q))
+            .collect::<Result<_>>()?;
+        self.config.quota_config = QuotaConfig { quotas };
+        Ok(self)
+    }
+
+    /// Refuses new uploads and proxy downloads, and reports not ready, when less disk space than
+    /// `size` is available
+    pub fn with_min_free_space(&mut self, size: &str) -> Result<&mut Self> {
+        self.config.min_free_space = parse_size(size)?;
+        Ok(self)
+    }
+
+    /// Truncates the repository names used as metric labels to `depth` path components, to limit
+    /// the number of time series (0 keeps full names)
+    pub fn with_metrics_repo_depth(&mut self, depth: usize) -> &mut TrowBuilder {
+        self.config.metrics_repo_depth = depth;
+        self
+    }
+
     pub fn with_user(&mut self, user: String, pass: String) -> &mut TrowBuilder {
         let hash_config = argon2::Config::default();
         let hash_encoded =
@@ -2,11 +2,14 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::time::Duration;
 
 use clap::builder::ArgPredicate;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use trow::TrowBuilder;
 
+const GRPC_LISTEN: &str = "127.0.0.1:51000";
+
 #[derive(Parser, Debug)]
 #[command(name = "Trow")]
 #[command(about = "The Cluster Registry")]
@@ -72,9 +75,139 @@ struct Args {
     #[arg(long)]
     proxy_registry_config_file: Option<String>,
 
+    /// Load a YAML file containing tag retention policies, which are enforced periodically.
+    #[arg(long)]
+    retention_config_file: Option<String>,
+
     /// Enable Cross-Origin Resource Sharing(CORS) requests.
     #[arg(long, value_delimiter(','))]
     cors: Option<Vec<String>>,
+
+    /// Delete uploads that haven't received any data for this many seconds, along with other
+    /// stale temporary files. Must be at least 300.
+    #[arg(
+        long,
+        default_value_t = trow_server::DEFAULT_UPLOAD_TTL.as_secs(),
+        value_parser = clap::value_parser!(u64).range(trow_server::MIN_UPLOAD_TTL.as_secs()..)
+    )]
+    upload_ttl: u64,
+
+    /// Only accept manifests whose config blob, and artifactType if any, has one of these media
+    /// types, e.g. to only allow images and Helm charts. An entry ending in `*` matches any media
+    /// type with that prefix.
+    #[arg(long, value_delimiter(','))]
+    allowed_config_media_types: Vec<String>,
+
+    /// Only accept manifests whose layers have one of these media types.
+    /// An entry ending in `*` matches any media type with that prefix.
+    #[arg(long, value_delimiter(','))]
+    allowed_layer_media_types: Vec<String>,
+
+    /// Tags that can't be moved to another digest once pushed, as `<repository>:<tag>` patterns
+    /// where `*` matches anything, e.g. `prod/*:v*`.
+    #[arg(long, value_delimiter(','))]
+    immutable_tags: Vec<String>,
+
+    /// Limit the storage used by namespaces, as `<namespace>=<size>` where the size can have a
+    /// K, M, G or T suffix, e.g. `team-a=10G`. A namespace covers the repositories under it.
+    #[arg(long, value_delimiter(','))]
+    quotas: Vec<String>,
+
+    /// Refuse new uploads and proxy downloads, and report not ready, when less disk space is
+    /// available, e.g. `5G`.
+    #[arg(long)]
+    min_free_space: Option<String>,
+
+    /// Truncate the repository names used as metric labels to this many path components, to
+    /// limit the number of time series. 0 keeps full names.
+    #[arg(long, default_value_t = 0)]
+    metrics_repo_depth: usize,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Delete blobs that aren't referenced by any manifest in a running Trow instance.
+    Gc {
+        /// Don't delete anything, just report what would be deleted.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Delete the tags expired by the retention policies of a running Trow instance.
+    Retention {
+        /// Don't delete anything, just report what would be deleted.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
+async fn collect_garbage(dry_run: bool) {
+    let report = trow::build_handlers(format!("https://{}", GRPC_LISTEN))
+        .unwrap()
+        .collect_garbage(dry_run)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to collect garbage: {:#}", e);
+            std::process::exit(1);
+        });
+    print_garbage_collection_report(&report);
+}
+
+async fn enforce_retention(dry_run: bool) {
+    let report = trow::build_handlers(format!("https://{}", GRPC_LISTEN))
+        .unwrap()
+        .enforce_retention(dry_run)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to enforce retention policies: {:#}", e);
+            std::process::exit(1);
+        });
+
+    let verb = if report.dry_run {
+        "Would delete"
+    } else {
+        "Deleted"
+    };
+    for tag in &report.tags {
+        println!(
+            "{} {}:{} ({}, last pushed {})",
+            verb, tag.repo_name, tag.tag, tag.digest, tag.last_pushed
+        );
+    }
+    println!("{} expired tags", report.tags.len());
+    for tag in &report.protected {
+        println!(
+            "Kept immutable {}:{} ({}, last pushed {})",
+            tag.repo_name, tag.tag, tag.digest, tag.last_pushed
+        );
+    }
+    if let Some(garbage) = &report.garbage {
+        print_garbage_collection_report(garbage);
+    }
+}
+
+fn print_garbage_collection_report(report: &trow::types::GarbageCollectionReport) {
+    let verb = if report.dry_run {
+        "Would delete"
+    } else {
+        "Deleted"
+    };
+    for blob in &report.blobs {
+        println!("{} {} ({} bytes)", verb, blob.digest, blob.size);
+    }
+    let summary = if report.dry_run {
+        "reclaimable"
+    } else {
+        "reclaimed"
+    };
+    println!(
+        "{} unreferenced blobs, {} bytes {}",
+        report.blobs.len(),
+        report.total_bytes,
+        summary
+    );
 }
 
 #[tokio::main]
@@ -83,17 +216,46 @@ async fn main() {
 
     let args = Args::parse();
 
+    match args.command {
+        Some(Command::Gc { dry_run }) => {
+            collect_garbage(dry_run).await;
+            return;
+        }
+        Some(Command::Retention { dry_run }) => {
+            enforce_retention(dry_run).await;
+            return;
+        }
+        None => {}
+    }
+
     let addr = SocketAddr::new(args.host, args.port);
     let host_name = args.name.unwrap_or(addr.to_string());
 
     let mut builder = TrowBuilder::new(
         args.data_dir.clone(),
         addr,
-        "127.0.0.1:51000".to_string(),
+        GRPC_LISTEN.to_string(),
         host_name,
         args.dry_run,
         args.cors,
     );
+    builder.with_upload_ttl(Duration::from_secs(args.upload_ttl));
+    builder.with_media_types(
+        args.allowed_config_media_types,
+        args.allowed_layer_media_types,
+    );
+    builder.with_immutable_tags(args.immutable_tags);
+    builder.with_metrics_repo_depth(args.metrics_repo_depth);
+    if let Err(e) = builder.with_quotas(args.quotas) {
+        eprintln!("Invalid quota: {:#}", e);
+        std::process::exit(1);
+    }
+    if let Some(size) = args.min_free_space {
+        if let Err(e) = builder.with_min_free_space(&size) {
+            eprintln!("Invalid minimum free space: {:#}", e);
+            std::process::exit(1);
+        }
+    }
     if let Some(tls) = args.tls {
         if tls.len() != 2 {
             eprintln!("tls must be a pair of paths, cert then key (got: {tls:?})");
@@ -133,6 +295,12 @@ async fn main() {
             std::process::exit(1);
         }
     }
+    if let Some(config_file) = args.retention_config_file {
+        if let Err(e) = builder.with_retention_policies(config_file) {
+            eprintln!("Failed to load retention config file: {:#}", e);
+            std::process::exit(1);
+        }
+    }
 
     builder.start().await.unwrap_or_else(|e| {
         eprintln!("Error launching Trow:\n\n{}", e);
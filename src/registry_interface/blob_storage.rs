@@ -1,29 +1,52 @@
+use std::pin::Pin;
+
 use axum::extract::BodyStream;
+use tokio::io::AsyncRead;
 
 use super::digest::Digest;
-use super::{AsyncSeekRead, StorageDriverError};
+use super::StorageDriverError;
 
 pub struct ContentInfo {
     pub length: u64,
     pub range: (u64, u64),
 }
 
-#[allow(dead_code)]
 pub struct UploadInfo {
     name: String,
     session_id: String,
-    uploaded: u32,
-    size: u32,
+    uploaded: u64,
 }
 
 pub struct BlobReader {
     digest: Digest,
-    reader: tokio::fs::File,
-    size: u64,
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    size: Option<u64>,
 }
 pub struct Stored {
     pub total_stored: u64,
-    pub chunk: u64,
+}
+
+impl UploadInfo {
+    pub fn new(name: String, session_id: String, uploaded: u64) -> Self {
+        Self {
+            name,
+            session_id,
+            uploaded,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Number of bytes received so far
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
 }
 
 impl BlobReader {
@@ -31,12 +54,25 @@ impl BlobReader {
         let file_size = file.metadata().await.unwrap().len();
         Self {
             digest,
-            reader: file,
-            size: file_size,
+            reader: Box::pin(file),
+            size: Some(file_size),
+        }
+    }
+
+    /// A blob that is read while it is being fetched, e.g. from a proxied registry
+    pub fn from_stream(
+        digest: Digest,
+        reader: impl AsyncRead + Send + 'static,
+        size: Option<u64>,
+    ) -> Self {
+        Self {
+            digest,
+            reader: Box::pin(reader),
+            size,
         }
     }
 
-    pub fn get_reader(self) -> impl AsyncSeekRead {
+    pub fn get_reader(self) -> impl AsyncRead + Send {
         self.reader
     }
 
@@ -44,7 +80,8 @@ impl BlobReader {
         &self.digest
     }
 
-    pub fn blob_size(&self) -> u64 {
+    /// The size of the blob, if known
+    pub fn blob_size(&self) -> Option<u64> {
         self.size
     }
 }
@@ -52,7 +89,6 @@ impl BlobReader {
 #[axum::async_trait]
 pub trait BlobStorage {
     /// Retrieve the blob from the registry identified by digest.
-    /// A HEAD request can also be issued to this endpoint to obtain resource information without receiving all data.
     /// GET: /v2/<name>/blobs/<digest>
     async fn get_blob(&self, name: &str, digest: &Digest)
         -> Result<BlobReader, StorageDriverError>;
@@ -61,6 +97,16 @@ pub trait BlobStorage {
     /// DELETE: /v2/<name>/blobs/<digest>
     async fn delete_blob(&self, name: &str, digest: &Digest) -> Result<(), StorageDriverError>;
 
+    /// Makes a blob that is readable from repository `from` available in repository `name`,
+    /// without uploading it again.
+    /// POST: /v2/<name>/blobs/uploads/?mount=<digest>&from=<from>
+    async fn mount_blob(
+        &self,
+        name: &str,
+        from: &str,
+        digest: &Digest,
+    ) -> Result<(), StorageDriverError>;
+
     /// Requests to start a resumable upload for the given repository.
     /// Returns a session identifier for the upload.
     async fn start_blob_upload(&self, name: &str) -> Result<String, StorageDriverError>;
@@ -68,7 +114,11 @@ pub trait BlobStorage {
     /// Retrieve status of upload identified by session_id.
     /// The primary purpose of this endpoint is to resolve the current status of a resumable upload.
     /// GET: /v2/<name>/blobs/uploads/<session_id>
-    async fn status_blob_upload(&self, name: &str, session_id: &str) -> UploadInfo;
+    async fn status_blob_upload(
+        &self,
+        name: &str,
+        session_id: &str,
+    ) -> Result<UploadInfo, StorageDriverError>;
 
     /// Upload a chunk of data for the specified upload.
     /// PATCH: /v2/<name>/blobs/uploads/<session_id>
@@ -108,7 +158,17 @@ pub trait BlobStorage {
         session_id: &str,
     ) -> Result<(), StorageDriverError>;
 
-    /// Whether the specific blob exists
-    /// AM: Assume this is for HEAD requests?
-    async fn has_blob(&self, name: &str, digest: &Digest) -> bool;
+    /// Checks the blob exists and is readable from the given repository, without reading it.
+    /// The returned reader is empty, only its digest and size are set.
+    /// HEAD: /v2/<name>/blobs/<digest>
+    async fn head_blob(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<BlobReader, StorageDriverError>;
+
+    /// Whether the specific blob exists and is readable from the given repository
+    async fn has_blob(&self, name: &str, digest: &Digest) -> bool {
+        self.head_blob(name, digest).await.is_ok()
+    }
 }
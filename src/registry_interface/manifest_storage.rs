@@ -1,8 +1,11 @@
+use std::collections::BTreeMap;
+
 use axum::extract::BodyStream;
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
 use tracing::{event, Level};
 
-use super::{AsyncSeekRead, Digest, DigestAlgorithm, StorageDriverError};
+use super::{AsyncSeekRead, Digest, StorageDriverError};
 
 pub struct ManifestReader {
     content_type: String,
@@ -49,13 +52,38 @@ impl ManifestReader {
     }
 }
 
+/// What a HEAD request says about a manifest, without its content
+pub struct ManifestInfo {
+    pub content_type: String,
+    pub digest: Digest,
+    /// Unknown for proxied manifests the upstream registry didn't give the size of
+    pub size: Option<u64>,
+}
+
+pub struct StoredManifest {
+    pub digest: Digest,
+    /// Digest of the manifest's `subject` field, if it has one
+    pub subject: Option<Digest>,
+}
+
+/// Descriptor of a manifest that refers to another one through its `subject` field
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Referrer {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
 // This trait handles all the necessary Manifest Operations (get, save delete)
 #[axum::async_trait]
 pub trait ManifestStorage {
     /// Fetch the manifest identified by name and reference where reference can be a tag or digest.
-    /// A HEAD request can also be issued to this endpoint to obtain resource information without receiving all data.
     /// GET: /v2/<name>/manifests/<reference>
-    /// HEAD: /v2/<name>/manifests/<reference>
     async fn get_manifest(
         &self,
         name: &str,
@@ -64,29 +92,49 @@ pub trait ManifestStorage {
 
     // Stores should take a reader that has the data, possibly a second method that returns byte array
 
-    /// TODO: DataStream is currently tied to Rocket implementation to handle transfers that get capped.
-    /// Fixing this means either changing the interface to return a sink the route can write to or coming up with a
-    /// new interface type.
+    // TODO: DataStream is currently tied to Rocket implementation to handle transfers that get capped.
+    // Fixing this means either changing the interface to return a sink the route can write to or coming up with a
+    // new interface type.
 
     /// Put the manifest identified by name and tag. (Note that manifests cannot be pushed by digest)
     /// data is a link to reader for supplying the bytes of the manifest.
-    /// Returns digest of the manifest.
+    /// Returns digest of the manifest, and of its subject if any.
     ///
     async fn store_manifest<'a>(
         &self,
         name: &str,
         tag: &str,
         data: BodyStream,
-    ) -> Result<Digest, StorageDriverError>;
+    ) -> Result<StoredManifest, StorageDriverError>;
 
     // Store a manifest via Writer trait for drivers which support it
     // AM: I think this was just for Trow, so we can remove, right?
     //fn store_manifest_with_writer(&self, name: &str, tag: &str) -> Result<Box<dyn Write>>;
 
-    /// Delete the manifest identified by name and reference. Note that a manifest can only be deleted by digest.
-    /// DELETE: /v2/<name>/manifests/<reference>
+    /// Delete the manifest identified by name and digest, along with every tag pointing to it.
+    /// DELETE: /v2/<name>/manifests/<digest>
     async fn delete_manifest(&self, name: &str, digest: &Digest) -> Result<(), StorageDriverError>;
 
-    /// Whether the specific manifest exists
-    async fn has_manifest(&self, name: &str, algo: &DigestAlgorithm, reference: &str) -> bool;
+    /// Delete a single tag and its history. Other tags pointing to the same manifest are kept.
+    /// DELETE: /v2/<name>/manifests/<tag>
+    async fn delete_tag(&self, name: &str, tag: &str) -> Result<(), StorageDriverError>;
+
+    /// List the manifests whose subject is the given manifest, optionally only those of the
+    /// given artifact type.
+    /// GET: /v2/<name>/referrers/<digest>
+    async fn get_referrers(
+        &self,
+        name: &str,
+        digest: &Digest,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Referrer>, StorageDriverError>;
+
+    /// Looks up the manifest identified by name and reference without reading it, so proxied
+    /// manifests aren't downloaded.
+    /// HEAD: /v2/<name>/manifests/<reference>
+    async fn head_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ManifestInfo, StorageDriverError>;
 }
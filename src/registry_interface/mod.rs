@@ -1,8 +1,10 @@
 pub use admission::AdmissionValidation;
 pub use blob_storage::{BlobReader, BlobStorage, ContentInfo, UploadInfo};
 pub use catalog_operations::{CatalogOperations, ManifestHistory};
-pub use digest::{Digest, DigestAlgorithm};
-pub use manifest_storage::{ManifestReader, ManifestStorage};
+pub use digest::Digest;
+pub use manifest_storage::{
+    ManifestInfo, ManifestReader, ManifestStorage, Referrer, StoredManifest,
+};
 pub use metrics::{Metrics, MetricsError, MetricsResponse};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncSeek};
@@ -22,12 +24,22 @@ pub enum StorageDriverError {
     InvalidName(String),
     #[error("manifest is not valid")]
     InvalidManifest,
+    #[error("manifest was rejected: {0}")]
+    ManifestRejected(String),
+    #[error("manifest references unknown blob `{0}`")]
+    ManifestBlobUnknown(String),
     #[error("Digest did not match content")]
     InvalidDigest,
     #[error("Unsupported Operation")]
     Unsupported,
     #[error("Requested index does not match actual")]
     InvalidContentRange,
+    #[error("no upload in progress with id `{0}`")]
+    UnknownUpload(String),
+    #[error("Operation denied: {0}")]
+    Denied(String),
+    #[error("blob is referenced: {0}")]
+    BlobReferenced(String),
     #[error("Internal storage error")]
     Internal,
 }
@@ -36,15 +48,3 @@ pub enum StorageDriverError {
 //I'd much rather not have to write an impl for every class :(
 pub trait AsyncSeekRead: AsyncRead + AsyncSeek + Send {}
 impl AsyncSeekRead for tokio::fs::File {}
-
-// Super trait
-pub trait RegistryStorage: ManifestStorage + BlobStorage + CatalogOperations {
-    /// Whether the specific name(space) exists
-    fn exists(&self, name: &str) -> Result<bool, StorageDriverError>;
-
-    /// Whether the driver supports processing of data chunks in a streaming mode
-    /// For example when the client uploads chunks of data, instead of buffering them
-    /// in memory and then passing the full data, the driver can process single chunks
-    /// individually. This significantly decrease the memory usage of the registry
-    fn support_streaming(&self) -> bool;
-}
@@ -32,7 +32,7 @@ mod test {
     use axum::http::StatusCode;
     use axum::response::IntoResponse;
 
-    use crate::registry_interface::{Digest, DigestAlgorithm};
+    use crate::registry_interface::digest::{Digest, DigestAlgorithm};
     use crate::types::{AcceptedUpload, RepoName, Uuid};
 
     #[tokio::test]
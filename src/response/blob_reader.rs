@@ -11,10 +11,13 @@ impl IntoResponse for BlobReader {
         let size = self.blob_size();
         let stream = FramedRead::new(self.get_reader(), BytesCodec::new());
 
-        Response::builder()
+        let mut builder = Response::builder()
             .header(header::CONTENT_TYPE, "application/octet-stream")
-            .header(header::CONTENT_LENGTH, size)
-            .header("Docker-Content-Digest", digest)
+            .header("Docker-Content-Digest", digest);
+        if let Some(size) = size {
+            builder = builder.header(header::CONTENT_LENGTH, size);
+        }
+        builder
             .body(body::StreamBody::new(stream))
             .unwrap()
             .into_response()
@@ -13,19 +13,18 @@ pub enum Error {
 
     BLOB_UPLOAD_UNKNOWN,
     DIGEST_INVALID,
-    MANIFEST_BLOB_UNKNOWN,
     ,
     MANIFEST_UNVERIFIED,
     NAME_UNKNOWN,
     SIZE_INVALID,
     TAG_INVALID,
     UNAUTHORIZED,
-    DENIED,
     */
     NameInvalid(String),
     BlobUploadInvalid(String),
     ManifestUnknown(String),
     ManifestInvalid(String),
+    ManifestBlobUnknown(String),
     Unauthorized,
     BlobUnknown,
     BlobUploadUnknown,
@@ -33,6 +32,9 @@ pub enum Error {
     InternalError,
     DigestInvalid,
     NotFound,
+    Denied(String),
+    /// A blob can't be deleted while manifests reference it
+    BlobReferenced(String),
 }
 
 // Create ErrorMsg struct that serializes to json of appropriate type
@@ -74,6 +76,12 @@ impl fmt::Display for Error {
                 "Manifest invalid",
                 Some(json!({ "detail": detail })),
             ),
+            Error::ManifestBlobUnknown(ref digest) => format_error_json(
+                f,
+                "MANIFEST_BLOB_UNKNOWN",
+                "Manifest references a manifest or blob unknown to the registry",
+                Some(json!({ "digest": digest })),
+            ),
             Error::ManifestUnknown(ref tag) => format_error_json(
                 f,
                 "MANIFEST_UNKNOWN",
@@ -87,6 +95,18 @@ impl fmt::Display for Error {
                 Some(json!({ "Repository": name })),
             ),
             Error::NotFound => format_error_json(f, "NOT_FOUND", "Not Found", None),
+            Error::Denied(ref reason) => format_error_json(
+                f,
+                "DENIED",
+                "Requested access to the resource is denied",
+                Some(json!({ "Reason": reason })),
+            ),
+            Error::BlobReferenced(ref reason) => format_error_json(
+                f,
+                "DENIED",
+                "Blob is referenced by manifests",
+                Some(json!({ "Reason": reason })),
+            ),
         }
     }
 }
@@ -121,9 +141,12 @@ impl error::Error for Error {
             Error::InternalError => "An internal error occurred, please consult the logs for more details.",
             Error::DigestInvalid => "When a blob is uploaded, the registry will check that the content matches the digest provided by the client. The error may include a detail structure with the key \"digest\", including the invalid digest string. This error may also be returned when a manifest includes an invalid layer digest.",
             Error::ManifestInvalid(_) => "During upload, manifests undergo several checks ensuring validity. If those checks fail, this error may be returned, unless a more specific error is included. The detail will contain information the failed validation.",
+            Error::ManifestBlobUnknown(_) => "This error may be returned when a manifest blob is unknown to the registry.",
             Error::ManifestUnknown(_) => "This error is returned when the manifest, identified by name and tag is unknown to the repository.",
             Error::NameInvalid(_) => "Invalid repository name encountered either during manifest validation or any API operation.",
             Error::NotFound => "The specified resource could not be found. This error may also occur if the client does not have permission to access the resource.",
+            Error::Denied(_) => "The access controller denied access for the operation on a resource.",
+            Error::BlobReferenced(_) => "The blob is still referenced by manifests, which should be deleted first.",
         }
     }
 }
@@ -140,9 +163,12 @@ impl IntoResponse for Error {
             Error::BlobUploadInvalid(_) => StatusCode::RANGE_NOT_SATISFIABLE,
             Error::DigestInvalid
             | Error::ManifestInvalid(_)
+            | Error::ManifestBlobUnknown(_)
             | Error::BlobUnknown
             | Error::NameInvalid(_) => StatusCode::BAD_REQUEST,
             Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Denied(_) => StatusCode::FORBIDDEN,
+            Error::BlobReferenced(_) => StatusCode::CONFLICT,
         };
         Response::builder()
             .header(header::CONTENT_TYPE, "application/json")
@@ -0,0 +1,17 @@
+use axum::body;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+
+use crate::registry_interface::ManifestInfo;
+
+impl IntoResponse for ManifestInfo {
+    fn into_response(self) -> Response {
+        let mut builder = Response::builder()
+            .header(header::CONTENT_TYPE, self.content_type)
+            .header("Docker-Content-Digest", self.digest.to_string());
+        if let Some(size) = self.size {
+            builder = builder.header(header::CONTENT_LENGTH, size);
+        }
+        builder.body(body::Empty::new()).unwrap().into_response()
+    }
+}
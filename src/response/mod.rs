@@ -14,13 +14,17 @@ pub mod health;
 pub mod html;
 pub mod manifest_deleted;
 pub mod manifest_history;
+pub mod manifest_info;
 pub mod manifest_reader;
 pub mod metrics;
+pub mod mounted_blob;
 pub mod readiness;
+pub mod referrers;
 pub mod repo_catalog;
 pub mod tag_list;
 pub mod trow_token;
 pub mod upload;
+pub mod upload_cancelled;
 pub mod upload_info;
 pub mod verified_manifest;
 
@@ -0,0 +1,56 @@
+use axum::body;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+use crate::types::MountedBlob;
+
+impl IntoResponse for MountedBlob {
+    fn into_response(self) -> Response {
+        let location = format!(
+            "{}/v2/{}/blobs/{}",
+            self.base_url(),
+            self.repo_name(),
+            self.digest()
+        );
+        Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Location", location)
+            .header("Docker-Content-Digest", self.digest().to_string())
+            .header("Content-Length", "0")
+            .body(body::Empty::new())
+            .unwrap()
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use crate::registry_interface::digest::{Digest, DigestAlgorithm};
+    use crate::types::{MountedBlob, RepoName};
+
+    #[tokio::test]
+    async fn test_resp() {
+        let mounted = MountedBlob::new(
+            "http://trowuw".to_string(),
+            Digest {
+                algo: DigestAlgorithm::Sha256,
+                hash: "05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"
+                    .to_string(),
+            },
+            RepoName("moredhel/test".to_owned()),
+        );
+
+        let response = mounted.into_response();
+
+        let headers = response.headers();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            headers.get("Location").unwrap(),
+            "http://trowuw/v2/moredhel/test/blobs/sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"
+        );
+        assert!(headers.contains_key("Docker-Content-Digest"));
+    }
+}
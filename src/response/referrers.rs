@@ -0,0 +1,39 @@
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+
+use crate::types::Referrers;
+
+impl IntoResponse for Referrers {
+    fn into_response(self) -> Response {
+        let json = serde_json::to_string(&self).unwrap();
+
+        let mut builder = Response::builder()
+            .header(header::CONTENT_TYPE, self.media_type())
+            .header(header::CONTENT_LENGTH, json.len());
+        if self.filtered() {
+            builder = builder.header("OCI-Filters-Applied", "artifactType");
+        }
+        builder.body(json).unwrap().into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use crate::types::Referrers;
+
+    #[test]
+    fn filters_header() {
+        let response = Referrers::new(vec![], false).into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("OCI-Filters-Applied").is_none());
+
+        let response = Referrers::new(vec![], true).into_response();
+        assert_eq!(
+            response.headers().get("OCI-Filters-Applied").unwrap(),
+            "artifactType"
+        );
+    }
+}
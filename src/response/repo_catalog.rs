@@ -8,11 +8,12 @@ impl IntoResponse for RepoCatalog {
     fn into_response(self) -> Response {
         let json = serde_json::to_string(&self).unwrap();
 
-        Response::builder()
+        let mut builder = Response::builder()
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::CONTENT_LENGTH, json.len())
-            .body(Body::from(json))
-            .unwrap()
-            .into_response()
+            .header(header::CONTENT_LENGTH, json.len());
+        if let Some(link) = self.next_link() {
+            builder = builder.header(header::LINK, format!("<{}>; rel=\"next\"", link));
+        }
+        builder.body(Body::from(json)).unwrap().into_response()
     }
 }
@@ -7,11 +7,12 @@ impl IntoResponse for TagList {
     fn into_response(self) -> Response {
         let json = serde_json::to_string(&self).unwrap();
 
-        Response::builder()
+        let mut builder = Response::builder()
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::CONTENT_LENGTH, json.len())
-            .body(json)
-            .unwrap()
-            .into_response()
+            .header(header::CONTENT_LENGTH, json.len());
+        if let Some(link) = self.next_link() {
+            builder = builder.header(header::LINK, format!("<{}>; rel=\"next\"", link));
+        }
+        builder.body(json).unwrap().into_response()
     }
 }
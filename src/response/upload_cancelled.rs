@@ -0,0 +1,10 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+use crate::types::UploadCancelled;
+
+impl IntoResponse for UploadCancelled {
+    fn into_response(self) -> Response {
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
@@ -59,4 +59,17 @@ mod test {
         assert!(headers.contains_key("Location"));
         assert!(headers.contains_key("Range"));
     }
+
+    #[test]
+    fn range_of_large_uploads() {
+        let size: u64 = 5 * 1024 * 1024 * 1024;
+        let response = UploadInfo::new(
+            "ftp://darpa.org".to_string(),
+            Uuid("whatever".to_owned()),
+            RepoName("moredhel/test".to_owned()),
+            (0, size - 1),
+        )
+        .into_response();
+        assert_eq!(response.headers()["Range"], "0-5368709119");
+    }
 }
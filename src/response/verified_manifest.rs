@@ -13,9 +13,14 @@ impl IntoResponse for VerifiedManifest {
             self.repo_name(),
             self.tag()
         );
-        Response::builder()
+        let mut builder = Response::builder()
             .header("Location", location)
-            .header("Docker-Content-Digest", self.digest().to_string())
+            .header("Docker-Content-Digest", self.digest().to_string());
+        // Tells clients the registry supports the referrers API
+        if let Some(subject) = self.subject() {
+            builder = builder.header("OCI-Subject", subject.to_string());
+        }
+        builder
             .status(StatusCode::CREATED)
             .body(body::Empty::new())
             .unwrap()
@@ -28,7 +33,7 @@ mod test {
     use axum::http::StatusCode;
     use axum::response::IntoResponse;
 
-    use crate::registry_interface::{Digest, DigestAlgorithm};
+    use crate::registry_interface::digest::{Digest, DigestAlgorithm};
     use crate::types::{RepoName, VerifiedManifest};
 
     #[test]
@@ -3,6 +3,7 @@ use std::sync::Arc;
 use anyhow::Result;
 use axum::extract::{BodyStream, Path, Query, State};
 use axum::http::header::HeaderMap;
+use axum::http::StatusCode;
 use tracing::{event, Level};
 
 use crate::registry_interface::{digest, BlobReader, BlobStorage, ContentInfo, StorageDriverError};
@@ -10,7 +11,10 @@ use crate::response::errors::Error;
 use crate::response::get_base_url;
 use crate::response::trow_token::TrowToken;
 use crate::response::upload_info::UploadInfo;
-use crate::types::{AcceptedUpload, BlobDeleted, DigestQuery, RepoName, Upload, Uuid};
+use crate::types::{
+    AcceptedUpload, BlobDeleted, DigestQuery, MountQuery, MountedBlob, RepoName, Upload,
+    UploadCancelled, Uuid,
+};
 use crate::TrowServerState;
 
 /*
@@ -101,6 +105,90 @@ pub async fn get_blob_5level(
     .await
 }
 
+/*
+---
+Checking a Layer Exists
+HEAD /v2/<name>/blobs/<digest>
+
+Answered without reading the blob, so proxied blobs aren't fetched from upstream.
+ */
+pub async fn head_blob(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((one, digest)): Path<(String, String)>,
+) -> Result<BlobReader, Error> {
+    let digest = match digest::parse(&digest) {
+        Ok(d) => d,
+        Err(e) => {
+            event!(Level::ERROR, "Error parsing digest: {}", e);
+            return Err(Error::DigestInvalid);
+        }
+    };
+
+    match state.client.head_blob(&one, &digest).await {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            event!(Level::ERROR, "Error checking blob: {}", e);
+            Err(Error::NotFound)
+        }
+    }
+}
+pub async fn head_blob_2level(
+    auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((one, two, digest)): Path<(String, String, String)>,
+) -> Result<BlobReader, Error> {
+    head_blob(
+        auth_user,
+        State(state),
+        Path((format!("{one}/{two}"), digest)),
+    )
+    .await
+}
+pub async fn head_blob_3level(
+    auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((one, two, three, digest)): Path<(String, String, String, String)>,
+) -> Result<BlobReader, Error> {
+    head_blob(
+        auth_user,
+        State(state),
+        Path((format!("{one}/{two}/{three}"), digest)),
+    )
+    .await
+}
+pub async fn head_blob_4level(
+    auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((one, two, three, four, digest)): Path<(String, String, String, String, String)>,
+) -> Result<BlobReader, Error> {
+    head_blob(
+        auth_user,
+        State(state),
+        Path((format!("{one}/{two}/{three}/{four}"), digest)),
+    )
+    .await
+}
+pub async fn head_blob_5level(
+    auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((one, two, three, four, five, digest)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<BlobReader, Error> {
+    head_blob(
+        auth_user,
+        State(state),
+        Path((format!("{one}/{two}/{three}/{four}/{five}"), digest)),
+    )
+    .await
+}
+
 /*
 ---
 Monolithic Upload
@@ -152,6 +240,7 @@ pub async fn put_blob(
         .await
         .map_err(|e| match e {
             StorageDriverError::InvalidDigest => Error::DigestInvalid,
+            StorageDriverError::Denied(reason) => Error::Denied(reason),
             e => {
                 event!(Level::ERROR, "Error completing blob upload: {}", e);
                 Error::InternalError
@@ -163,7 +252,7 @@ pub async fn put_blob(
         digest_obj,
         RepoName(repo),
         Uuid(uuid),
-        (0, (size as u32).saturating_sub(1)), // Note first byte is 0
+        (0, size.saturating_sub(1)), // Note first byte is 0
     ))
 }
 pub async fn put_blob_2level(
@@ -284,7 +373,7 @@ pub async fn patch_blob(
                 get_base_url(&headers, &state.config),
                 uuid,
                 repo_name,
-                (0, (stored.total_stored as u32).saturating_sub(1)), // First byte is 0
+                (0, stored.total_stored.saturating_sub(1)), // First byte is 0
             ))
         }
         Err(StorageDriverError::InvalidName(name)) => Err(Error::NameInvalid(name)),
@@ -378,6 +467,187 @@ pub async fn patch_blob_5level(
     .await
 }
 
+/*
+---
+Upload Progress
+
+GET /v2/<name>/blobs/uploads/<uuid>
+---
+
+Returns the current state of an upload, so that clients can resume it.
+The Range header holds the bytes received so far.
+
+# Responses
+204 - upload in progress
+404 - unknown upload
+*/
+pub async fn get_blob_upload(
+    headers: HeaderMap,
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((repo, uuid)): Path<(String, String)>,
+) -> Result<(StatusCode, UploadInfo), Error> {
+    let status = state
+        .client
+        .status_blob_upload(&repo, &uuid)
+        .await
+        .map_err(|e| match e {
+            StorageDriverError::UnknownUpload(_) => Error::BlobUploadUnknown,
+            _ => Error::InternalError,
+        })?;
+    Ok((
+        StatusCode::NO_CONTENT,
+        UploadInfo::new(
+            get_base_url(&headers, &state.config),
+            Uuid(status.session_id().to_string()),
+            RepoName(status.name().to_string()),
+            (0, status.uploaded().saturating_sub(1)), // First byte is 0
+        ),
+    ))
+}
+pub async fn get_blob_upload_2level(
+    headers: HeaderMap,
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, uuid)): Path<(String, String, String)>,
+) -> Result<(StatusCode, UploadInfo), Error> {
+    get_blob_upload(
+        headers,
+        auth_user,
+        state,
+        Path((format!("{one}/{two}"), uuid)),
+    )
+    .await
+}
+pub async fn get_blob_upload_3level(
+    headers: HeaderMap,
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, uuid)): Path<(String, String, String, String)>,
+) -> Result<(StatusCode, UploadInfo), Error> {
+    get_blob_upload(
+        headers,
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}"), uuid)),
+    )
+    .await
+}
+pub async fn get_blob_upload_4level(
+    headers: HeaderMap,
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, uuid)): Path<(String, String, String, String, String)>,
+) -> Result<(StatusCode, UploadInfo), Error> {
+    get_blob_upload(
+        headers,
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}"), uuid)),
+    )
+    .await
+}
+pub async fn get_blob_upload_5level(
+    headers: HeaderMap,
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, five, uuid)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<(StatusCode, UploadInfo), Error> {
+    get_blob_upload(
+        headers,
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}/{five}"), uuid)),
+    )
+    .await
+}
+
+/*
+---
+Canceling an Upload
+
+DELETE /v2/<name>/blobs/uploads/<uuid>
+---
+
+Aborts the upload and discards any data received so far.
+
+# Responses
+204 - upload cancelled
+404 - unknown upload
+*/
+pub async fn delete_blob_upload(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((repo, uuid)): Path<(String, String)>,
+) -> Result<UploadCancelled, Error> {
+    state
+        .client
+        .cancel_blob_upload(&repo, &uuid)
+        .await
+        .map_err(|e| match e {
+            StorageDriverError::UnknownUpload(_) => Error::BlobUploadUnknown,
+            _ => Error::InternalError,
+        })?;
+    Ok(UploadCancelled {})
+}
+pub async fn delete_blob_upload_2level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, uuid)): Path<(String, String, String)>,
+) -> Result<UploadCancelled, Error> {
+    delete_blob_upload(auth_user, state, Path((format!("{one}/{two}"), uuid))).await
+}
+pub async fn delete_blob_upload_3level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, uuid)): Path<(String, String, String, String)>,
+) -> Result<UploadCancelled, Error> {
+    delete_blob_upload(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}"), uuid)),
+    )
+    .await
+}
+pub async fn delete_blob_upload_4level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, uuid)): Path<(String, String, String, String, String)>,
+) -> Result<UploadCancelled, Error> {
+    delete_blob_upload(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}"), uuid)),
+    )
+    .await
+}
+pub async fn delete_blob_upload_5level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, five, uuid)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<UploadCancelled, Error> {
+    delete_blob_upload(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}/{five}"), uuid)),
+    )
+    .await
+}
+
 /*
  Starting point for an uploading a new image or new version of an image.
 
@@ -385,15 +655,51 @@ pub async fn patch_blob_5level(
 
  No data is being transferred _unless_ the request ends with "?digest".
  In this case the whole blob is attached.
+
+ If the request has "?mount=<digest>&from=<repo>" and the blob can be read from
+ `repo`, it is mounted into this repository and no upload is needed. Otherwise we
+ fall back to a normal upload.
 */
 pub async fn post_blob_upload(
     auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
     headers: HeaderMap,
     Query(digest): Query<DigestQuery>,
+    Query(mount): Query<MountQuery>,
     Path(repo_name): Path<String>,
     data: BodyStream,
 ) -> Result<Upload, Error> {
+    if let (Some(mount_digest), Some(from)) = (mount.mount, mount.from) {
+        let mount_digest = digest::parse(&mount_digest).map_err(|_| Error::DigestInvalid)?;
+        // A blob already in this repository is mounted from it, which still resets its GC age
+        let mounted = if state.client.has_blob(&repo_name, &mount_digest).await {
+            state
+                .client
+                .mount_blob(&repo_name, &repo_name, &mount_digest)
+                .await
+        } else {
+            state
+                .client
+                .mount_blob(&repo_name, &from, &mount_digest)
+                .await
+        };
+        match mounted {
+            Ok(()) => {
+                return Ok(Upload::Mounted(MountedBlob::new(
+                    get_base_url(&headers, &state.config),
+                    mount_digest,
+                    RepoName(repo_name),
+                )))
+            }
+            Err(e) => event!(
+                Level::DEBUG,
+                "Could not mount blob from {}, falling back to upload: {}",
+                from,
+                e
+            ),
+        }
+    }
+
     /*
         Ask the backend for a UUID.
 
@@ -410,6 +716,7 @@ pub async fn post_blob_upload(
         .await
         .map_err(|e| match e {
             StorageDriverError::InvalidName(n) => Error::NameInvalid(n),
+            StorageDriverError::Denied(reason) => Error::Denied(reason),
             _ => Error::InternalError,
         })?;
 
@@ -439,6 +746,7 @@ pub async fn post_blob_upload_2level(
     state: State<Arc<TrowServerState>>,
     headers: HeaderMap,
     digest: Query<DigestQuery>,
+    mount: Query<MountQuery>,
     Path((one, two)): Path<(String, String)>,
     data: BodyStream,
 ) -> Result<Upload, Error> {
@@ -447,6 +755,7 @@ pub async fn post_blob_upload_2level(
         state,
         headers,
         digest,
+        mount,
         Path(format!("{one}/{two}")),
         data,
     )
@@ -457,6 +766,7 @@ pub async fn post_blob_upload_3level(
     state: State<Arc<TrowServerState>>,
     headers: HeaderMap,
     digest: Query<DigestQuery>,
+    mount: Query<MountQuery>,
     Path((one, two, three)): Path<(String, String, String)>,
     data: BodyStream,
 ) -> Result<Upload, Error> {
@@ -465,6 +775,7 @@ pub async fn post_blob_upload_3level(
         state,
         headers,
         digest,
+        mount,
         Path(format!("{one}/{two}/{three}")),
         data,
     )
@@ -475,6 +786,7 @@ pub async fn post_blob_upload_4level(
     state: State<Arc<TrowServerState>>,
     headers: HeaderMap,
     digest: Query<DigestQuery>,
+    mount: Query<MountQuery>,
     Path((one, two, three, four)): Path<(String, String, String, String)>,
     data: BodyStream,
 ) -> Result<Upload, Error> {
@@ -483,6 +795,7 @@ pub async fn post_blob_upload_4level(
         state,
         headers,
         digest,
+        mount,
         Path(format!("{one}/{two}/{three}/{four}")),
         data,
     )
@@ -493,6 +806,7 @@ pub async fn post_blob_upload_5level(
     state: State<Arc<TrowServerState>>,
     headers: HeaderMap,
     digest: Query<DigestQuery>,
+    mount: Query<MountQuery>,
     Path((one, two, three, four, five)): Path<(String, String, String, String, String)>,
     data: BodyStream,
 ) -> Result<Upload, Error> {
@@ -501,6 +815,7 @@ pub async fn post_blob_upload_5level(
         state,
         headers,
         digest,
+        mount,
         Path(format!("{one}/{two}/{three}/{four}/{five}")),
         data,
     )
@@ -511,8 +826,7 @@ pub async fn post_blob_upload_5level(
  * Deletes the given blob.
  *
  * Really unsure about this method - why should the user delete a blob?
- * TODO: This should probably be denied if the blob is referenced by any manifests
- * (manifest should be deleted first)
+ * Denied if the blob is referenced by any manifests (manifest should be deleted first).
  */
 pub async fn delete_blob(
     _auth_user: TrowToken,
@@ -524,7 +838,10 @@ pub async fn delete_blob(
         .client
         .delete_blob(&one, &digest)
         .await
-        .map_err(|_| Error::NotFound)?;
+        .map_err(|e| match e {
+            StorageDriverError::BlobReferenced(reason) => Error::BlobReferenced(reason),
+            _ => Error::NotFound,
+        })?;
     Ok(BlobDeleted {})
 }
 pub async fn delete_blob_2level(
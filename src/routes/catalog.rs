@@ -16,21 +16,37 @@ pub struct CatalogListQuery {
     last: Option<String>,
 }
 
+/// Truncates `entries` to `limit` and returns the `last` value of the next page, if there is one.
+///
+/// Callers ask the backend for one more entry than the client did, so that a full page isn't
+/// mistaken for the last one.
+fn paginate(entries: &mut Vec<String>, limit: u32) -> Option<String> {
+    if entries.len() <= limit as usize {
+        return None;
+    }
+    entries.truncate(limit as usize);
+    entries.last().cloned()
+}
+
 pub async fn get_catalog(
     _auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
     Query(query): Query<CatalogListQuery>,
 ) -> Result<RepoCatalog, Error> {
-    let limit = query.n.unwrap_or(std::u32::MAX);
+    let limit = query.n.unwrap_or(u32::MAX);
     let last_repo = query.last.clone().unwrap_or_default();
 
-    let cat = state
+    let mut cat = state
         .client
-        .get_catalog(Some(&last_repo), Some(limit))
+        .get_catalog(Some(&last_repo), Some(limit.saturating_add(1)))
         .await
         .map_err(|_| Error::InternalError)?;
 
-    Ok(RepoCatalog::from(cat))
+    match paginate(&mut cat, limit) {
+        Some(last) => Ok(RepoCatalog::from(cat)
+            .with_next_link(format!("/v2/_catalog?n={}&last={}", limit, last))),
+        None => Ok(RepoCatalog::from(cat)),
+    }
 }
 
 pub async fn list_tags(
@@ -39,15 +55,22 @@ pub async fn list_tags(
     Path(repo_name): Path<String>,
     Query(query): Query<CatalogListQuery>,
 ) -> Result<TagList, Error> {
-    let limit = query.n.unwrap_or(std::u32::MAX);
+    let limit = query.n.unwrap_or(u32::MAX);
     let last_tag = query.last.clone().unwrap_or_default();
 
-    let tags = state
+    let mut tags = state
         .client
-        .get_tags(&repo_name, Some(&last_tag), Some(limit))
+        .get_tags(&repo_name, Some(&last_tag), Some(limit.saturating_add(1)))
         .await
         .map_err(|_| Error::InternalError)?;
-    Ok(TagList::new_filled(repo_name, tags))
+
+    match paginate(&mut tags, limit) {
+        Some(last) => {
+            let link = format!("/v2/{}/tags/list?n={}&last={}", repo_name, limit, last);
+            Ok(TagList::new_filled(repo_name, tags).with_next_link(link))
+        }
+        None => Ok(TagList::new_filled(repo_name, tags)),
+    }
 }
 pub async fn list_tags_2level(
     auth_user: TrowToken,
@@ -106,7 +129,7 @@ pub async fn get_manifest_history(
     Path((name, reference)): Path<(String, String)>,
     Query(query): Query<CatalogListQuery>,
 ) -> Result<ManifestHistory, Error> {
-    let limit = query.n.unwrap_or(std::u32::MAX);
+    let limit = query.n.unwrap_or(u32::MAX);
     let last_digest = query.last.clone().unwrap_or_default();
 
     let mh = state
@@ -1,13 +1,16 @@
 use std::sync::Arc;
 
-use axum::extract::{BodyStream, Path, State};
+use axum::extract::{BodyStream, Path, Query, State};
 use axum::headers::HeaderMap;
+use serde_derive::Deserialize;
 
-use crate::registry_interface::{digest, ManifestReader, ManifestStorage, StorageDriverError};
+use crate::registry_interface::{
+    digest, ManifestInfo, ManifestReader, ManifestStorage, StorageDriverError,
+};
 use crate::response::errors::Error;
 use crate::response::get_base_url;
 use crate::response::trow_token::TrowToken;
-use crate::types::{ManifestDeleted, RepoName, VerifiedManifest};
+use crate::types::{ManifestDeleted, Referrers, RepoName, VerifiedManifest};
 use crate::TrowServerState;
 
 /*
@@ -92,6 +95,79 @@ pub async fn get_manifest_5level(
     .await
 }
 
+/*
+---
+Checking a manifest exists
+HEAD /v2/<name>/manifests/<reference>
+
+Answered without reading the manifest, so proxied manifests aren't downloaded.
+
+# Returns
+200 - with the Content-Type, Content-Length and Docker-Content-Digest of the manifest
+404 - manifest not known to the registry
+ */
+pub async fn head_manifest(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((name, reference)): Path<(String, String)>,
+) -> Result<ManifestInfo, Error> {
+    state
+        .client
+        .head_manifest(&name, &reference)
+        .await
+        .map_err(|_| Error::ManifestUnknown(reference))
+}
+pub async fn head_manifest_2level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, reference)): Path<(String, String, String)>,
+) -> Result<ManifestInfo, Error> {
+    head_manifest(auth_user, state, Path((format!("{one}/{two}"), reference))).await
+}
+pub async fn head_manifest_3level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, reference)): Path<(String, String, String, String)>,
+) -> Result<ManifestInfo, Error> {
+    head_manifest(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}"), reference)),
+    )
+    .await
+}
+pub async fn head_manifest_4level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, reference)): Path<(String, String, String, String, String)>,
+) -> Result<ManifestInfo, Error> {
+    head_manifest(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}"), reference)),
+    )
+    .await
+}
+pub async fn head_manifest_5level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, five, reference)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<ManifestInfo, Error> {
+    head_manifest(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}/{five}"), reference)),
+    )
+    .await
+}
+
 /*
 
 ---
@@ -114,14 +190,20 @@ pub async fn put_image_manifest(
         .store_manifest(&repo_name, &reference, chunk)
         .await
     {
-        Ok(digest) => Ok(VerifiedManifest::new(
+        Ok(stored) => Ok(VerifiedManifest::new(
             Some(base_url),
             RepoName(repo_name),
-            digest,
+            stored.digest,
             reference,
-        )),
+        )
+        .with_subject(stored.subject)),
         Err(StorageDriverError::InvalidName(name)) => Err(Error::NameInvalid(name)),
         Err(StorageDriverError::InvalidManifest) => Err(Error::ManifestInvalid("".to_string())),
+        Err(StorageDriverError::ManifestRejected(reason)) => Err(Error::ManifestInvalid(reason)),
+        Err(StorageDriverError::ManifestBlobUnknown(digest)) => {
+            Err(Error::ManifestBlobUnknown(digest))
+        }
+        Err(StorageDriverError::Denied(reason)) => Err(Error::Denied(reason)),
         Err(_) => Err(Error::InternalError),
     }
 }
@@ -201,17 +283,25 @@ pub async fn put_image_manifest_5level(
 ---
 Deleting an Image
 DELETE /v2/<name>/manifests/<reference>
+
+If the reference is a digest, the manifest and every tag pointing to it are deleted.
+If it is a tag, only that tag is deleted.
 */
 pub async fn delete_image_manifest(
     _auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
-    Path((repo, digest)): Path<(String, String)>,
+    Path((repo, reference)): Path<(String, String)>,
 ) -> Result<ManifestDeleted, Error> {
-    let digest = digest::parse(&digest).map_err(|_| Error::Unsupported)?;
-    match state.client.delete_manifest(&repo, &digest).await {
+    let res = match digest::parse(&reference) {
+        Ok(digest) => state.client.delete_manifest(&repo, &digest).await,
+        Err(_) => state.client.delete_tag(&repo, &reference).await,
+    };
+    match res {
         Ok(_) => Ok(ManifestDeleted {}),
         Err(StorageDriverError::Unsupported) => Err(Error::Unsupported),
-        Err(StorageDriverError::InvalidManifest) => Err(Error::ManifestUnknown(repo)),
+        Err(StorageDriverError::InvalidName(tag)) => Err(Error::NameInvalid(tag)),
+        Err(StorageDriverError::InvalidManifest) => Err(Error::ManifestUnknown(reference)),
+        Err(StorageDriverError::Denied(reason)) => Err(Error::Denied(reason)),
         Err(_) => Err(Error::InternalError),
     }
 }
@@ -265,3 +355,96 @@ pub async fn delete_image_manifest_5level(
     )
     .await
 }
+
+/*
+---
+Listing referrers
+GET /v2/<name>/referrers/<digest>?artifactType=<type>
+
+Returns an image index of the manifests whose subject is <digest>. The index is empty if there are
+none, even if the subject doesn't exist.
+*/
+#[derive(Debug, Deserialize)]
+pub struct ReferrersQuery {
+    #[serde(rename = "artifactType")]
+    artifact_type: Option<String>,
+}
+
+pub async fn get_referrers(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((repo, reference)): Path<(String, String)>,
+    Query(query): Query<ReferrersQuery>,
+) -> Result<Referrers, Error> {
+    let digest = digest::parse(&reference).map_err(|_| Error::DigestInvalid)?;
+    let artifact_type = query.artifact_type.filter(|t| !t.is_empty());
+    let referrers = state
+        .client
+        .get_referrers(&repo, &digest, artifact_type.as_deref())
+        .await
+        .map_err(|_| Error::InternalError)?;
+    Ok(Referrers::new(referrers, artifact_type.is_some()))
+}
+pub async fn get_referrers_2level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, digest)): Path<(String, String, String)>,
+    query: Query<ReferrersQuery>,
+) -> Result<Referrers, Error> {
+    get_referrers(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}"), digest)),
+        query,
+    )
+    .await
+}
+pub async fn get_referrers_3level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, digest)): Path<(String, String, String, String)>,
+    query: Query<ReferrersQuery>,
+) -> Result<Referrers, Error> {
+    get_referrers(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}"), digest)),
+        query,
+    )
+    .await
+}
+pub async fn get_referrers_4level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, digest)): Path<(String, String, String, String, String)>,
+    query: Query<ReferrersQuery>,
+) -> Result<Referrers, Error> {
+    get_referrers(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}"), digest)),
+        query,
+    )
+    .await
+}
+pub async fn get_referrers_5level(
+    auth_user: TrowToken,
+    state: State<Arc<TrowServerState>>,
+    Path((one, two, three, four, five, digest)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+    query: Query<ReferrersQuery>,
+) -> Result<Referrers, Error> {
+    get_referrers(
+        auth_user,
+        state,
+        Path((format!("{one}/{two}/{three}/{four}/{five}"), digest)),
+        query,
+    )
+    .await
+}
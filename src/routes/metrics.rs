@@ -1,7 +1,12 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Result;
-use axum::extract::State;
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use trow_server::metrics;
 
 use crate::registry_interface::{Metrics, MetricsResponse};
 use crate::response::errors::Error;
@@ -18,3 +23,112 @@ pub async fn metrics(State(state): State<Arc<TrowServerState>>) -> Result<Metric
         .await
         .map_err(|_| Error::InternalError)
 }
+
+/// Path parameters that make up repository names in routes
+const REPO_PARAMS: [&str; 5] = [":one", ":two", ":three", ":four", ":five"];
+
+/// The repository a request is for and the kind of object it targets (`manifests`, `blobs`,
+/// `uploads`...), from the route it matched and its path
+fn repo_and_kind<'a>(route: &'a str, path: &str) -> Option<(String, &'a str)> {
+    let mut segments = route.strip_prefix("/v2/")?.split('/');
+    let mut depth = 0;
+    let kind = loop {
+        match segments.next()? {
+            s if REPO_PARAMS.contains(&s) => depth += 1,
+            "blobs" if segments.next() == Some("uploads") => break "uploads",
+            s => break s,
+        }
+    };
+    if depth == 0 {
+        return None;
+    }
+    let repo = path
+        .strip_prefix("/v2/")?
+        .split('/')
+        .take(depth)
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((repo, kind))
+}
+
+/*
+ * Records the latency of every request, and pushes, pulls, deletes and bytes served per
+ * repository
+ */
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string());
+    let path = req.uri().path().to_string();
+
+    let response = next.run(req).await;
+
+    let status = response.status();
+    metrics::HTTP_REQUEST_DURATION
+        .with_label_values(&[
+            route.as_deref().unwrap_or("unmatched"),
+            method.as_str(),
+            status.as_str(),
+        ])
+        .observe(start.elapsed().as_secs_f64());
+
+    let (repo, kind) = match route.as_deref().and_then(|r| repo_and_kind(r, &path)) {
+        Some(r) => r,
+        None => return response,
+    };
+    let repo = metrics::repo_label(&repo);
+    match (method, kind, status) {
+        (Method::GET, "manifests" | "blobs", StatusCode::OK) => {
+            if kind == "manifests" {
+                metrics::PULLS.with_label_values(&[&repo]).inc();
+            }
+            let size = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            if let Some(size) = size {
+                metrics::BYTES_SERVED
+                    .with_label_values(&[&repo])
+                    .inc_by(size);
+            }
+        }
+        (Method::PUT, "manifests", StatusCode::CREATED) => {
+            metrics::PUSHES.with_label_values(&[&repo]).inc();
+        }
+        (Method::DELETE, "manifests" | "blobs", StatusCode::ACCEPTED) => {
+            metrics::DELETES.with_label_values(&[&repo]).inc();
+        }
+        _ => {}
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::repo_and_kind;
+
+    #[test]
+    fn finds_repo_and_kind() {
+        assert_eq!(
+            repo_and_kind(
+                "/v2/:one/:two/manifests/:reference",
+                "/v2/team/web/manifests/v1"
+            ),
+            Some(("team/web".to_string(), "manifests"))
+        );
+        assert_eq!(
+            repo_and_kind("/v2/:one/blobs/:digest", "/v2/blobs/blobs/sha256:abc"),
+            Some(("blobs".to_string(), "blobs"))
+        );
+        assert_eq!(
+            repo_and_kind("/v2/:one/blobs/uploads/:uuid", "/v2/web/blobs/uploads/123"),
+            Some(("web".to_string(), "uploads"))
+        );
+        assert_eq!(repo_and_kind("/v2/_catalog", "/v2/_catalog"), None);
+        assert_eq!(repo_and_kind("/metrics", "/metrics"), None);
+    }
+}
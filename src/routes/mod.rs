@@ -6,6 +6,7 @@ use axum::body::{boxed, Body};
 use axum::extract::State;
 use axum::http::method::Method;
 use axum::http::{header, StatusCode};
+use axum::middleware;
 use axum::response::Response;
 use axum::routing::{get, post, put};
 use axum::Router;
@@ -71,6 +72,7 @@ pub fn create_app(state: super::TrowServerState) -> Router {
         app,
         "/v2" "/blobs/:digest",
         get(blob::get_blob, blob::get_blob_2level, blob::get_blob_3level, blob::get_blob_4level, blob::get_blob_5level),
+        head(blob::head_blob, blob::head_blob_2level, blob::head_blob_3level, blob::head_blob_4level, blob::head_blob_5level),
         delete(blob::delete_blob, blob::delete_blob_2level, blob::delete_blob_3level, blob::delete_blob_4level, blob::delete_blob_5level)
     );
     #[rustfmt::skip]
@@ -84,7 +86,9 @@ pub fn create_app(state: super::TrowServerState) -> Router {
         app,
         "/v2" "/blobs/uploads/:uuid",
         put(blob::put_blob, blob::put_blob_2level, blob::put_blob_3level, blob::put_blob_4level, blob::put_blob_5level),
-        patch(blob::patch_blob, blob::patch_blob_2level, blob::patch_blob_3level, blob::patch_blob_4level, blob::patch_blob_5level)
+        patch(blob::patch_blob, blob::patch_blob_2level, blob::patch_blob_3level, blob::patch_blob_4level, blob::patch_blob_5level),
+        get(blob::get_blob_upload, blob::get_blob_upload_2level, blob::get_blob_upload_3level, blob::get_blob_upload_4level, blob::get_blob_upload_5level),
+        delete(blob::delete_blob_upload, blob::delete_blob_upload_2level, blob::delete_blob_upload_3level, blob::delete_blob_upload_4level, blob::delete_blob_upload_5level)
     );
 
     // catalog
@@ -108,9 +112,16 @@ pub fn create_app(state: super::TrowServerState) -> Router {
         app,
         "/v2" "/manifests/:reference",
         get(manifest::get_manifest, manifest::get_manifest_2level, manifest::get_manifest_3level, manifest::get_manifest_4level, manifest::get_manifest_5level),
+        head(manifest::head_manifest, manifest::head_manifest_2level, manifest::head_manifest_3level, manifest::head_manifest_4level, manifest::head_manifest_5level),
         put(manifest::put_image_manifest, manifest::put_image_manifest_2level, manifest::put_image_manifest_3level, manifest::put_image_manifest_4level, manifest::put_image_manifest_5level),
         delete(manifest::delete_image_manifest, manifest::delete_image_manifest_2level, manifest::delete_image_manifest_3level, manifest::delete_image_manifest_4level, manifest::delete_image_manifest_5level)
     );
+    #[rustfmt::skip]
+    route_5_levels!(
+        app,
+        "/v2" "/referrers/:digest",
+        get(manifest::get_referrers, manifest::get_referrers_2level, manifest::get_referrers_3level, manifest::get_referrers_4level, manifest::get_referrers_5level)
+    );
 
     app = app.layer(
         trace::TraceLayer::new_for_http()
@@ -125,6 +136,7 @@ pub fn create_app(state: super::TrowServerState) -> Router {
                 tracing::info!("done in {:?}", duration)
             }),
     );
+    app = app.layer(middleware::from_fn(metrics::track_requests));
 
     if let Some(domains) = &state.config.cors {
         app = app.layer(
@@ -1,7 +1,7 @@
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
 
-use crate::registry_interface::Digest;
+use crate::registry_interface::{Digest, Referrer};
 
 // TODO: Kill this file. Move types and methods to where they're used.
 
@@ -18,20 +18,28 @@ pub struct DigestQuery {
     pub digest: Option<String>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct MountQuery {
+    pub mount: Option<String>,
+    pub from: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UploadInfo {
     base_url: String,
     uuid: Uuid,
     repo_name: RepoName,
-    range: (u32, u32),
+    range: (u64, u64),
 }
 
 pub struct BlobDeleted {}
 
+pub struct UploadCancelled {}
+
 pub struct ManifestDeleted {}
 
 impl UploadInfo {
-    pub fn new(base_url: String, uuid: Uuid, repo_name: RepoName, range: (u32, u32)) -> Self {
+    pub fn new(base_url: String, uuid: Uuid, repo_name: RepoName, range: (u64, u64)) -> Self {
         Self {
             base_url,
             uuid,
@@ -48,7 +56,7 @@ impl UploadInfo {
         &self.repo_name
     }
 
-    pub fn range(&self) -> (u32, u32) {
+    pub fn range(&self) -> (u64, u64) {
         self.range
     }
 
@@ -63,7 +71,7 @@ pub struct AcceptedUpload {
     digest: Digest,
     repo_name: RepoName,
     uuid: Uuid,
-    range: (u32, u32),
+    range: (u64, u64),
 }
 
 impl AcceptedUpload {
@@ -72,7 +80,7 @@ impl AcceptedUpload {
         digest: Digest,
         repo_name: RepoName,
         uuid: Uuid,
-        range: (u32, u32),
+        range: (u64, u64),
     ) -> Self {
         Self {
             base_url,
@@ -91,7 +99,7 @@ impl AcceptedUpload {
         &self.repo_name
     }
 
-    pub fn range(&self) -> (u32, u32) {
+    pub fn range(&self) -> (u64, u64) {
         self.range
     }
 
@@ -100,10 +108,40 @@ impl AcceptedUpload {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct MountedBlob {
+    base_url: String,
+    digest: Digest,
+    repo_name: RepoName,
+}
+
+impl MountedBlob {
+    pub fn new(base_url: String, digest: Digest, repo_name: RepoName) -> Self {
+        Self {
+            base_url,
+            digest,
+            repo_name,
+        }
+    }
+
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+
+    pub fn repo_name(&self) -> &RepoName {
+        &self.repo_name
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub enum Upload {
     Accepted(AcceptedUpload),
     Info(UploadInfo),
+    Mounted(MountedBlob),
 }
 
 #[derive(Debug, Serialize)]
@@ -112,6 +150,7 @@ pub struct VerifiedManifest {
     repo_name: RepoName,
     digest: Digest,
     tag: String,
+    subject: Option<Digest>,
 }
 
 impl VerifiedManifest {
@@ -121,9 +160,20 @@ impl VerifiedManifest {
             repo_name,
             digest,
             tag,
+            subject: None,
         }
     }
 
+    pub fn with_subject(mut self, subject: Option<Digest>) -> Self {
+        self.subject = subject;
+        self
+    }
+
+    /// Digest of the manifest this one refers to, if any
+    pub fn subject(&self) -> Option<&Digest> {
+        self.subject.as_ref()
+    }
+
     pub fn digest(&self) -> &Digest {
         &self.digest
     }
@@ -144,15 +194,28 @@ impl VerifiedManifest {
 pub struct RepoCatalog {
     #[serde(rename = "repositories")]
     catalog: Vec<String>,
+    /// URL of the next page, if the list was truncated
+    #[serde(skip)]
+    next_link: Option<String>,
 }
 
 impl RepoCatalog {
     pub fn new() -> RepoCatalog {
         RepoCatalog {
             catalog: Vec::new(),
+            next_link: None,
         }
     }
 
+    pub fn with_next_link(mut self, link: String) -> RepoCatalog {
+        self.next_link = Some(link);
+        self
+    }
+
+    pub fn next_link(&self) -> Option<&str> {
+        self.next_link.as_deref()
+    }
+
     pub fn insert(&mut self, rn: String) {
         self.catalog.push(rn);
         self.catalog.sort();
@@ -169,7 +232,10 @@ impl RepoCatalog {
 
 impl From<Vec<String>> for RepoCatalog {
     fn from(cat: Vec<String>) -> Self {
-        RepoCatalog { catalog: cat }
+        RepoCatalog {
+            catalog: cat,
+            next_link: None,
+        }
     }
 }
 
@@ -179,6 +245,9 @@ pub struct TagList {
     repo: String,
     #[serde(rename = "tags")]
     list: Vec<String>,
+    /// URL of the next page, if the list was truncated
+    #[serde(skip)]
+    next_link: Option<String>,
 }
 
 impl TagList {
@@ -186,11 +255,25 @@ impl TagList {
         TagList {
             repo: repo_name,
             list: Vec::new(),
+            next_link: None,
         }
     }
 
     pub fn new_filled(repo: String, list: Vec<String>) -> TagList {
-        TagList { repo, list }
+        TagList {
+            repo,
+            list,
+            next_link: None,
+        }
+    }
+
+    pub fn with_next_link(mut self, link: String) -> TagList {
+        self.next_link = Some(link);
+        self
+    }
+
+    pub fn next_link(&self) -> Option<&str> {
+        self.next_link.as_deref()
     }
 
     pub fn insert(&mut self, tag: String) {
@@ -210,6 +293,37 @@ impl TagList {
     }
 }
 
+/// Image index returned by the referrers API
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Referrers {
+    schema_version: u8,
+    media_type: String,
+    manifests: Vec<Referrer>,
+    /// Whether the list was filtered by artifact type
+    #[serde(skip)]
+    filtered: bool,
+}
+
+impl Referrers {
+    pub fn new(manifests: Vec<Referrer>, filtered: bool) -> Referrers {
+        Referrers {
+            schema_version: 2,
+            media_type: "application/vnd.oci.image.index.v1+json".to_string(),
+            manifests,
+            filtered,
+        }
+    }
+
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    pub fn filtered(&self) -> bool {
+        self.filtered
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct HealthResponse {
     pub message: String,
@@ -221,3 +335,32 @@ pub struct ReadinessResponse {
     pub message: String,
     pub is_ready: bool,
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CollectedBlob {
+    pub digest: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GarbageCollectionReport {
+    pub blobs: Vec<CollectedBlob>,
+    pub total_bytes: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ExpiredTag {
+    pub repo_name: String,
+    pub tag: String,
+    pub digest: String,
+    pub last_pushed: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RetentionReport {
+    pub tags: Vec<ExpiredTag>,
+    pub dry_run: bool,
+    pub garbage: Option<GarbageCollectionReport>,
+    pub protected: Vec<ExpiredTag>,
+}
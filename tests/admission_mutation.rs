@@ -37,12 +37,14 @@ mod admission_mutation_tests {
                     host: "registry-1.docker.io".to_string(),
                     username: None,
                     password: None,
+                    tag_ttl: 0,
                 },
                 SingleRegistryProxyConfig {
                     alias: "ecr".to_string(),
                     host: "1234.dkr.ecr.saturn-5.amazonaws.com".to_string(),
                     username: Some("AWS".to_string()),
                     password: None,
+                    tag_ttl: 0,
                 },
             ],
         });
@@ -138,7 +140,7 @@ mod admission_mutation_tests {
         });
 
         let resp = cl
-            .post(&format!("{}/mutate-image", ORIGIN))
+            .post(format!("{}/mutate-image", ORIGIN))
             .header(header::CONTENT_TYPE, "application/json")
             .body(review.to_string())
             .send()
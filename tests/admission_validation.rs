@@ -193,7 +193,7 @@ mod validation_tests {
 }"#;
 
         let resp = cl
-            .post(&format!("{}/validate-image", ORIGIN))
+            .post(format!("{}/validate-image", ORIGIN))
             .header(header::CONTENT_TYPE, "application/json")
             .body(review)
             .send()
@@ -265,7 +265,7 @@ mod validation_tests {
         .to_string();
 
         let resp = cl
-            .post(&format!("{}/validate-image", ORIGIN))
+            .post(format!("{}/validate-image", ORIGIN))
             .header(header::CONTENT_TYPE, "application/json")
             .body(review)
             .send()
@@ -25,7 +25,6 @@ mod authentication_tests {
     }
     /// Call out to cargo to start trow.
     /// Seriously considering moving to docker run.
-
     async fn start_trow() -> TrowInstance {
         let mut child = Command::new("cargo")
             .arg("run")
@@ -97,7 +96,7 @@ mod authentication_tests {
         // Uncomment if you want to inspect the token
         // let _token: JsonValue = resp.json().unwrap();
         let resp = cl
-            .get(&format!(
+            .get(format!(
                 "{}/v2/{}/manifests/{}",
                 TROW_ADDRESS, "name", "tag"
             ))
@@ -40,6 +40,15 @@ mod cli {
             .stdout(predicate::str::contains("Trow"));
     }
 
+    #[test]
+    fn gc_help_works() {
+        get_command()
+            .args(["gc", "--help"])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("--dry-run"));
+    }
+
     #[test]
     fn host_name_parsing() {
         get_command()
@@ -109,12 +118,14 @@ mod cli {
                     host: "jul.example.com".to_string(),
                     username: Some("robert".to_string()),
                     password: Some("1234".to_string()),
+                    tag_ttl: 0,
                 },
                 SingleRegistryProxyConfig {
                     alias: "trow".to_string(),
                     host: "127.0.0.1".to_string(),
                     username: None,
                     password: None,
+                    tag_ttl: 0,
                 },
             ],
         });
@@ -136,6 +147,20 @@ mod cli {
             ));
     }
 
+    #[test]
+    fn upload_ttl() {
+        get_command()
+            .args(["--upload-ttl", "3600"])
+            .assert()
+            .success();
+
+        get_command()
+            .args(["--upload-ttl", "0"])
+            .assert()
+            .stderr(predicate::str::contains("invalid value '0'"))
+            .failure();
+    }
+
     #[test]
     fn cors() {
         get_command()
@@ -40,7 +40,7 @@ pub fn kill_gracefully(child: &Child) {
 #[allow(dead_code)]
 pub async fn upload_layer(cl: &reqwest::Client, trow_address: &str, name: &str, tag: &str) {
     let resp = cl
-        .post(&format!("{}/v2/{}/blobs/uploads/", trow_address, name))
+        .post(format!("{}/v2/{}/blobs/uploads/", trow_address, name))
         .send()
         .await
         .expect("Error uploading layer");
@@ -67,7 +67,7 @@ pub async fn upload_layer(cl: &reqwest::Client, trow_address: &str, name: &str,
 
     let digest = digest::sha256_tag_digest(BufReader::new(blob.as_slice())).unwrap();
     let resp = cl
-        .put(&format!(
+        .put(format!(
             "{}/v2/{}/blobs/uploads/{}?digest={}",
             trow_address, name, uuid, digest
         ))
@@ -78,7 +78,7 @@ pub async fn upload_layer(cl: &reqwest::Client, trow_address: &str, name: &str,
 
     //Finally get it back again
     let resp = cl
-        .get(&format!("{}/v2/{}/blobs/{}", trow_address, name, digest))
+        .get(format!("{}/v2/{}/blobs/{}", trow_address, name, digest))
         .send()
         .await
         .unwrap();
@@ -110,6 +110,9 @@ pub async fn upload_layer(cl: &reqwest::Client, trow_address: &str, name: &str,
         media_type: Some("application/vnd.docker.distribution.manifest.v2+json".to_owned()),
         config,
         layers,
+        artifact_type: None,
+        subject: None,
+        annotations: None,
     };
     let manifest_addr = format!("{}/v2/{}/manifests/{}", trow_address, name, tag);
     let resp = cl.put(&manifest_addr).json(&mani).send().await.unwrap();
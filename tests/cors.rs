@@ -24,7 +24,6 @@ mod cors_tests {
     }
     /// Call out to cargo to start trow.
     /// Seriously considering moving to docker run.
-
     async fn start_trow() -> TrowInstance {
         let mut child = Command::new("cargo")
             .arg("run")
@@ -77,7 +76,7 @@ mod cors_tests {
         );
 
         let resp = cl
-            .request(hyper::Method::OPTIONS, &(ORIGIN.to_owned()))
+            .request(hyper::Method::OPTIONS, ORIGIN)
             .headers(headers)
             .send()
             .await
@@ -108,7 +107,7 @@ mod cors_tests {
 
     async fn test_cors_method_get(cl: &reqwest::Client) {
         let resp = cl
-            .get(&(ORIGIN.to_owned()))
+            .get(ORIGIN)
             .header(header::ORIGIN, "https://example.com")
             .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
             .send()
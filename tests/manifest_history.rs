@@ -25,7 +25,6 @@ mod interface_tests {
     }
     /// Call out to cargo to start trow.
     /// Seriously considering moving to docker run.
-
     async fn start_trow() -> TrowInstance {
         let mut child = Command::new("cargo")
             .arg("run")
@@ -60,13 +59,13 @@ mod interface_tests {
         }
     }
 
-    async fn upload_config(cl: &reqwest::Client) {
+    async fn upload_config(cl: &reqwest::Client, name: &str) {
         let config = "{}\n".as_bytes();
         let digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
         let resp = cl
-            .post(&format!(
+            .post(format!(
                 "{}/v2/{}/blobs/uploads/?digest={}",
-                TROW_ADDRESS, "config", digest
+                TROW_ADDRESS, name, digest
             ))
             .body(config)
             .send()
@@ -112,7 +111,7 @@ mod interface_tests {
         );
         let bytes = manifest.clone();
         let resp = cl
-            .put(&format!("{}/v2/{}/manifests/{}", TROW_ADDRESS, name, tag))
+            .put(format!("{}/v2/{}/manifests/{}", TROW_ADDRESS, name, tag))
             .body(bytes)
             .send()
             .await
@@ -142,7 +141,7 @@ mod interface_tests {
         }
 
         let resp = cl
-            .get(&format!(
+            .get(format!(
                 "{}/{}/manifest_history/{}{}",
                 TROW_ADDRESS, repo, tag, options
             ))
@@ -170,7 +169,7 @@ mod interface_tests {
         let _trow = start_trow().await;
         let client = reqwest::Client::new();
 
-        upload_config(&client).await;
+        upload_config(&client, "history").await;
 
         // Following is intentionally interleaved to add delays
         let mut history_one = Vec::new();
@@ -26,7 +26,6 @@ mod no_cors_tests {
     }
     /// Call out to cargo to start trow.
     /// Seriously considering moving to docker run.
-
     async fn start_trow() -> TrowInstance {
         let mut child = Command::new("cargo")
             .arg("run")
@@ -69,7 +68,7 @@ mod no_cors_tests {
         headers.insert(ACCESS_CONTROL_REQUEST_METHOD, "OPTIONS".parse().unwrap());
 
         let resp = cl
-            .request(hyper::Method::OPTIONS, &(TROW_ADDRESS.to_owned()))
+            .request(hyper::Method::OPTIONS, TROW_ADDRESS)
             .headers(headers)
             .send()
             .await
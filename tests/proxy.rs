@@ -30,18 +30,21 @@ mod interface_tests {
                     host: "registry-1.docker.io".to_string(),
                     username: None,
                     password: None,
+                    tag_ttl: 0,
                 },
                 SingleRegistryProxyConfig {
                     alias: "nvcr".to_string(),
                     host: "nvcr.io".to_string(),
                     username: None,
                     password: None,
+                    tag_ttl: 0,
                 },
                 SingleRegistryProxyConfig {
                     alias: "quay".to_string(),
                     host: "quay.io".to_string(),
                     username: None,
                     password: None,
+                    tag_ttl: 0,
                 },
             ],
         });
@@ -85,7 +88,7 @@ mod interface_tests {
     async fn get_manifest(cl: &reqwest::Client, name: &str, tag: &str) -> manifest::Manifest {
         //Might need accept headers here
         let resp = cl
-            .get(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .get(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
             .send()
             .await
             .unwrap();
@@ -101,7 +104,7 @@ mod interface_tests {
 
     async fn upload_to_nonwritable_repo(cl: &reqwest::Client, name: &str) {
         let resp = cl
-            .post(&format!("{ORIGIN}/v2/{name}/blobs/uploads/"))
+            .post(format!("{ORIGIN}/v2/{name}/blobs/uploads/"))
             .send()
             .await
             .expect("Error uploading layer");
@@ -125,6 +128,9 @@ mod interface_tests {
             media_type: Some("application/vnd.docker.distribution.manifest.v2+json".to_owned()),
             config,
             layers,
+            artifact_type: None,
+            subject: None,
+            annotations: None,
         };
         let manifest_addr = format!("{}/v2/{}/manifests/{}", ORIGIN, name, "tag");
         let resp = cl.put(&manifest_addr).json(&mani).send().await.unwrap();
@@ -26,7 +26,6 @@ mod interface_tests {
     }
     /// Call out to cargo to start trow.
     /// Seriously considering moving to docker run.
-
     async fn start_trow() -> TrowInstance {
         let mut child = Command::new("cargo")
             .arg("run")
@@ -35,6 +34,10 @@ mod interface_tests {
             .arg(HOST)
             .arg("--port")
             .arg(PORT)
+            .arg("--immutable-tags")
+            .arg("immutabletest:v*")
+            .arg("--quotas")
+            .arg("quotatest=320,quotafull=0")
             .env_clear()
             .envs(Environment::inherit().compile())
             .spawn()
@@ -83,10 +86,22 @@ mod interface_tests {
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 
+    async fn get_blob_from_other_repo(cl: &reqwest::Client, name: &str) {
+        //Config was uploaded to puttest, it shouldn't be visible anywhere else
+        let config = "{}\n".as_bytes();
+        let config_digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .get(format!("{}/v2/{}/blobs/{}", ORIGIN, name, config_digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
     async fn get_manifest(cl: &reqwest::Client, name: &str, tag: &str, size: Option<usize>) {
         //Might need accept headers here
         let resp = cl
-            .get(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .get(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
             .send()
             .await
             .unwrap();
@@ -115,7 +130,7 @@ mod interface_tests {
     async fn get_non_existent_manifest(cl: &reqwest::Client, name: &str, tag: &str) {
         // Might need accept headers here
         let resp = cl
-            .get(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .get(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
             .send()
             .await
             .unwrap();
@@ -124,7 +139,7 @@ mod interface_tests {
 
     async fn check_repo_catalog(cl: &reqwest::Client, rc: &RepoCatalog) {
         let resp = cl
-            .get(&format!("{}/v2/_catalog", ORIGIN))
+            .get(format!("{}/v2/_catalog", ORIGIN))
             .send()
             .await
             .unwrap();
@@ -134,7 +149,7 @@ mod interface_tests {
 
     async fn check_tag_list(cl: &reqwest::Client, tl: &TagList) {
         let resp = cl
-            .get(&format!("{}/v2/{}/tags/list", ORIGIN, tl.repo_name()))
+            .get(format!("{}/v2/{}/tags/list", ORIGIN, tl.repo_name()))
             .send()
             .await
             .unwrap();
@@ -144,7 +159,7 @@ mod interface_tests {
 
     async fn check_tag_list_n_last(cl: &reqwest::Client, n: u32, last: &str, tl: &TagList) {
         let resp = cl
-            .get(&format!(
+            .get(format!(
                 "{}/v2/{}/tags/list?last={}&n={}",
                 ORIGIN,
                 tl.repo_name(),
@@ -158,9 +173,62 @@ mod interface_tests {
         assert_eq!(tl, &tl_resp);
     }
 
+    /// Follows the `Link` headers from `url`, returning every page
+    async fn get_pages(cl: &reqwest::Client, url: &str) -> Vec<serde_json::Value> {
+        let mut pages = vec![];
+        let mut next = Some(url.to_string());
+        while let Some(url) = next {
+            let resp = cl.get(format!("{}{}", ORIGIN, url)).send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            next = resp.headers().get("Link").map(|link| {
+                let link = link.to_str().unwrap();
+                assert!(link.ends_with(">; rel=\"next\""));
+                link[1..link.find('>').unwrap()].to_string()
+            });
+            pages.push(resp.json().await.unwrap());
+        }
+        pages
+    }
+
+    async fn paginate_catalog_and_tags(cl: &reqwest::Client) {
+        let full: RepoCatalog = cl
+            .get(format!("{}/v2/_catalog", ORIGIN))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let mut sorted = full.catalog().clone();
+        sorted.sort();
+        assert_eq!(full.catalog(), &sorted);
+
+        let pages = get_pages(cl, "/v2/_catalog?n=2").await;
+        assert_eq!(pages.len(), sorted.len().div_ceil(2));
+        let paged: Vec<String> = pages
+            .iter()
+            .flat_map(|p| p["repositories"].as_array().unwrap().clone())
+            .map(|r| r.as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(paged, sorted);
+
+        let pages = get_pages(cl, "/v2/onename/tags/list?n=2").await;
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0]["tags"], serde_json::json!(["four", "latest"]));
+        assert_eq!(pages[1]["tags"], serde_json::json!(["tag", "three"]));
+
+        // `last` doesn't have to be an existing tag
+        let pages = get_pages(cl, "/v2/onename/tags/list?last=m").await;
+        assert_eq!(pages[0]["tags"], serde_json::json!(["tag", "three"]));
+        let pages = get_pages(cl, "/v2/onename/tags/list?last=zzz").await;
+        assert_eq!(pages[0]["tags"], serde_json::json!([]));
+        let pages = get_pages(cl, "/v2/_catalog?n=1&last=a").await;
+        assert_eq!(pages[0]["repositories"], serde_json::json!([sorted[0]]));
+    }
+
     async fn upload_with_put(cl: &reqwest::Client, name: &str) {
         let resp = cl
-            .post(&format!("{}/v2/{}/blobs/uploads/", ORIGIN, name))
+            .post(format!("{}/v2/{}/blobs/uploads/", ORIGIN, name))
             .send()
             .await
             .unwrap();
@@ -203,7 +271,7 @@ mod interface_tests {
         let config = "{ }\n".as_bytes();
         let digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
         let resp = cl
-            .post(&format!(
+            .post(format!(
                 "{}/v2/{}/blobs/uploads/?digest={}",
                 ORIGIN, name, digest
             ))
@@ -221,10 +289,454 @@ mod interface_tests {
         assert_eq!(range, format!("0-{}", (config.len() - 1))); //note first byte is 0, hence len - 1
     }
 
+    async fn mount_blob(cl: &reqwest::Client, name: &str, from: &str) {
+        //Blob was uploaded to posttest in upload_with_post
+        let config = "{ }\n".as_bytes();
+        let digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .post(format!(
+                "{}/v2/{}/blobs/uploads/?mount={}&from={}",
+                ORIGIN, name, digest, from
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let location = resp
+            .headers()
+            .get(common::LOCATION_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(location, format!("{}/v2/{}/blobs/{}", ORIGIN, name, digest));
+
+        let resp = cl.get(location).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.bytes().await.unwrap(), config);
+    }
+
+    async fn mount_blob_fallback(cl: &reqwest::Client, name: &str, from: &str) {
+        let config = "{ }\n".as_bytes();
+        let digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .post(format!(
+                "{}/v2/{}/blobs/uploads/?mount={}&from={}",
+                ORIGIN, name, digest, from
+            ))
+            .send()
+            .await
+            .unwrap();
+        // Not readable from `from`, so we get a normal upload session
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert!(resp.headers().get(common::UPLOAD_HEADER).is_some());
+    }
+
+    async fn upload_status_and_cancel(cl: &reqwest::Client, name: &str) {
+        let resp = cl
+            .post(format!("{}/v2/{}/blobs/uploads/", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let location = resp
+            .headers()
+            .get(common::LOCATION_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let resp = cl.get(&location).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get(common::RANGE_HEADER).unwrap(), "0-0");
+
+        let resp = cl
+            .patch(&location)
+            .body(common::gen_rand_blob(10))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        let resp = cl.get(&location).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get(common::RANGE_HEADER).unwrap(), "0-9");
+        assert!(resp.headers().get(common::UPLOAD_HEADER).is_some());
+
+        let resp = cl.delete(&location).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = cl.get(&location).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = cl.delete(&location).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn head_blob(cl: &reqwest::Client, name: &str) {
+        //Blob was uploaded to posttest in upload_with_post
+        let config = "{ }\n".as_bytes();
+        let digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .head(format!("{}/v2/{}/blobs/{}", ORIGIN, name, digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Docker-Content-Digest").unwrap(),
+            &digest
+        );
+        assert_eq!(
+            resp.headers().get("Content-Length").unwrap(),
+            &config.len().to_string()
+        );
+
+        let resp = cl
+            .head(format!("{}/v2/{}/blobs/sha256:baadf00d", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn head_manifest(cl: &reqwest::Client, name: &str, tag: &str) {
+        let resp = cl
+            .head(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let headers = resp.headers().clone();
+        assert!(resp.bytes().await.unwrap().is_empty());
+
+        let resp = cl
+            .get(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .send()
+            .await
+            .unwrap();
+        for header in ["Docker-Content-Digest", "Content-Length", "Content-Type"] {
+            assert_eq!(headers.get(header), resp.headers().get(header));
+        }
+
+        let resp = cl
+            .head(format!("{}/v2/{}/manifests/nosuchtag", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Pushes an artifact referring to `subject` by digest and returns its digest
+    async fn push_referrer(
+        cl: &reqwest::Client,
+        name: &str,
+        subject: &serde_json::Value,
+        artifact_type: &str,
+    ) -> String {
+        let config = "{}".as_bytes();
+        let config_digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .post(format!(
+                "{}/v2/{}/blobs/uploads/?digest={}",
+                ORIGIN, name, config_digest
+            ))
+            .body(config)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": artifact_type,
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "size": config.len(),
+                "digest": config_digest,
+            },
+            "layers": [],
+            "subject": subject,
+            "annotations": { "org.example.type": artifact_type },
+        })
+        .to_string();
+        let manifest_digest =
+            digest::sha256_tag_digest(BufReader::new(manifest.as_bytes())).unwrap();
+        let resp = cl
+            .put(format!(
+                "{}/v2/{}/manifests/{}",
+                ORIGIN, name, manifest_digest
+            ))
+            .body(manifest)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            resp.headers().get("OCI-Subject").unwrap(),
+            subject["digest"].as_str().unwrap()
+        );
+        manifest_digest
+    }
+
+    async fn get_referrers(cl: &reqwest::Client, name: &str) {
+        common::upload_layer(cl, ORIGIN, name, "image").await;
+        let resp = cl
+            .get(format!("{}/v2/{}/manifests/image", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let image_digest = resp
+            .headers()
+            .get("Docker-Content-Digest")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let subject = serde_json::json!({
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "size": resp.bytes().await.unwrap().len(),
+            "digest": image_digest,
+        });
+
+        let sbom = push_referrer(cl, name, &subject, "application/vnd.example.sbom.v1").await;
+        let sig = push_referrer(cl, name, &subject, "application/vnd.example.sig.v1").await;
+
+        let resp = cl
+            .get(format!("{}/v2/{}/referrers/{}", ORIGIN, name, image_digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/vnd.oci.image.index.v1+json"
+        );
+        assert!(resp.headers().get("OCI-Filters-Applied").is_none());
+        let index: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(index["schemaVersion"], 2);
+        let mut digests: Vec<&str> = index["manifests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["digest"].as_str().unwrap())
+            .collect();
+        digests.sort();
+        let mut expected = vec![sbom.as_str(), sig.as_str()];
+        expected.sort();
+        assert_eq!(digests, expected);
+
+        let resp = cl
+            .get(format!(
+                "{}/v2/{}/referrers/{}?artifactType=application/vnd.example.sbom.v1",
+                ORIGIN, name, image_digest
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("OCI-Filters-Applied").unwrap(),
+            "artifactType"
+        );
+        let index: serde_json::Value = resp.json().await.unwrap();
+        let manifests = index["manifests"].as_array().unwrap();
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0]["digest"], sbom);
+        assert_eq!(
+            manifests[0]["artifactType"],
+            "application/vnd.example.sbom.v1"
+        );
+        assert_eq!(
+            manifests[0]["annotations"]["org.example.type"],
+            "application/vnd.example.sbom.v1"
+        );
+
+        // Referrers are per repository
+        let resp = cl
+            .get(format!("{}/v2/onename/referrers/{}", ORIGIN, image_digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let index: serde_json::Value = resp.json().await.unwrap();
+        assert!(index["manifests"].as_array().unwrap().is_empty());
+
+        let resp = cl
+            .get(format!("{}/v2/{}/referrers/notadigest", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Uploads `content` as a blob of `name` and returns its digest
+    async fn upload_blob(cl: &reqwest::Client, name: &str, content: &'static [u8]) -> String {
+        let digest = digest::sha256_tag_digest(BufReader::new(content)).unwrap();
+        let resp = cl
+            .post(format!(
+                "{}/v2/{}/blobs/uploads/?digest={}",
+                ORIGIN, name, digest
+            ))
+            .body(content)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        digest
+    }
+
+    async fn push_and_pull_artifacts(cl: &reqwest::Client, name: &str) {
+        let config = upload_blob(cl, name, b"{\"name\":\"chart\",\"version\":\"1.0.0\"}").await;
+        let chart = upload_blob(cl, name, b"not really a tarball").await;
+        let helm_chart = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.cncf.helm.config.v1+json",
+                "size": 34,
+                "digest": config,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.cncf.helm.chart.content.v1.tar+gzip",
+                "size": 20,
+                "digest": chart,
+            }],
+        })
+        .to_string();
+        let empty = upload_blob(cl, name, b"{}").await;
+        let sig = upload_blob(cl, name, b"signature").await;
+        let artifact = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.example.signature.v1",
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "size": 2,
+                "digest": empty,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.example.signature.v1+json",
+                "size": 9,
+                "digest": sig,
+            }],
+        })
+        .to_string();
+
+        for (tag, manifest, media_type) in [
+            (
+                "chart",
+                helm_chart,
+                "application/vnd.oci.image.manifest.v1+json",
+            ),
+            (
+                "sig",
+                artifact,
+                "application/vnd.oci.image.manifest.v1+json",
+            ),
+        ] {
+            let resp = cl
+                .put(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+                .header("Content-Type", media_type)
+                .body(manifest.clone())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::CREATED);
+
+            let resp = cl
+                .get(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), media_type);
+            assert_eq!(resp.text().await.unwrap(), manifest);
+        }
+    }
+
+    async fn push_sha512_image(cl: &reqwest::Client, name: &str) {
+        let config = "{\"sha512\":true}\n".as_bytes();
+        let config_digest = digest::sha512_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .post(format!(
+                "{}/v2/{}/blobs/uploads/?digest={}",
+                ORIGIN, name, config_digest
+            ))
+            .body(config)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = cl
+            .get(format!("{}/v2/{}/blobs/{}", ORIGIN, name, config_digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.bytes().await.unwrap(), config);
+
+        let manifest = format!(
+            r#"{{ "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                 "config": {{ "digest": "{}",
+                             "mediaType": "application/vnd.oci.image.config.v1+json",
+                             "size": {} }},
+                 "layers": [], "schemaVersion": 2 }}"#,
+            config_digest,
+            config.len()
+        );
+        let manifest_digest =
+            digest::sha512_tag_digest(BufReader::new(manifest.as_bytes())).unwrap();
+        let resp = cl
+            .put(format!(
+                "{}/v2/{}/manifests/{}",
+                ORIGIN, name, manifest_digest
+            ))
+            .body(manifest.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            resp.headers().get("Docker-Content-Digest").unwrap(),
+            &manifest_digest
+        );
+
+        let resp = cl
+            .get(format!(
+                "{}/v2/{}/manifests/{}",
+                ORIGIN, name, manifest_digest
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Docker-Content-Digest").unwrap(),
+            &manifest_digest
+        );
+
+        // The digest in the URL must match the content
+        let resp = cl
+            .put(format!(
+                "{}/v2/{}/manifests/{}",
+                ORIGIN,
+                name,
+                digest::sha512_tag_digest(BufReader::new("other".as_bytes())).unwrap()
+            ))
+            .body(manifest)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
     async fn push_oci_manifest(cl: &reqwest::Client, name: &str, tag: &str) -> String {
-        //Note config was uploaded as blob in earlier test
+        // Blobs have to be in the repository the manifest is pushed to
         let config = "{}\n".as_bytes();
-        let config_digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let config_digest = upload_blob(cl, name, b"{}\n").await;
 
         let manifest = format!(
             r#"{{ "mediaType": "application/vnd.oci.image.manifest.v1+json",
@@ -237,7 +749,7 @@ mod interface_tests {
         );
         let bytes = manifest.clone();
         let resp = cl
-            .put(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .put(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
             .body(bytes)
             .send()
             .await
@@ -261,8 +773,8 @@ mod interface_tests {
                 "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
                 "manifests": [
                   {{
-                    "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
-                    "size": 7143,
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 354,
                     "digest": "{}",
                     "platform": {{
                       "architecture": "ppc64le",
@@ -276,7 +788,7 @@ mod interface_tests {
         );
         let bytes = manifest.clone();
         let resp = cl
-            .put(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .put(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
             .body(bytes)
             .send()
             .await
@@ -288,14 +800,291 @@ mod interface_tests {
         digest
     }
 
+    async fn push_invalid_manifest_lists(cl: &reqwest::Client, name: &str) {
+        let image = push_oci_manifest(cl, name, "child").await;
+        let unknown = format!("sha256:{}", "0".repeat(64));
+        let cases = [
+            (
+                &unknown,
+                "application/vnd.oci.image.manifest.v1+json",
+                354,
+                "MANIFEST_BLOB_UNKNOWN",
+            ),
+            (
+                &image,
+                "application/vnd.oci.image.manifest.v1+json",
+                7143,
+                "MANIFEST_INVALID",
+            ),
+            (
+                &image,
+                "application/vnd.docker.distribution.manifest.v2+json",
+                354,
+                "MANIFEST_INVALID",
+            ),
+        ];
+        for (digest, media_type, size, code) in cases {
+            let manifest = serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [{ "mediaType": media_type, "size": size, "digest": digest }],
+            });
+            let resp = cl
+                .put(format!("{}/v2/{}/manifests/list", ORIGIN, name))
+                .body(manifest.to_string())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["errors"][0]["code"], code);
+            // The offending child is named
+            assert!(body["errors"][0]["detail"]
+                .to_string()
+                .contains(digest.as_str()));
+        }
+    }
+
+    async fn push_manifest_with_wrong_sizes(cl: &reqwest::Client, name: &str) {
+        let config = upload_blob(cl, name, b"{\"wrong\":\"sizes\"}").await;
+        let layer = upload_blob(cl, name, b"twelve bytes").await;
+        let cases = [
+            (17, "application/vnd.oci.image.layer.v1.tar", 13, &layer),
+            (18, "application/vnd.oci.image.layer.v1.tar", 12, &config),
+            (17, "not-a-media-type", 12, &layer),
+        ];
+        for (config_size, layer_type, layer_size, culprit) in cases {
+            let manifest = serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "size": config_size,
+                    "digest": config,
+                },
+                "layers": [{ "mediaType": layer_type, "size": layer_size, "digest": layer }],
+            });
+            let resp = cl
+                .put(format!("{}/v2/{}/manifests/wrong", ORIGIN, name))
+                .body(manifest.to_string())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["errors"][0]["code"], "MANIFEST_INVALID");
+            assert!(body["errors"][0]["detail"]
+                .to_string()
+                .contains(culprit.as_str()));
+        }
+    }
+
+    async fn push_manifest_with_other_repos_blob(cl: &reqwest::Client, name: &str, other: &str) {
+        let config = upload_blob(cl, name, b"{\"other\":\"repo\"}").await;
+        let layer = upload_blob(cl, other, b"only in the other repo").await;
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "size": 16,
+                "digest": config,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "size": 22,
+                "digest": layer,
+            }],
+        });
+        let resp = cl
+            .put(format!("{}/v2/{}/manifests/stolen", ORIGIN, name))
+            .body(manifest.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], "MANIFEST_BLOB_UNKNOWN");
+    }
+
+    async fn push_immutable_tags(cl: &reqwest::Client, name: &str) {
+        let config = upload_blob(cl, name, b"{}").await;
+        let first = upload_blob(cl, name, b"first").await;
+        let second = upload_blob(cl, name, b"second").await;
+        let manifest = |layer: &str| {
+            serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "size": 2,
+                    "digest": config,
+                },
+                "layers": [{
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                    "digest": layer,
+                }],
+            })
+            .to_string()
+        };
+        let push = |tag: &str, body: String| {
+            cl.put(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+                .body(body)
+                .send()
+        };
+
+        let resp = push("v1", manifest(&first)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let digest = resp.headers()["Docker-Content-Digest"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        // Pushing the same manifest again is fine
+        let resp = push("v1", manifest(&first)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = push("v1", manifest(&second)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], "DENIED");
+
+        // Tags that don't match the pattern can still be moved
+        for layer in [&first, &second] {
+            let resp = push("latest", manifest(layer)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        // Nor can the tag be deleted, directly or with the manifest it points to
+        for reference in ["v1", &digest] {
+            let resp = cl
+                .delete(format!("{}/v2/{}/manifests/{}", ORIGIN, name, reference))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["errors"][0]["code"], "DENIED");
+        }
+    }
+
+    async fn push_over_quota(cl: &reqwest::Client, name: &str, full_name: &str) {
+        let other = format!("{}/other", name);
+        let first = upload_blob(cl, &other, b"forty bytes of data to fill up the quota").await;
+        let image = |annotations: serde_json::Value| {
+            serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {
+                    "mediaType": "application/vnd.oci.image.config.v1+json",
+                    "size": 40,
+                    "digest": first,
+                },
+                "layers": [],
+                "annotations": annotations,
+            })
+            .to_string()
+        };
+        let push = |repo: String, manifest: String| {
+            cl.put(format!("{}/v2/{}/manifests/latest", ORIGIN, repo))
+                .body(manifest)
+                .send()
+        };
+        // Only blobs referenced by manifests count, so the first upload is free until it is pushed
+        let manifest = image(serde_json::json!({}));
+        let resp = push(other, manifest.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let usage = 40 + manifest.len();
+
+        // Blobs already stored in the namespace don't count again
+        upload_blob(cl, name, b"forty bytes of data to fill up the quota").await;
+
+        let content = b"another forty bytes that won't fit in it";
+        let digest = digest::sha256_tag_digest(BufReader::new(&content[..])).unwrap();
+        let resp = cl
+            .post(format!(
+                "{}/v2/{}/blobs/uploads/?digest={}",
+                ORIGIN, name, digest
+            ))
+            .body(&content[..])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], "DENIED");
+
+        // Nor does another manifest
+        let resp = push(
+            name.to_string(),
+            image(serde_json::json!({ "version": "2" })),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Uploads can't even start in a full namespace
+        let resp = cl
+            .post(format!("{}/v2/{}/blobs/uploads/", ORIGIN, full_name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let metrics = cl
+            .get(format!("{}/metrics", ORIGIN))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains(&format!(
+            "quota_usage_bytes{{namespace=\"{}\",type=\"quota\"}} {}",
+            name, usage
+        )));
+    }
+
+    async fn check_request_metrics(cl: &reqwest::Client) {
+        // Pulled after the tag was pushed in push_immutable_tags
+        let resp = cl
+            .get(format!("{}/v2/immutabletest/manifests/v1", ORIGIN))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let size = resp.bytes().await.unwrap().len();
+
+        let metrics = cl
+            .get(format!("{}/metrics", ORIGIN))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        for expected in [
+            "total_pushes{repo=\"immutabletest\",type=\"repository\"} 4".to_string(),
+            "total_pulls{repo=\"immutabletest\",type=\"repository\"} 1".to_string(),
+            format!(
+                "total_bytes_served{{repo=\"immutabletest\",type=\"repository\"}} {}",
+                size
+            ),
+            "http_request_duration_seconds_count{method=\"PUT\",route=\"/v2/:one/manifests/:reference\",status=\"403\",type=\"http\"}".to_string(),
+            "upload_duration_seconds_count".to_string(),
+            "active_uploads{type=\"uploads\"}".to_string(),
+        ] {
+            assert!(metrics.contains(&expected), "missing {}", expected);
+        }
+    }
+
     async fn push_oci_manifest_with_foreign_blob(
         cl: &reqwest::Client,
         name: &str,
         tag: &str,
     ) -> String {
-        //Note config was uploaded as blob in earlier test
+        // Blobs have to be in the repository the manifest is pushed to
         let config = "{}\n".as_bytes();
-        let config_digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let config_digest = upload_blob(cl, name, b"{}\n").await;
 
         let manifest = format!(
             r#"{{ "mediaType": "application/vnd.oci.image.manifest.v1+json",
@@ -317,7 +1106,7 @@ mod interface_tests {
         );
         let bytes = manifest.clone();
         let resp = cl
-            .put(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .put(format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
             .body(bytes)
             .send()
             .await
@@ -331,7 +1120,7 @@ mod interface_tests {
 
     async fn delete_manifest(cl: &reqwest::Client, name: &str, digest: &str) {
         let resp = cl
-            .delete(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, digest))
+            .delete(format!("{}/v2/{}/manifests/{}", ORIGIN, name, digest))
             .send()
             .await
             .unwrap();
@@ -340,7 +1129,7 @@ mod interface_tests {
 
     async fn delete_non_existent_manifest(cl: &reqwest::Client, name: &str) {
         let resp = cl
-            .delete(&format!(
+            .delete(format!(
                 "{}/v2/{}/manifests/{}",
                 ORIGIN,
                 name,
@@ -349,16 +1138,50 @@ mod interface_tests {
             .send()
             .await
             .unwrap();
-        // If it doesn't exist, that's kinda the same as deleted, right?
-        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], "MANIFEST_UNKNOWN");
+    }
+    async fn delete_non_existent_tag(cl: &reqwest::Client, name: &str) {
+        let resp = cl
+            .delete(format!("{}/v2/{}/manifests/nosuchtag", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
-    async fn attempt_delete_by_tag(cl: &reqwest::Client, name: &str, tag: &str) {
+
+    async fn delete_tag(cl: &reqwest::Client, name: &str) {
+        common::upload_layer(cl, ORIGIN, name, "one").await;
+        let resp = cl
+            .get(format!("{}/v2/{}/manifests/one", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        let digest = resp.headers()["Docker-Content-Digest"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let manifest = resp.bytes().await.unwrap();
         let resp = cl
-            .delete(&format!("{}/v2/{}/manifests/{}", ORIGIN, name, tag))
+            .put(format!("{}/v2/{}/manifests/two", ORIGIN, name))
+            .body(manifest)
             .send()
             .await
             .unwrap();
-        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = cl
+            .delete(format!("{}/v2/{}/manifests/one", ORIGIN, name))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        get_non_existent_manifest(cl, name, "one").await;
+        // Other tags for the same manifest are left alone
+        get_manifest(cl, name, "two", None).await;
+        get_manifest(cl, name, &digest, None).await;
     }
 
     async fn delete_config_blob(cl: &reqwest::Client, name: &str) {
@@ -366,15 +1189,30 @@ mod interface_tests {
         let config = "{}\n".as_bytes();
         let config_digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
         let resp = cl
-            .delete(&format!("{}/v2/{}/blobs/{}", ORIGIN, name, config_digest))
+            .delete(format!("{}/v2/{}/blobs/{}", ORIGIN, name, config_digest))
             .send()
             .await
             .unwrap();
         assert_eq!(resp.status(), StatusCode::ACCEPTED);
     }
 
+    async fn delete_referenced_config_blob(cl: &reqwest::Client, name: &str, manifest: &str) {
+        let config = "{}\n".as_bytes();
+        let config_digest = digest::sha256_tag_digest(BufReader::new(config)).unwrap();
+        let resp = cl
+            .delete(format!("{}/v2/{}/blobs/{}", ORIGIN, name, config_digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["errors"][0]["code"], "DENIED");
+        let reason = body["errors"][0]["detail"]["Reason"].as_str().unwrap();
+        assert!(reason.contains(&format!("{}@{}", name, manifest)));
+    }
+
     async fn get_health(cl: &reqwest::Client) {
-        let resp = cl.get(&format!("{}/healthz", ORIGIN)).send().await.unwrap();
+        let resp = cl.get(format!("{}/healthz", ORIGIN)).send().await.unwrap();
 
         assert_eq!(resp.status(), StatusCode::OK);
 
@@ -385,7 +1223,7 @@ mod interface_tests {
 
     async fn get_readiness(cl: &reqwest::Client) {
         let resp = cl
-            .get(&format!("{}/readiness", ORIGIN))
+            .get(format!("{}/readiness", ORIGIN))
             .send()
             .await
             .unwrap();
@@ -398,7 +1236,7 @@ mod interface_tests {
     }
 
     async fn get_metrics(cl: &reqwest::Client) {
-        let resp = cl.get(&format!("{}/metrics", ORIGIN)).send().await.unwrap();
+        let resp = cl.get(format!("{}/metrics", ORIGIN)).send().await.unwrap();
 
         assert_eq!(resp.status(), StatusCode::OK);
 
@@ -414,14 +1252,14 @@ mod interface_tests {
         assert!(body.contains("total_blob_requests{type=\"blobs\"} 9"));
 
         get_manifest(cl, "onename", "tag", None).await;
-        let manifest_response = cl.get(&format!("{}/metrics", ORIGIN)).send().await.unwrap();
+        let manifest_response = cl.get(format!("{}/metrics", ORIGIN)).send().await.unwrap();
 
         let manifest_body = manifest_response.text().await.unwrap();
 
         assert!(manifest_body.contains("total_manifest_requests{type=\"manifests\"} 7"));
 
         get_non_existent_blob(cl).await;
-        let blob_response = cl.get(&format!("{}/metrics", ORIGIN)).send().await.unwrap();
+        let blob_response = cl.get(format!("{}/metrics", ORIGIN)).send().await.unwrap();
 
         assert_eq!(blob_response.status(), StatusCode::OK);
 
@@ -464,20 +1302,24 @@ mod interface_tests {
         println!("Running push_oci_manifest()");
         let manifest_digest = push_oci_manifest(&client, "puttest", "puttest1").await;
         println!("Running push_manifest_list()");
+        let list_child = push_oci_manifest(&client, "listtest", "child").await;
         let digest_manifest_list =
-            push_manifest_list(&client, &manifest_digest, "listtest", "listtest1").await;
+            push_manifest_list(&client, &list_child, "listtest", "listtest1").await;
         println!("Running get_manifest(puttest:puttest1)");
         get_manifest(&client, "puttest", "puttest1", Some(354)).await;
         println!("Running get_manifest(puttest:digest)");
         get_manifest(&client, "puttest", &manifest_digest, Some(354)).await;
+        println!("Running delete_referenced_config_blob(puttest)");
+        delete_referenced_config_blob(&client, "puttest", &manifest_digest).await;
         println!("Running delete_manifest(puttest:digest)");
         delete_manifest(&client, "puttest", &manifest_digest).await;
         println!("Running delete_manifest(listtest)");
         delete_manifest(&client, "listtest", &digest_manifest_list).await;
+        delete_manifest(&client, "listtest", &list_child).await;
         println!("Running delete_non_existent_manifest(onename)");
         delete_non_existent_manifest(&client, "onename").await;
-        println!("Running attempt_delete_by_tag(onename:tag)");
-        attempt_delete_by_tag(&client, "onename", "tag").await;
+        println!("Running delete_non_existent_tag(onename)");
+        delete_non_existent_tag(&client, "onename").await;
         println!("Running get_non_existent_manifest(puttest:puttest1)");
         get_non_existent_manifest(&client, "puttest", "puttest1").await;
 
@@ -543,5 +1385,46 @@ mod interface_tests {
         println!("Running get_metrics");
         get_metrics(&client).await;
         check_tag_list_n_last(&client, 2, "latest", &tl4).await;
+
+        println!("Running get_blob_from_other_repo(posttest)");
+        get_blob_from_other_repo(&client, "posttest").await;
+
+        println!("Running mount_blob(mounttest)");
+        mount_blob(&client, "mounttest", "posttest").await;
+        println!("Running mount_blob_fallback(mounttest2)");
+        mount_blob_fallback(&client, "mounttest2", "puttest").await;
+        println!("Running mount_blob_fallback(mounttest2) with an invalid repository");
+        mount_blob_fallback(&client, "mounttest2", "nosuchrepo/../posttest").await;
+        println!("Running mount_blob(mounttest) for blob already in repo");
+        mount_blob(&client, "mounttest", "nosuchrepo").await;
+
+        println!("Running upload_status_and_cancel(statustest)");
+        upload_status_and_cancel(&client, "statustest").await;
+        println!("Running head_blob(posttest)");
+        head_blob(&client, "posttest").await;
+        println!("Running head_manifest(onename:tag)");
+        head_manifest(&client, "onename", "tag").await;
+        println!("Running push_sha512_image(sha512test)");
+        push_sha512_image(&client, "sha512test").await;
+        println!("Running delete_tag(tagdeletetest)");
+        delete_tag(&client, "tagdeletetest").await;
+        println!("Running get_referrers(referrerstest)");
+        get_referrers(&client, "referrerstest").await;
+        println!("Running push_and_pull_artifacts(artifacttest)");
+        push_and_pull_artifacts(&client, "artifacttest").await;
+        println!("Running push_invalid_manifest_lists(invalidlisttest)");
+        push_invalid_manifest_lists(&client, "invalidlisttest").await;
+        println!("Running push_manifest_with_wrong_sizes(sizetest)");
+        push_manifest_with_wrong_sizes(&client, "sizetest").await;
+        println!("Running push_manifest_with_other_repos_blob(blobthief, blobowner)");
+        push_manifest_with_other_repos_blob(&client, "blobthief", "blobowner").await;
+        println!("Running paginate_catalog_and_tags()");
+        paginate_catalog_and_tags(&client).await;
+        println!("Running push_immutable_tags(immutabletest)");
+        push_immutable_tags(&client, "immutabletest").await;
+        println!("Running push_over_quota(quotatest, quotafull)");
+        push_over_quota(&client, "quotatest", "quotafull").await;
+        println!("Running check_request_metrics()");
+        check_request_metrics(&client).await;
     }
 }
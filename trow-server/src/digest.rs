@@ -1,8 +1,8 @@
 use std::io::Read;
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 // Crypto and crypto related imports
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 
 // Buffer size for SHA2 hashing
 const BUFFER_SIZE: usize = 1024;
@@ -32,11 +32,56 @@ pub fn sha256_tag_digest<R: Read>(mut reader: R) -> Result<String> {
     Ok(format!("sha256:{}", digest))
 }
 
+pub fn sha512_tag_digest<R: Read>(mut reader: R) -> Result<String> {
+    let digest = digest::<Sha512, _>(&mut reader)?;
+    Ok(format!("sha512:{}", digest))
+}
+
+/// Computes the digest of data received in chunks
+pub(crate) enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestHasher {
+    pub(crate) fn new(algorithm: &str) -> Result<Self> {
+        match algorithm {
+            "sha256" => Ok(DigestHasher::Sha256(Sha256::new())),
+            "sha512" => Ok(DigestHasher::Sha512(Sha512::new())),
+            _ => Err(anyhow!("Hash algorithm {} not supported", algorithm)),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            DigestHasher::Sha256(sh) => sh.update(data),
+            DigestHasher::Sha512(sh) => sh.update(data),
+        }
+    }
+
+    /// The digest of all the data, e.g. `sha256:...`
+    pub(crate) fn finish(self) -> String {
+        match self {
+            DigestHasher::Sha256(sh) => format!("sha256:{}", hex::encode(sh.finalize())),
+            DigestHasher::Sha512(sh) => format!("sha512:{}", hex::encode(sh.finalize())),
+        }
+    }
+}
+
+/// Digest of the data using the given algorithm, e.g. `sha512:...`
+pub fn tag_digest<R: Read>(algorithm: &str, reader: R) -> Result<String> {
+    match algorithm {
+        "sha256" => sha256_tag_digest(reader),
+        "sha512" => sha512_tag_digest(reader),
+        _ => Err(anyhow!("Hash algorithm {} not supported", algorithm)),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::BufReader;
 
-    use crate::digest::{sha256_digest, sha256_tag_digest};
+    use crate::digest::{sha256_digest, sha256_tag_digest, tag_digest, DigestHasher};
 
     #[test]
     fn sha256_digest_test() {
@@ -97,4 +142,28 @@ mod test {
             "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"
         );
     }
+
+    #[test]
+    fn tag_digest_test() {
+        let result = tag_digest("sha512", BufReader::new("hello world".as_bytes())).unwrap();
+        assert_eq!(
+            result,
+            "sha512:309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f"
+        );
+        let result = tag_digest("sha256", BufReader::new("hello world".as_bytes())).unwrap();
+        assert!(result.starts_with("sha256:b94d27b9"));
+        assert!(tag_digest("md5", BufReader::new("hello world".as_bytes())).is_err());
+    }
+
+    #[test]
+    fn hasher_matches_tag_digest() {
+        for alg in ["sha256", "sha512"] {
+            let mut hasher = DigestHasher::new(alg).unwrap();
+            hasher.update(b"hello ");
+            hasher.update(b"world");
+            let expected = tag_digest(alg, BufReader::new("hello world".as_bytes())).unwrap();
+            assert_eq!(hasher.finish(), expected);
+        }
+        assert!(DigestHasher::new("md5").is_err());
+    }
 }
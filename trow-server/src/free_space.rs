@@ -0,0 +1,97 @@
+//! Refusing writes when the disk holding the data directory is almost full.
+//!
+//! Below the low-water mark, new uploads and proxy downloads are refused and the registry reports
+//! itself as not ready, instead of failing halfway through writes with internal errors. Reads,
+//! deletions and uploads that are already in progress are not affected.
+
+use anyhow::{anyhow, Result};
+use thiserror::Error;
+use tonic::Status;
+use tracing::{event, Level};
+
+use crate::server::TrowServer;
+
+#[derive(Error, Debug)]
+#[error("Only {available} bytes of disk space are available, below the minimum of {minimum} bytes")]
+pub struct LowFreeSpaceError {
+    available: u64,
+    minimum: u64,
+}
+
+/// Parses a size in bytes, optionally with a `K`, `M`, `G` or `T` suffix (powers of 1024)
+pub fn parse_size(size: &str) -> Result<u64> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'G')) => (&size[..i], 1 << 30),
+        Some((i, 'T')) => (&size[..i], 1 << 40),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("Invalid size `{}`", size))
+}
+
+/// Maps errors from free space checks to gRPC statuses
+pub(crate) fn free_space_status(e: anyhow::Error) -> Status {
+    match e.downcast::<LowFreeSpaceError>() {
+        Ok(e) => Status::resource_exhausted(e.to_string()),
+        Err(e) => {
+            event!(Level::ERROR, "Failed to check free disk space: {:?}", e);
+            Status::internal("Internal error checking free disk space")
+        }
+    }
+}
+
+impl TrowServer {
+    /// Checks that the space available in the data directory is above the low-water mark
+    pub(crate) fn check_free_space(&self) -> Result<()> {
+        if self.min_free_space == 0 {
+            return Ok(());
+        }
+        let available = fs3::available_space(&self.blobs_path)?;
+        if available < self.min_free_space {
+            return Err(LowFreeSpaceError {
+                available,
+                minimum: self.min_free_space,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_size, LowFreeSpaceError};
+    use crate::server::{ServerPolicies, TrowServer};
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("3K").unwrap(), 3072);
+        assert_eq!(parse_size("10G").unwrap(), 10 << 30);
+        for invalid in ["", "G", "1X", "-1", "99999999T"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn refuses_writes_below_minimum() {
+        let dir = tempfile::tempdir().unwrap();
+        let with_minimum = |min_free_space| {
+            let policies = ServerPolicies {
+                min_free_space,
+                ..Default::default()
+            };
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, policies).unwrap()
+        };
+        assert!(with_minimum(0).check_free_space().is_ok());
+        assert!(with_minimum(1).check_free_space().is_ok());
+
+        let err = with_minimum(u64::MAX).check_free_space().unwrap_err();
+        assert!(err.is::<LowFreeSpaceError>());
+    }
+}
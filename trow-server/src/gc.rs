@@ -0,0 +1,377 @@
+//! Mark-and-sweep garbage collection of blobs.
+//!
+//! Every manifest in the reference index is considered live, along with every blob it references.
+//! The index holds every digest listed in a tag history file under `manifests/`: the full history
+//! is kept rather than just the current digest, as it can still be pulled by digest and is exposed
+//! through the manifest history endpoint. The children of manifest lists are marked as well, along
+//! with the blobs they reference: they usually have a history of their own, but it may have been
+//! deleted while the list still points at them.
+//!
+//! Anything else in `blobs/` is garbage. The exception is blobs that were written recently: layers
+//! are uploaded before the manifest that references them, so a young unreferenced blob is most
+//! likely part of a push that is still in progress. Mounting a blob resets its age for the same
+//! reason.
+//!
+//! Pushes that are being verified or completed hold the GC lock for reading, and GC holds it for
+//! writing from the mark to the sweep, so a blob can't be referenced after it was found unused.
+
+use std::collections::HashSet;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use tracing::{event, Level};
+
+use crate::manifest::Manifest;
+use crate::server::trow_server::{CollectedBlob, GarbageCollectionReport};
+use crate::server::{RepoIterator, TrowServer, PROXY_DIR, SUPPORTED_DIGESTS};
+
+/// Unreferenced blobs younger than this are left alone.
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+impl TrowServer {
+    /// Deletes all blobs that aren't referenced from any tag history and are older than
+    /// `grace_period`. If `dry_run` is set, nothing is deleted but the report is still produced.
+    pub(crate) fn collect_garbage(
+        &self,
+        dry_run: bool,
+        grace_period: Duration,
+    ) -> Result<GarbageCollectionReport> {
+        event!(
+            Level::INFO,
+            "Starting garbage collection (dry run: {})",
+            dry_run
+        );
+        let _gc_guard = self.gc_lock.blocking_write();
+        let live = self.mark_live_digests();
+
+        let mut blobs = vec![];
+        let now = SystemTime::now();
+        for alg in SUPPORTED_DIGESTS {
+            let alg_dir = self.blobs_path.join(alg);
+            if !alg_dir.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(&alg_dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let digest = format!("{}:{}", alg, entry.file_name().to_string_lossy());
+                if live.contains(&digest) {
+                    continue;
+                }
+                let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+                if age < grace_period {
+                    event!(Level::DEBUG, "Skipping recently written blob {}", digest);
+                    continue;
+                }
+                if !dry_run {
+                    if let Err(e) = fs::remove_file(entry.path()) {
+                        event!(Level::WARN, "Failed to delete blob {}: {:?}", digest, e);
+                        continue;
+                    }
+                    #[cfg(feature = "sqlite")]
+                    if let Err(e) = self.metadata.remove_blob(&digest) {
+                        event!(Level::WARN, "Failed to forget blob {}: {:?}", digest, e);
+                    }
+                }
+                blobs.push(CollectedBlob {
+                    digest,
+                    size: metadata.len(),
+                });
+            }
+        }
+        blobs.sort_by(|a, b| a.digest.cmp(&b.digest));
+        if !dry_run {
+            self.remove_dangling_links()?;
+        }
+
+        let total_bytes = blobs.iter().map(|b| b.size).sum();
+        event!(
+            Level::INFO,
+            "Garbage collection found {} unreferenced blobs ({} bytes)",
+            blobs.len(),
+            total_bytes
+        );
+        Ok(GarbageCollectionReport {
+            blobs,
+            total_bytes,
+            dry_run,
+        })
+    }
+
+    fn mark_live_digests(&self) -> HashSet<String> {
+        let index = self.reference_index.read().unwrap();
+        let mut live = HashSet::new();
+        for (manifest, blobs) in index.manifests() {
+            live.insert(manifest.digest.clone());
+            live.extend(blobs.iter().cloned());
+        }
+        let mut marked = HashSet::new();
+        for (manifest, _) in index.manifests() {
+            self.mark_manifest(&manifest.digest, &mut marked, &mut live);
+        }
+        live
+    }
+
+    /// Marks `digest` and the blobs it references, recursing into manifest lists
+    fn mark_manifest(
+        &self,
+        digest: &str,
+        marked: &mut HashSet<String>,
+        live: &mut HashSet<String>,
+    ) {
+        if !marked.insert(digest.to_string()) {
+            return;
+        }
+        live.insert(digest.to_string());
+        let manifest = match self.read_manifest(digest) {
+            Ok(m) => m,
+            Err(e) => {
+                // Manifests of proxied images may not have been fetched yet
+                event!(Level::DEBUG, "Could not read manifest {}: {:?}", digest, e);
+                return;
+            }
+        };
+        for asset in manifest.get_local_asset_digests() {
+            match manifest {
+                Manifest::List(_) => self.mark_manifest(asset, marked, live),
+                Manifest::V2(_) => {
+                    live.insert(asset.to_string());
+                }
+            }
+        }
+    }
+
+    /// Removes repository links to blobs that no longer exist. Blobs of proxied images are only
+    /// fetched when first requested, so their links are kept while a manifest of the repository
+    /// still references them.
+    fn remove_dangling_links(&self) -> Result<()> {
+        for link in RepoIterator::new(&self.links_path)? {
+            let path = link.path();
+            // Links are stored as `<repo>/_blobs/<alg>/<value>`
+            let alg_dir = path.parent();
+            let repo_name = alg_dir
+                .and_then(|p| p.parent())
+                .and_then(|p| p.parent())
+                .and_then(|p| p.strip_prefix(&self.links_path).ok())
+                .map(|r| r.to_string_lossy().to_string());
+            let (repo_name, alg, val) = match (
+                repo_name,
+                alg_dir.and_then(|p| p.file_name()),
+                path.file_name(),
+            ) {
+                (Some(r), Some(alg), Some(val)) => (r, alg, val),
+                _ => continue,
+            };
+            if self.blobs_path.join(alg).join(val).exists() {
+                continue;
+            }
+            let digest = format!("{}:{}", alg.to_string_lossy(), val.to_string_lossy());
+            if repo_name.starts_with(PROXY_DIR)
+                && self
+                    .reference_index
+                    .read()
+                    .unwrap()
+                    .referrers(&digest)
+                    .iter()
+                    .any(|m| m.repo_name == repo_name)
+            {
+                continue;
+            }
+            event!(Level::DEBUG, "Removing dangling link {:?}", path);
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::GC_GRACE_PERIOD;
+    use crate::digest::sha256_tag_digest;
+    use crate::server::TrowServer;
+
+    fn add_blob(server: &TrowServer, content: &[u8]) -> String {
+        let digest = sha256_tag_digest(content).unwrap();
+        let path = server.get_catalog_path_for_blob(&digest).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(content).unwrap();
+        digest
+    }
+
+    fn add_tag(server: &TrowServer, repo: &str, tag: &str, digest: &str) {
+        let repo_dir = server.manifests_path.join(repo);
+        fs::create_dir_all(&repo_dir).unwrap();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(repo_dir.join(tag))
+            .unwrap();
+        writeln!(file, "{} 2023-05-30T09:21:14.081204316Z", digest).unwrap();
+        server.reindex_repo(repo).unwrap();
+    }
+
+    fn image_manifest(config: &str, layer: &str) -> String {
+        format!(
+            r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {{ "mediaType": "application/vnd.oci.image.config.v1+json", "size": 2, "digest": "{}" }},
+                "layers": [{{ "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 5, "digest": "{}" }}]
+            }}"#,
+            config, layer
+        )
+    }
+
+    #[test]
+    fn collects_only_unreferenced_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+
+        let config = add_blob(&server, b"{}");
+        let layer = add_blob(&server, b"layer");
+        let image = add_blob(&server, image_manifest(&config, &layer).as_bytes());
+        let list = add_blob(
+            &server,
+            format!(
+                r#"{{
+                    "schemaVersion": 2,
+                    "mediaType": "application/vnd.oci.image.index.v1+json",
+                    "manifests": [{{ "mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 1, "digest": "{}" }}]
+                }}"#,
+                image
+            )
+            .as_bytes(),
+        );
+        let old_config = add_blob(&server, b"{\"old\":true}");
+        let old_image = add_blob(&server, image_manifest(&old_config, &layer).as_bytes());
+        let orphan = add_blob(&server, b"orphan");
+
+        add_tag(&server, "multi/arch", "latest", &list);
+        add_tag(&server, "single", "v1", &old_image);
+
+        // Recent blobs are protected by the grace period
+        let report = server.collect_garbage(true, GC_GRACE_PERIOD).unwrap();
+        assert!(report.blobs.is_empty());
+
+        let report = server.collect_garbage(true, Duration::ZERO).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.blobs.len(), 1);
+        assert_eq!(report.blobs[0].digest, orphan);
+        assert_eq!(report.total_bytes, 6);
+        let orphan_path = server.get_catalog_path_for_blob(&orphan).unwrap();
+        assert!(orphan_path.exists());
+
+        let orphan_link = server
+            .links_path
+            .join("single/_blobs/sha256")
+            .join(&orphan[7..]);
+        fs::create_dir_all(orphan_link.parent().unwrap()).unwrap();
+        File::create(&orphan_link).unwrap();
+
+        let report = server.collect_garbage(false, Duration::ZERO).unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(report.blobs.len(), 1);
+        assert!(!orphan_path.exists());
+        assert!(!orphan_link.exists());
+        for digest in [config, layer, image, list, old_config, old_image] {
+            assert!(server.get_catalog_path_for_blob(&digest).unwrap().exists());
+        }
+    }
+
+    #[test]
+    fn keeps_history_of_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+
+        let config = add_blob(&server, b"{}");
+        let first = add_blob(
+            &server,
+            image_manifest(&config, &add_blob(&server, b"one")).as_bytes(),
+        );
+        let second = add_blob(
+            &server,
+            image_manifest(&config, &add_blob(&server, b"two")).as_bytes(),
+        );
+        add_tag(&server, "repo", "latest", &first);
+        add_tag(&server, "repo", "latest", &second);
+
+        let report = server.collect_garbage(false, Duration::ZERO).unwrap();
+        assert!(report.blobs.is_empty());
+        assert_eq!(report.total_bytes, 0);
+    }
+
+    #[test]
+    fn waits_for_pushes_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+
+        // Old blobs that nothing references yet, e.g. mounted from another repository
+        let config = add_blob(&server, b"{}");
+        let layer = add_blob(&server, b"layer");
+        let image = add_blob(&server, image_manifest(&config, &layer).as_bytes());
+
+        // A push referencing them is being verified
+        let push = server.gc_lock.blocking_read();
+        let gc = {
+            let server = server.clone();
+            thread::spawn(move || server.collect_garbage(false, Duration::ZERO).unwrap())
+        };
+        thread::sleep(Duration::from_millis(200));
+        assert!(!gc.is_finished());
+        add_tag(&server, "repo", "v1", &image);
+        drop(push);
+
+        let report = gc.join().unwrap();
+        assert!(report.blobs.is_empty());
+        for digest in [config, layer, image] {
+            assert!(server.get_catalog_path_for_blob(&digest).unwrap().exists());
+        }
+    }
+
+    #[test]
+    fn keeps_links_of_uncached_proxied_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+
+        // The config and layer haven't been fetched from upstream yet
+        let config = sha256_tag_digest(&b"{}"[..]).unwrap();
+        let layer = sha256_tag_digest(&b"layer"[..]).unwrap();
+        let image = add_blob(&server, image_manifest(&config, &layer).as_bytes());
+        add_tag(&server, "f/mock/img", "latest", &image);
+        let link = |repo: &str, digest: &str| {
+            server
+                .links_path
+                .join(repo)
+                .join("_blobs/sha256")
+                .join(&digest[7..])
+        };
+        let missing = sha256_tag_digest(&b"missing"[..]).unwrap();
+        for (repo, digest) in [
+            ("f/mock/img", &layer),
+            ("f/mock/img", &missing),
+            ("single", &layer),
+        ] {
+            let path = link(repo, digest);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        server.collect_garbage(false, Duration::ZERO).unwrap();
+        assert!(link("f/mock/img", &layer).exists());
+        assert!(!link("f/mock/img", &missing).exists());
+        assert!(!link("single", &layer).exists());
+    }
+}
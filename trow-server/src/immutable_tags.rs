@@ -0,0 +1,84 @@
+//! Tags that can't be moved once pushed.
+//!
+//! Patterns have the form `<repository>:<tag>`, where `*` matches any sequence of characters,
+//! including `/`. For example `prod/*:v*` makes every tag starting with `v` immutable in all
+//! repositories under `prod/`. A pattern without a `:` applies to every tag of the matching
+//! repositories.
+//!
+//! An immutable tag can still be pushed again with the digest it already points to, so retried
+//! pushes succeed.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImmutableTagsConfig {
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+/// Matches `text` against `pattern`, where `*` matches any sequence of characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There is always a first part, possibly empty
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    // Without any `*`, the whole text must have been matched
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl ImmutableTagsConfig {
+    pub(crate) fn is_immutable(&self, repo_name: &str, tag: &str) -> bool {
+        self.patterns.iter().any(|pattern| {
+            let (repo_pattern, tag_pattern) = pattern.rsplit_once(':').unwrap_or((pattern, "*"));
+            glob_match(repo_pattern, repo_name) && glob_match(tag_pattern, tag)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob_match, ImmutableTagsConfig};
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("prod/*", "prod/web"));
+        assert!(glob_match("prod/*", "prod/team/web"));
+        assert!(!glob_match("prod/*", "dev/web"));
+        assert!(glob_match("v*", "v1.2.3"));
+        assert!(!glob_match("v*", "latest"));
+        assert!(glob_match("*-rc*", "v1-rc2"));
+        assert!(!glob_match("*-rc*", "v1"));
+        assert!(glob_match("a*a", "aa"));
+        assert!(!glob_match("a*a", "a"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn matches_repo_and_tag() {
+        let config = ImmutableTagsConfig {
+            patterns: vec!["prod/*:v*".to_string(), "releases".to_string()],
+        };
+        assert!(config.is_immutable("prod/web", "v1.0.0"));
+        assert!(!config.is_immutable("prod/web", "latest"));
+        assert!(!config.is_immutable("dev/web", "v1.0.0"));
+        assert!(config.is_immutable("releases", "latest"));
+        assert!(!config.is_immutable("releases/web", "latest"));
+        assert!(!ImmutableTagsConfig::default().is_immutable("prod/web", "v1"));
+    }
+}
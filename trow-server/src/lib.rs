@@ -1,19 +1,38 @@
 mod admission;
 pub mod digest;
+mod free_space;
+mod gc;
 mod image;
+mod immutable_tags;
 pub mod manifest;
-mod metrics;
+mod media_types;
+#[cfg(feature = "sqlite")]
+mod metadata;
+pub mod metrics;
 mod proxy_auth;
+mod proxy_blobs;
+mod quotas;
+mod reaper;
+mod references;
+mod retention;
 mod server;
 mod temporary_file;
+mod uploads;
 
 use std::future::Future;
+use std::time::Duration;
 
 pub use admission::ImageValidationConfig;
+pub use free_space::parse_size;
+pub use immutable_tags::ImmutableTagsConfig;
+pub use media_types::MediaTypeConfig;
 pub use proxy_auth::{RegistryProxiesConfig, SingleRegistryProxyConfig};
+pub use quotas::{Quota, QuotaConfig};
+pub use reaper::{DEFAULT_UPLOAD_TTL, MIN_UPLOAD_TTL};
+pub use retention::{RetentionConfig, RetentionPolicy};
 use server::trow_server::admission_controller_server::AdmissionControllerServer;
 use server::trow_server::registry_server::RegistryServer;
-use server::TrowServer;
+use server::{ServerPolicies, TrowServer};
 use tonic::transport::Server;
 
 pub struct TrowServerBuilder {
@@ -24,6 +43,9 @@ pub struct TrowServerBuilder {
     tls_cert: Option<Vec<u8>>,
     tls_key: Option<Vec<u8>>,
     root_key: Option<Vec<u8>>,
+    upload_ttl: Duration,
+    policies: ServerPolicies,
+    metrics_repo_depth: usize,
 }
 
 pub fn build_server(
@@ -40,6 +62,9 @@ pub fn build_server(
         tls_cert: None,
         tls_key: None,
         root_key: None,
+        upload_ttl: DEFAULT_UPLOAD_TTL,
+        policies: ServerPolicies::default(),
+        metrics_repo_depth: 0,
     }
 }
 
@@ -55,17 +80,75 @@ impl TrowServerBuilder {
         self
     }
 
+    /// Uploads that don't receive any data for `upload_ttl` are deleted. TTLs shorter than
+    /// `MIN_UPLOAD_TTL` are raised to it.
+    pub fn with_upload_ttl(mut self, upload_ttl: Duration) -> TrowServerBuilder {
+        self.upload_ttl = upload_ttl;
+        self
+    }
+
+    /// Restricts the media types of config blobs and layers that can be pushed
+    pub fn with_media_type_config(mut self, config: MediaTypeConfig) -> TrowServerBuilder {
+        self.policies.media_type_config = config;
+        self
+    }
+
+    /// Prevents matching tags from being moved to another digest
+    pub fn with_immutable_tags(mut self, config: ImmutableTagsConfig) -> TrowServerBuilder {
+        self.policies.immutable_tags = config;
+        self
+    }
+
+    /// Limits the storage used by namespaces
+    pub fn with_quota_config(mut self, config: QuotaConfig) -> TrowServerBuilder {
+        self.policies.quota_config = config;
+        self
+    }
+
+    /// Refuses new uploads and proxy downloads when less than `min_free_space` bytes are
+    /// available on disk (0 disables the check)
+    pub fn with_min_free_space(mut self, min_free_space: u64) -> TrowServerBuilder {
+        self.policies.min_free_space = min_free_space;
+        self
+    }
+
+    /// Truncates the repository names used as metric labels to `depth` path components (0 keeps
+    /// full names)
+    pub fn with_metrics_repo_depth(mut self, depth: usize) -> TrowServerBuilder {
+        self.metrics_repo_depth = depth;
+        self
+    }
+
+    /// Periodically deletes the tags expired by these retention policies
+    pub fn with_retention_config(mut self, config: Option<RetentionConfig>) -> TrowServerBuilder {
+        self.policies.retention_config = config;
+        self
+    }
+
     pub fn get_server_future(self) -> impl Future<Output = Result<(), tonic::transport::Error>> {
+        metrics::set_repo_label_depth(self.metrics_repo_depth);
+        let retention_config = self.policies.retention_config.clone();
         let ts = TrowServer::new(
             &self.data_path,
             self.proxy_registry_config,
             self.image_validation_config,
+            self.policies,
         )
         .expect("Failure configuring Trow Server");
 
-        Server::builder()
+        let reaper = reaper::reap_periodically(ts.clone(), self.upload_ttl);
+        let retention =
+            retention_config.map(|config| retention::enforce_periodically(ts.clone(), config));
+        let server = Server::builder()
             .add_service(RegistryServer::new(ts.clone()))
             .add_service(AdmissionControllerServer::new(ts))
-            .serve(self.listen_addr)
+            .serve(self.listen_addr);
+        async move {
+            tokio::spawn(reaper);
+            if let Some(retention) = retention {
+                tokio::spawn(retention);
+            }
+            server.await
+        }
     }
 }
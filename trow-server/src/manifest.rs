@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
 use thiserror::Error;
@@ -35,6 +39,13 @@ pub struct ManifestList {
     pub schema_version: u8,
     pub media_type: String, //TODO: make enum
     pub manifests: Vec<ManifestListEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    /// Manifest this one refers to, see the referrers API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Object>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -66,6 +77,13 @@ pub struct ManifestV2 {
     pub media_type: Option<String>, //TODO: make enum
     pub config: Object,
     pub layers: Vec<Object>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    /// Manifest this one refers to, see the referrers API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Object>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -95,6 +113,21 @@ pub mod manifest_media_type {
     pub const DEFAULT: &str = OCI_V1;
 }
 
+/// Whether `media_type` has the `type/subtype` form required by RFC 6838.
+///
+/// This is only a syntax check. Blobs are stored as opaque bytes without a media type of their
+/// own, so there is nothing to compare the declared media type with: a layer declared as
+/// `application/vnd.oci.image.layer.v1.tar+gzip` may hold anything.
+pub fn is_valid_media_type(media_type: &str) -> bool {
+    lazy_static! {
+        static ref MEDIA_TYPE: Regex = Regex::new(
+            r"^[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]{0,126}/[A-Za-z0-9][A-Za-z0-9!#$&^_.+-]{0,126}$"
+        )
+        .unwrap();
+    }
+    MEDIA_TYPE.is_match(media_type)
+}
+
 fn schema_2(raw: &Value) -> Result<Manifest> {
     // According to the spec, manifests don't have to have a mediaType (?!).
     // Assume V2 if not present.
@@ -163,6 +196,38 @@ impl Manifest {
         }
     }
 
+    /// Descriptors of the blobs this registry is expected to store for the manifest: config and
+    /// layers of images. Foreign layers and the children of lists aren't included.
+    pub fn get_local_asset_descriptors(&self) -> Vec<&Object> {
+        match *self {
+            Manifest::V2(ref m2) => m2
+                .layers
+                .iter()
+                .filter(|x| {
+                    x.media_type != "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"
+                })
+                .chain(std::iter::once(&m2.config))
+                .collect(),
+            Manifest::List(_) => vec![],
+        }
+    }
+
+    /// Media type of the config blob; only image manifests have one
+    pub fn get_config_media_type(&self) -> Option<&str> {
+        match *self {
+            Manifest::V2(ref m2) => Some(&m2.config.media_type),
+            Manifest::List(_) => None,
+        }
+    }
+
+    /// Media types of the layers of an image
+    pub fn get_layer_media_types(&self) -> Vec<&str> {
+        match *self {
+            Manifest::V2(ref m2) => m2.layers.iter().map(|l| l.media_type.as_str()).collect(),
+            Manifest::List(_) => vec![],
+        }
+    }
+
     // TODO: use proper enums and return &str
     pub fn get_media_type(&self) -> String {
         match *self {
@@ -174,6 +239,34 @@ impl Manifest {
             Manifest::List(ref list) => list.media_type.clone(),
         }
     }
+
+    /// Digest of the manifest this one refers to, e.g. the image a signature is for
+    pub fn get_subject(&self) -> Option<&str> {
+        let subject = match *self {
+            Manifest::V2(ref m2) => m2.subject.as_ref(),
+            Manifest::List(ref list) => list.subject.as_ref(),
+        };
+        subject.map(|s| s.digest.as_str())
+    }
+
+    /// The type of artifact, falling back to the config media type for image manifests as the
+    /// referrers API requires
+    pub fn get_artifact_type(&self) -> Option<&str> {
+        match *self {
+            Manifest::V2(ref m2) => m2
+                .artifact_type
+                .as_deref()
+                .or(Some(m2.config.media_type.as_str())),
+            Manifest::List(ref list) => list.artifact_type.as_deref(),
+        }
+    }
+
+    pub fn get_annotations(&self) -> Option<&HashMap<String, String>> {
+        match *self {
+            Manifest::V2(ref m2) => m2.annotations.as_ref(),
+            Manifest::List(ref list) => list.annotations.as_ref(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -182,9 +275,20 @@ mod test {
 
     use serde_json::{self, Value};
 
-    use super::{FromJson, Manifest};
+    use super::{is_valid_media_type, FromJson, Manifest};
     use crate::digest::sha256_tag_digest;
 
+    #[test]
+    fn media_types() {
+        assert!(is_valid_media_type(
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+        ));
+        assert!(is_valid_media_type("text/plain"));
+        assert!(!is_valid_media_type("application"));
+        assert!(!is_valid_media_type("application/"));
+        assert!(!is_valid_media_type("application/json; charset=utf-8"));
+    }
+
     #[test]
     fn valid_v2_2() {
         let data = r#"{
@@ -252,6 +356,7 @@ mod test {
         );
 
         assert_eq!(mani.get_local_asset_digests().len(), 3);
+        assert_eq!(mani.get_local_asset_descriptors().len(), 3);
         assert!(mani
             .get_local_asset_digests()
             .contains(&"sha256:9d48c3bd43c520dc2784e868a780e976b207cbf493eaff8c6596eb871cbd9609"));
@@ -338,6 +443,87 @@ mod test {
         assert!(Manifest::from_json(&v).is_ok());
     }
 
+    #[test]
+    fn artifact_with_subject() {
+        let data = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.example.sbom.v1",
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "size": 2,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+            },
+            "layers": [],
+            "subject": {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 354,
+                "digest": "sha256:4a415e3663882fbc554ee830889c68a33b3585503892cc718a4698e91ef2a526"
+            },
+            "annotations": { "org.example.format": "spdx" }
+        }"#;
+
+        let v: Value = serde_json::from_str(data).unwrap();
+        let mani = Manifest::from_json(&v).unwrap();
+        assert_eq!(
+            mani.get_subject(),
+            Some("sha256:4a415e3663882fbc554ee830889c68a33b3585503892cc718a4698e91ef2a526")
+        );
+        assert_eq!(
+            mani.get_artifact_type(),
+            Some("application/vnd.example.sbom.v1")
+        );
+        assert_eq!(
+            mani.get_annotations().unwrap()["org.example.format"],
+            "spdx"
+        );
+        // The subject isn't needed to push the artifact
+        assert_eq!(mani.get_local_asset_digests().len(), 1);
+    }
+
+    #[test]
+    fn artifact_manifest_is_not_supported() {
+        // Artifacts are image manifests with an artifactType, the draft artifact manifest
+        // didn't make it into the spec
+        let data = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+            "artifactType": "application/vnd.dev.cosign.artifact.sig.v1+json",
+            "blobs": []
+        }"#;
+
+        let v: Value = serde_json::from_str(data).unwrap();
+        assert!(Manifest::from_json(&v).is_err());
+    }
+
+    #[test]
+    fn helm_chart() {
+        let data = r#"{
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.cncf.helm.config.v1+json",
+                "size": 117,
+                "digest": "sha256:8ec7c0f2f6860037c19b54c3cfbab48d9b4b21b485a93d87b64690fdb68c2111"
+            },
+            "layers": [{
+                "mediaType": "application/vnd.cncf.helm.chart.content.v1.tar+gzip",
+                "size": 3505,
+                "digest": "sha256:1e76f742da490c8d7c921e811e5233def206e76683ee28d735397ec2231f131d"
+            }]
+        }"#;
+
+        let v: Value = serde_json::from_str(data).unwrap();
+        let mani = Manifest::from_json(&v).unwrap();
+        assert_eq!(
+            mani.get_config_media_type(),
+            Some("application/vnd.cncf.helm.config.v1+json")
+        );
+        assert_eq!(
+            mani.get_artifact_type(),
+            Some("application/vnd.cncf.helm.config.v1+json")
+        );
+    }
+
     #[test]
     fn valid_manifest_list() {
         let data = r#"{
@@ -0,0 +1,136 @@
+//! Restrictions on the kind of content that can be pushed.
+//!
+//! By default any config and layer media type is accepted, so Helm charts, WASM modules, signatures
+//! and other OCI artifacts can be stored next to images. Operators can limit this to a list of
+//! media types; an entry ending in `*` matches every media type starting with what precedes it,
+//! e.g. `application/vnd.oci.image.layer.*`. The `artifactType` a manifest declares is checked
+//! against the config media types, as it stands for the kind of content just like the config does.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::Manifest;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MediaTypeConfig {
+    /// Allowed media types of config blobs, and artifact types of manifests. Empty allows all.
+    #[serde(default)]
+    pub config: Vec<String>,
+    /// Allowed media types of layers. Empty allows all.
+    #[serde(default)]
+    pub layers: Vec<String>,
+}
+
+fn is_allowed(allowed: &[String], media_type: &str) -> bool {
+    allowed.is_empty()
+        || allowed.iter().any(|a| match a.strip_suffix('*') {
+            Some(prefix) => media_type.starts_with(prefix),
+            None => a == media_type,
+        })
+}
+
+impl MediaTypeConfig {
+    /// Returns an error naming the first media type of `manifest` that isn't allowed
+    pub(crate) fn check(&self, manifest: &Manifest) -> Result<()> {
+        if let Some(config) = manifest.get_config_media_type() {
+            if !is_allowed(&self.config, config) {
+                return Err(anyhow!("Config media type {} is not allowed", config));
+            }
+        }
+        if let Some(artifact_type) = manifest.get_artifact_type() {
+            if !is_allowed(&self.config, artifact_type) {
+                return Err(anyhow!("Artifact type {} is not allowed", artifact_type));
+            }
+        }
+        for layer in manifest.get_layer_media_types() {
+            if !is_allowed(&self.layers, layer) {
+                return Err(anyhow!("Layer media type {} is not allowed", layer));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::MediaTypeConfig;
+    use crate::manifest::{FromJson, Manifest};
+
+    fn helm_chart() -> Manifest {
+        Manifest::from_json(&json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.cncf.helm.config.v1+json",
+                "size": 117,
+                "digest": "sha256:8ec7c0f2f6860037c19b54c3cfbab48d9b4b21b485a93d87b64690fdb68c2111"
+            },
+            "layers": [{
+                "mediaType": "application/vnd.cncf.helm.chart.content.v1.tar+gzip",
+                "size": 3505,
+                "digest": "sha256:1e76f742da490c8d7c921e811e5233def206e76683ee28d735397ec2231f131d"
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        assert!(MediaTypeConfig::default().check(&helm_chart()).is_ok());
+    }
+
+    #[test]
+    fn checks_config_and_layers() {
+        let images_only = MediaTypeConfig {
+            config: vec!["application/vnd.oci.image.config.v1+json".to_string()],
+            layers: vec!["application/vnd.oci.image.layer.*".to_string()],
+        };
+        let err = images_only.check(&helm_chart()).unwrap_err();
+        assert!(err.to_string().contains("helm.config"));
+
+        let helm = MediaTypeConfig {
+            config: vec!["application/vnd.cncf.helm.config.v1+json".to_string()],
+            layers: vec!["application/vnd.oci.image.layer.*".to_string()],
+        };
+        let err = helm.check(&helm_chart()).unwrap_err();
+        assert!(err.to_string().contains("helm.chart.content"));
+
+        let helm = MediaTypeConfig {
+            config: vec!["application/vnd.cncf.helm.config.v1+json".to_string()],
+            layers: vec!["application/vnd.cncf.helm.*".to_string()],
+        };
+        assert!(helm.check(&helm_chart()).is_ok());
+    }
+
+    #[test]
+    fn checks_artifact_types() {
+        let sbom = Manifest::from_json(&json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.example.sbom.v1",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "size": 2,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+            },
+            "layers": []
+        }))
+        .unwrap();
+        let images_only = MediaTypeConfig {
+            config: vec!["application/vnd.oci.image.config.v1+json".to_string()],
+            layers: vec![],
+        };
+        let err = images_only.check(&sbom).unwrap_err();
+        assert!(err.to_string().contains("vnd.example.sbom.v1"));
+
+        let with_sboms = MediaTypeConfig {
+            config: vec![
+                "application/vnd.oci.image.config.v1+json".to_string(),
+                "application/vnd.example.sbom.*".to_string(),
+            ],
+            layers: vec![],
+        };
+        assert!(with_sboms.check(&sbom).is_ok());
+    }
+}
@@ -0,0 +1,486 @@
+//! SQLite index of the registry metadata, enabled with the `sqlite` feature.
+//!
+//! The files under `manifests/` and `blobs/` remain the source of truth; the database mirrors the
+//! repositories, tags, tag histories, manifest references and blob sizes so that listing the
+//! catalog, listing tags and resolving tags don't need to walk and read the data directory on
+//! every request. It is kept up to date as tags are pushed and deleted.
+//!
+//! The database is stored in `metadata.db` in the data directory. When it doesn't exist yet, it is
+//! created and filled from the existing data directory, so deleting it forces a full re-import.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::{event, Level};
+
+use crate::references::ManifestReference;
+use crate::server::{IndexEntry, RepoIterator, TrowServer, SUPPORTED_DIGESTS};
+
+pub(crate) const METADATA_DB: &str = "metadata.db";
+
+/// Bumped whenever the schema changes
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    DROP TABLE IF EXISTS repositories;
+    DROP TABLE IF EXISTS tags;
+    DROP TABLE IF EXISTS tag_history;
+    DROP TABLE IF EXISTS manifests;
+    DROP TABLE IF EXISTS manifest_blobs;
+    DROP TABLE IF EXISTS blobs;
+    CREATE TABLE repositories (
+        name TEXT PRIMARY KEY
+    );
+    CREATE TABLE tags (
+        repo TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        digest TEXT NOT NULL,
+        PRIMARY KEY (repo, tag)
+    );
+    CREATE TABLE tag_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        repo TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        digest TEXT NOT NULL,
+        date TEXT NOT NULL
+    );
+    CREATE INDEX tag_history_by_tag ON tag_history (repo, tag, id);
+    CREATE TABLE manifests (
+        digest TEXT PRIMARY KEY,
+        subject TEXT
+    );
+    CREATE TABLE manifest_blobs (
+        manifest TEXT NOT NULL,
+        blob TEXT NOT NULL,
+        PRIMARY KEY (manifest, blob)
+    );
+    CREATE TABLE blobs (
+        digest TEXT PRIMARY KEY,
+        size INTEGER NOT NULL
+    );
+";
+
+pub(crate) struct MetadataStore {
+    conn: Mutex<Connection>,
+}
+
+impl MetadataStore {
+    /// Opens the database at `path`, creating it if needed.
+    /// Returns whether it was just created and needs to be filled, in which case
+    /// [`MetadataStore::mark_imported`] must be called once it has been.
+    pub fn open(path: &Path) -> Result<(MetadataStore, bool)> {
+        let conn = Connection::open(path)?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        let created = version == 0;
+        if created {
+            // A previous import may have been interrupted, so start from empty tables
+            conn.execute_batch(&format!("BEGIN; {} COMMIT;", SCHEMA))?;
+        } else if version != SCHEMA_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported metadata schema version {} in {:?}, delete it to re-import",
+                version,
+                path
+            ));
+        }
+        let store = MetadataStore {
+            conn: Mutex::new(conn),
+        };
+        Ok((store, created))
+    }
+
+    /// Records that the database has been filled, so it isn't imported again on the next start
+    pub fn mark_imported(&self) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(())
+    }
+
+    /// Records that `tag` in `repo` now points to `digest`
+    pub fn add_tag(&self, repo: &str, tag: &str, digest: &str, date: &str) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO repositories (name) VALUES (?1)",
+            params![repo],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO tags (repo, tag, digest) VALUES (?1, ?2, ?3)",
+            params![repo, tag, digest],
+        )?;
+        tx.execute(
+            "INSERT INTO tag_history (repo, tag, digest, date) VALUES (?1, ?2, ?3, ?4)",
+            params![repo, tag, digest, date],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records the blobs a manifest references and its subject
+    pub fn add_manifest(
+        &self,
+        digest: &str,
+        blobs: &[String],
+        subject: Option<&str>,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO manifests (digest, subject) VALUES (?1, ?2)",
+            params![digest, subject],
+        )?;
+        for blob in blobs {
+            tx.execute(
+                "INSERT OR IGNORE INTO manifest_blobs (manifest, blob) VALUES (?1, ?2)",
+                params![digest, blob],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn add_blob(&self, digest: &str, size: u64) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO blobs (digest, size) VALUES (?1, ?2)",
+            params![digest, size],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_blob(&self, digest: &str) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM blobs WHERE digest = ?1", params![digest])?;
+        Ok(())
+    }
+
+    /// Size of a stored blob, if known
+    pub fn blob_size(&self, digest: &str) -> Result<Option<u64>> {
+        let size = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT size FROM blobs WHERE digest = ?1",
+                params![digest],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(size)
+    }
+
+    /// Forgets the tags and history of `repo`. Manifests and blobs are shared between
+    /// repositories and are kept.
+    pub fn remove_repo(&self, repo: &str) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM repositories WHERE name = ?1", params![repo])?;
+        tx.execute("DELETE FROM tags WHERE repo = ?1", params![repo])?;
+        tx.execute("DELETE FROM tag_history WHERE repo = ?1", params![repo])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// All repository names, sorted
+    pub fn repositories(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name FROM repositories ORDER BY name")?;
+        let names = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    /// All tags of `repo`, sorted
+    pub fn tags(&self, repo: &str) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT tag FROM tags WHERE repo = ?1 ORDER BY tag")?;
+        let tags = stmt
+            .query_map(params![repo], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tags)
+    }
+
+    /// The digest `tag` currently points to
+    pub fn current_digest(&self, repo: &str, tag: &str) -> Result<Option<String>> {
+        let digest = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT digest FROM tags WHERE repo = ?1 AND tag = ?2",
+                params![repo, tag],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(digest)
+    }
+
+    /// The digests `tag` has pointed to and when, oldest first
+    pub fn history(&self, repo: &str, tag: &str) -> Result<Vec<(String, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT digest, date FROM tag_history WHERE repo = ?1 AND tag = ?2 ORDER BY id",
+        )?;
+        let history = stmt
+            .query_map(params![repo, tag], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(history)
+    }
+
+    /// Every manifest in a tag history, with the blobs it references and its subject
+    pub fn indexed_manifests(&self) -> Result<Vec<IndexEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut blobs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT manifest, blob FROM manifest_blobs")?;
+        for row in stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))? {
+            let (manifest, blob): (String, String) = row?;
+            blobs.entry(manifest).or_default().push(blob);
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT h.repo, h.digest, m.subject
+             FROM tag_history h JOIN manifests m ON m.digest = h.digest",
+        )?;
+        let entries = stmt
+            .query_map([], |r| {
+                Ok((
+                    ManifestReference {
+                        repo_name: r.get(0)?,
+                        digest: r.get(1)?,
+                    },
+                    r.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .map(|row| {
+                row.map(|(manifest, subject)| {
+                    let refs = blobs.get(&manifest.digest).cloned().unwrap_or_default();
+                    (manifest, refs, subject)
+                })
+            })
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+}
+
+impl TrowServer {
+    /// Fills the metadata database from the data directory
+    pub(crate) fn import_metadata(&self) -> Result<()> {
+        event!(
+            Level::INFO,
+            "Importing registry metadata into {}",
+            METADATA_DB
+        );
+        let mut repos: Vec<String> = RepoIterator::new(&self.manifests_path)?
+            .filter_map(|tag_file| {
+                tag_file
+                    .path()
+                    .parent()
+                    .and_then(|p| p.strip_prefix(&self.manifests_path).ok())
+                    .map(|r| r.to_string_lossy().to_string())
+            })
+            .collect();
+        repos.sort();
+        repos.dedup();
+        for repo in repos {
+            self.import_repo_metadata(&repo)?;
+        }
+
+        for alg in SUPPORTED_DIGESTS {
+            let alg_dir = self.blobs_path.join(alg);
+            if !alg_dir.exists() {
+                continue;
+            }
+            for entry in fs::read_dir(&alg_dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    let digest = format!("{}:{}", alg, entry.file_name().to_string_lossy());
+                    self.metadata.add_blob(&digest, metadata.len())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the metadata of `repo_name` with what is in its tag files
+    pub(crate) fn import_repo_metadata(&self, repo_name: &str) -> Result<()> {
+        self.metadata.remove_repo(repo_name)?;
+        let repo_dir = self.manifests_path.join(repo_name);
+        if !repo_dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&repo_dir)? {
+            let entry = entry?;
+            // Subdirectories are other repositories
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let tag = entry.file_name().to_string_lossy().to_string();
+            let history = match fs::read_to_string(entry.path()) {
+                Ok(h) => h,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Not indexing tag file {:?}: {:?}",
+                        entry.path(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            // Each line is `{digest} {date}`
+            for line in history.lines() {
+                let (digest, date) = line.split_once(' ').unwrap_or((line, ""));
+                self.metadata
+                    .add_tag(repo_name, &tag, digest, date.trim())?;
+                self.import_manifest_metadata(digest);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the references of a manifest, if it can be read
+    pub(crate) fn import_manifest_metadata(&self, digest: &str) {
+        let manifest = match self.read_manifest(digest) {
+            Ok(m) => m,
+            Err(e) => {
+                event!(Level::DEBUG, "Not indexing manifest {}: {:?}", digest, e);
+                return;
+            }
+        };
+        let blobs: Vec<String> = manifest
+            .get_local_asset_digests()
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        if let Err(e) = self
+            .metadata
+            .add_manifest(digest, &blobs, manifest.get_subject())
+        {
+            event!(Level::WARN, "Failed to index manifest {}: {:?}", digest, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use super::{MetadataStore, METADATA_DB};
+    use crate::digest::sha256_tag_digest;
+    use crate::server::TrowServer;
+
+    #[test]
+    fn tracks_tags_and_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, created) = MetadataStore::open(&dir.path().join(METADATA_DB)).unwrap();
+        assert!(created);
+
+        store
+            .add_tag("b/repo", "v1", "sha256:one", "date1")
+            .unwrap();
+        store.add_tag("a", "latest", "sha256:one", "date1").unwrap();
+        store
+            .add_tag("b/repo", "v1", "sha256:two", "date2")
+            .unwrap();
+        store
+            .add_tag("b/repo", "latest", "sha256:two", "date2")
+            .unwrap();
+
+        assert_eq!(store.repositories().unwrap(), vec!["a", "b/repo"]);
+        assert_eq!(store.tags("b/repo").unwrap(), vec!["latest", "v1"]);
+        assert_eq!(
+            store.current_digest("b/repo", "v1").unwrap().unwrap(),
+            "sha256:two"
+        );
+        assert_eq!(store.current_digest("b/repo", "v2").unwrap(), None);
+        assert_eq!(
+            store.history("b/repo", "v1").unwrap(),
+            vec![
+                ("sha256:one".to_string(), "date1".to_string()),
+                ("sha256:two".to_string(), "date2".to_string())
+            ]
+        );
+
+        store.remove_repo("b/repo").unwrap();
+        assert_eq!(store.repositories().unwrap(), vec!["a"]);
+        assert!(store.history("b/repo", "v1").unwrap().is_empty());
+
+        store.mark_imported().unwrap();
+        drop(store);
+        let (store, created) = MetadataStore::open(&dir.path().join(METADATA_DB)).unwrap();
+        assert!(!created);
+        assert_eq!(store.tags("a").unwrap(), vec!["latest"]);
+    }
+
+    #[test]
+    fn restarts_interrupted_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, created) = MetadataStore::open(&dir.path().join(METADATA_DB)).unwrap();
+        assert!(created);
+        store.add_tag("a", "latest", "sha256:one", "date1").unwrap();
+
+        // Not marked as imported, so it is emptied and filled again
+        drop(store);
+        let (store, created) = MetadataStore::open(&dir.path().join(METADATA_DB)).unwrap();
+        assert!(created);
+        assert!(store.repositories().unwrap().is_empty());
+    }
+
+    #[test]
+    fn imports_existing_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().to_str().unwrap();
+        let server = TrowServer::new(data_path, None, None, Default::default()).unwrap();
+
+        let config = b"{}";
+        let config_digest = sha256_tag_digest(&config[..]).unwrap();
+        let manifest = format!(
+            r#"{{ "schemaVersion": 2,
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "config": {{ "mediaType": "application/vnd.oci.image.config.v1+json", "size": 2, "digest": "{}" }},
+                  "layers": [] }}"#,
+            config_digest
+        );
+        let manifest_digest = sha256_tag_digest(manifest.as_bytes()).unwrap();
+        for (digest, content) in [
+            (&config_digest, &config[..]),
+            (&manifest_digest, manifest.as_bytes()),
+        ] {
+            let path = server.get_catalog_path_for_blob(digest).unwrap();
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap().write_all(content).unwrap();
+        }
+        let repo_dir = server.manifests_path.join("my/repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(
+            repo_dir.join("v1"),
+            format!("{} 2023-05-30T09:21:14.081204316Z\n", manifest_digest),
+        )
+        .unwrap();
+
+        // Simulate an existing data dir by dropping the database
+        drop(server);
+        fs::remove_file(dir.path().join(METADATA_DB)).unwrap();
+        let server = TrowServer::new(data_path, None, None, Default::default()).unwrap();
+
+        assert_eq!(server.metadata.repositories().unwrap(), vec!["my/repo"]);
+        assert_eq!(
+            server.metadata.current_digest("my/repo", "v1").unwrap(),
+            Some(manifest_digest.clone())
+        );
+        assert_eq!(server.metadata.blob_size(&config_digest).unwrap(), Some(2));
+        let indexed = server.metadata.indexed_manifests().unwrap();
+        assert_eq!(indexed.len(), 1);
+        assert_eq!(indexed[0].0.digest, manifest_digest);
+        assert_eq!(indexed[0].1, vec![config_digest]);
+    }
+}
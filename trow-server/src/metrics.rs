@@ -1,12 +1,18 @@
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::Result;
 use lazy_static::lazy_static;
 use prometheus::{
-    labels, opts, register_int_counter, register_int_gauge, Encoder, IntCounter, IntGauge,
+    exponential_buckets, histogram_opts, labels, opts, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
     TextEncoder,
 };
 
+/// Number of path components repository labels are truncated to, 0 keeps full names
+static REPO_LABEL_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
 //  Metrics static values executed at runtime and registered to default
 //  prometheus registry
 lazy_static! {
@@ -35,6 +41,126 @@ lazy_static! {
         "total number of requests for blobs made",
         labels! {"type" => "blobs"}
     )).unwrap();
+    pub static ref RECLAIMED_SCRATCH_FILES: IntCounter = register_int_counter!(opts!(
+        "total_reclaimed_scratch_files",
+        "total number of abandoned uploads and stale temporary files deleted",
+        labels! {"type" => "scratch"}
+    )).unwrap();
+    pub static ref RECLAIMED_SCRATCH_BYTES: IntCounter = register_int_counter!(opts!(
+        "total_reclaimed_scratch_bytes",
+        "total size in bytes of abandoned uploads and stale temporary files deleted",
+        labels! {"type" => "scratch"}
+    )).unwrap();
+    pub static ref RETENTION_DELETED_TAGS: IntCounter = register_int_counter!(opts!(
+        "total_retention_deleted_tags",
+        "total number of tags deleted by retention policies",
+        labels! {"type" => "retention"}
+    )).unwrap();
+    pub static ref QUOTA_USAGE_BYTES: IntGaugeVec = register_int_gauge_vec!(opts!(
+        "quota_usage_bytes",
+        "size in bytes of the unique blobs used by each namespace with a quota",
+        labels! {"type" => "quota"}
+    ), &["namespace"]).unwrap();
+    pub static ref QUOTA_LIMIT_BYTES: IntGaugeVec = register_int_gauge_vec!(opts!(
+        "quota_limit_bytes",
+        "quota in bytes of each namespace",
+        labels! {"type" => "quota"}
+    ), &["namespace"]).unwrap();
+    pub static ref PUSHES: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_pushes",
+        "total number of manifests pushed per repository",
+        labels! {"type" => "repository"}
+    ), &["repo"]).unwrap();
+    pub static ref PULLS: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_pulls",
+        "total number of manifests pulled per repository",
+        labels! {"type" => "repository"}
+    ), &["repo"]).unwrap();
+    pub static ref DELETES: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_deletes",
+        "total number of manifests and blobs deleted per repository",
+        labels! {"type" => "repository"}
+    ), &["repo"]).unwrap();
+    pub static ref BYTES_UPLOADED: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_bytes_uploaded",
+        "total size in bytes of the blobs and manifests pushed per repository",
+        labels! {"type" => "repository"}
+    ), &["repo"]).unwrap();
+    pub static ref BYTES_SERVED: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_bytes_served",
+        "total size in bytes of the blobs and manifests pulled per repository",
+        labels! {"type" => "repository"}
+    ), &["repo"]).unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(histogram_opts!(
+        "http_request_duration_seconds",
+        "time in seconds taken to respond to HTTP requests, by route, method and status",
+        prometheus::DEFAULT_BUCKETS.to_vec(),
+        labels! {"type".to_string() => "http".to_string()}
+    ), &["route", "method", "status"]).unwrap();
+    pub static ref UPLOAD_DURATION: Histogram = register_histogram!(histogram_opts!(
+        "upload_duration_seconds",
+        "time in seconds from the start of blob uploads to their completion",
+        exponential_buckets(0.1, 4.0, 9).unwrap(),
+        labels! {"type".to_string() => "uploads".to_string()}
+    )).unwrap();
+    pub static ref PROXY_CACHE_HITS: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_proxy_cache_hits",
+        "total number of proxied manifests and blobs served from the cache, per registry alias",
+        labels! {"type" => "proxy"}
+    ), &["alias", "kind"]).unwrap();
+    pub static ref PROXY_CACHE_MISSES: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_proxy_cache_misses",
+        "total number of proxied manifests and blobs fetched from upstream, per registry alias",
+        labels! {"type" => "proxy"}
+    ), &["alias", "kind"]).unwrap();
+    pub static ref PROXY_UPSTREAM_BYTES: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_proxy_upstream_bytes",
+        "total size in bytes of the manifests and blobs fetched from each proxied registry",
+        labels! {"type" => "proxy"}
+    ), &["alias"]).unwrap();
+    pub static ref PROXY_UPSTREAM_ERRORS: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_proxy_upstream_errors",
+        "total number of failed requests to each proxied registry, by status code (none when no response was received)",
+        labels! {"type" => "proxy"}
+    ), &["alias", "status"]).unwrap();
+    pub static ref PROXY_DIGEST_MISMATCHES: IntCounterVec = register_int_counter_vec!(opts!(
+        "total_proxy_digest_mismatches",
+        "total number of manifests and blobs fetched from each proxied registry that didn't match their digest",
+        labels! {"type" => "proxy"}
+    ), &["alias", "kind"]).unwrap();
+    pub static ref PROXY_UPSTREAM_DURATION: HistogramVec = register_histogram_vec!(histogram_opts!(
+        "proxy_upstream_duration_seconds",
+        "time in seconds taken by proxied registries to respond, by kind of request",
+        prometheus::DEFAULT_BUCKETS.to_vec(),
+        labels! {"type".to_string() => "proxy".to_string()}
+    ), &["alias", "kind"]).unwrap();
+    pub static ref ACTIVE_UPLOADS: IntGauge = register_int_gauge!(opts!(
+        "active_uploads",
+        "number of blob upload sessions in progress",
+        labels! {"type" => "uploads"}
+    )).unwrap();
+}
+
+/// Truncates repository labels to their first `depth` path components, to limit the number of
+/// time series when there are many repositories. 0 keeps full names.
+pub fn set_repo_label_depth(depth: usize) {
+    REPO_LABEL_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// The value of the `repo` label for `repo_name`
+pub fn repo_label(repo_name: &str) -> String {
+    truncate_repo_name(repo_name, REPO_LABEL_DEPTH.load(Ordering::Relaxed))
+}
+
+fn truncate_repo_name(repo_name: &str, depth: usize) -> String {
+    if depth == 0 {
+        return repo_name.to_string();
+    }
+    repo_name
+        .split('/')
+        .take(depth)
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
 // Query disk metrics
@@ -57,6 +183,12 @@ pub fn gather_metrics(blobs_path: &Path) -> Result<String> {
     //      * disk
     //      * total manifest requests
     //      * total blob requests
+    //      * reclaimed scratch files and bytes
+    //      * tags deleted by retention policies
+    //      * quota usage
+    //      * pushes, pulls, deletes and bytes transferred per repository
+    //      * HTTP request and upload durations, active uploads
+    //      * proxy cache hits and misses, upstream bytes, errors and latency
 
     let metric_families = prometheus::gather();
     let mut buffer = vec![];
@@ -67,3 +199,16 @@ pub fn gather_metrics(blobs_path: &Path) -> Result<String> {
 
     Ok(metrics)
 }
+
+#[cfg(test)]
+mod test {
+    use super::truncate_repo_name;
+
+    #[test]
+    fn truncates_repo_names() {
+        assert_eq!(truncate_repo_name("team/web/api", 0), "team/web/api");
+        assert_eq!(truncate_repo_name("team/web/api", 1), "team");
+        assert_eq!(truncate_repo_name("team/web/api", 2), "team/web");
+        assert_eq!(truncate_repo_name("team", 2), "team");
+    }
+}
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use lazy_static::lazy_static;
 use quoted_string::strip_dquotes;
 use regex::Regex;
@@ -13,11 +16,14 @@ use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
 use crate::image::RemoteImage;
+use crate::metrics;
 use crate::server::create_accept_header;
 
 const AUTHN_HEADER: &str = "www-authenticate";
+/// Lifetime of bearer tokens that don't say how long they are valid for, as per the token spec
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HttpAuth {
     Basic(String, Option<String>),
     Bearer(String),
@@ -39,6 +45,70 @@ pub struct SingleRegistryProxyConfig {
     pub host: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Seconds for which a cached tag is served without checking for a newer version upstream
+    /// (0 always checks)
+    #[serde(default)]
+    pub tag_ttl: u64,
+}
+
+/// Auth for a scope of a proxied registry, and when it should be renewed
+struct CachedAuth {
+    auth: HttpAuth,
+    refresh_at: Option<Instant>,
+}
+
+/// The connection pools and auth of the proxied registries, shared by all requests so that
+/// connections are reused and tokens are only fetched when they are about to expire.
+///
+/// Auth is cached per alias and scope (the repository being pulled), and renewed once 80% of
+/// its lifetime has passed. It is dropped if the registry rejects it, and expired auth is evicted
+/// whenever new auth is cached.
+#[derive(Default)]
+pub struct ProxyClientCache {
+    clients: Mutex<HashMap<String, reqwest::Client>>,
+    auth: Mutex<HashMap<(String, String), CachedAuth>>,
+}
+
+impl ProxyClientCache {
+    /// The pooled client for `alias`
+    fn client(&self, alias: &str) -> Result<reqwest::Client> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(cl) = clients.get(alias) {
+            return Ok(cl.clone());
+        }
+        let cl = reqwest::ClientBuilder::new()
+            .connect_timeout(Duration::from_millis(1000))
+            .build()?;
+        clients.insert(alias.to_string(), cl.clone());
+        Ok(cl)
+    }
+
+    fn get_auth(&self, alias: &str, scope: &str) -> Option<HttpAuth> {
+        let auth = self.auth.lock().unwrap();
+        auth.get(&(alias.to_string(), scope.to_string()))
+            .filter(|a| a.refresh_at.is_none_or(|t| Instant::now() < t))
+            .map(|a| a.auth.clone())
+    }
+
+    /// Caches `auth`, which is valid for `lifetime` (forever if `None`)
+    fn insert_auth(&self, alias: &str, scope: &str, auth: HttpAuth, lifetime: Option<Duration>) {
+        let now = Instant::now();
+        let cached = CachedAuth {
+            auth,
+            refresh_at: lifetime.map(|l| now + l.mul_f64(0.8)),
+        };
+        let mut cache = self.auth.lock().unwrap();
+        // Expired auth would be replaced on the next pull of its repository anyway
+        cache.retain(|_, a| a.refresh_at.is_none_or(|t| now < t));
+        cache.insert((alias.to_string(), scope.to_string()), cached);
+    }
+
+    fn remove_auth(&self, alias: &str, scope: &str) {
+        self.auth
+            .lock()
+            .unwrap()
+            .remove(&(alias.to_string(), scope.to_string()));
+    }
 }
 
 /// Wrapper around `reqwest::Client` that automagically handles authentication
@@ -46,74 +116,89 @@ pub struct SingleRegistryProxyConfig {
 pub struct ProxyClient {
     pub cl: reqwest::Client,
     pub auth: HttpAuth,
+    /// Alias of the proxied registry, used to label metrics
+    pub alias: String,
+    /// Scope of the auth, e.g. `repository:library/alpine:pull`
+    scope: String,
+    cache: Arc<ProxyClientCache>,
 }
 
 impl ProxyClient {
+    /// Creates a client for `proxy_image`, reusing the connections and auth in `cache`
     pub async fn try_new(
         mut proxy_cfg: SingleRegistryProxyConfig,
         proxy_image: &RemoteImage,
+        cache: &Arc<ProxyClientCache>,
     ) -> Result<Self> {
-        let base_client = reqwest::ClientBuilder::new()
-            .connect_timeout(Duration::from_millis(1000))
-            .build()?;
+        let alias = proxy_cfg.alias.clone();
+        let base_client = cache.client(&alias)?;
+        let scope = format!("repository:{}:pull", proxy_image.get_repo());
+        let new_client = |auth| ProxyClient {
+            cl: base_client.clone(),
+            auth,
+            alias: alias.clone(),
+            scope: scope.clone(),
+            cache: cache.clone(),
+        };
+        if let Some(auth) = cache.get_auth(&alias, &scope) {
+            return Ok(new_client(auth));
+        }
 
         let authn_header = get_www_authenticate_header(&base_client, proxy_image).await?;
 
+        let mut lifetime = None;
         if proxy_cfg.host.contains(".dkr.ecr.")
             && proxy_cfg.host.contains(".amazonaws.com")
             && matches!(&proxy_cfg.username, Some(u) if u == "AWS")
             && proxy_cfg.password.is_none()
         {
-            let passwd = get_aws_ecr_password_from_env(&proxy_cfg.host)
+            let (passwd, expires_in) = get_aws_ecr_password_from_env(&proxy_cfg.host)
                 .await
                 .context("Could not fetch password to ECR registry")?;
             proxy_cfg.password = Some(passwd);
+            lifetime = expires_in;
         }
 
-        match authn_header {
-            Some(h) if h.starts_with("Basic") => {
-                Self::try_new_with_basic_auth(&proxy_cfg, base_client).await
-            }
+        let auth = match authn_header {
+            Some(h) if h.starts_with("Basic") => Self::get_basic_auth(&proxy_cfg)?,
             Some(h) if h.starts_with("Bearer") => {
-                Self::try_new_with_bearer_auth(&proxy_cfg, base_client, &h).await
+                let (auth, expires_in) =
+                    Self::get_bearer_auth(&proxy_cfg, &base_client, &h).await?;
+                lifetime = Some(expires_in);
+                auth
             }
-            None => Ok(ProxyClient {
-                cl: base_client,
-                auth: HttpAuth::None,
-            }),
-            Some(invalid_header) => Err(anyhow!(
-                "Could not parse {AUTHN_HEADER} of registry `{}`: `{}`",
-                proxy_cfg.host,
-                invalid_header
-            )),
-        }
+            None => HttpAuth::None,
+            Some(invalid_header) => {
+                return Err(anyhow!(
+                    "Could not parse {AUTHN_HEADER} of registry `{}`: `{}`",
+                    proxy_cfg.host,
+                    invalid_header
+                ))
+            }
+        };
+        cache.insert_auth(&alias, &scope, auth.clone(), lifetime);
+        Ok(new_client(auth))
     }
 
-    async fn try_new_with_basic_auth(
-        proxy_cfg: &SingleRegistryProxyConfig,
-        cl: reqwest::Client,
-    ) -> Result<Self> {
+    fn get_basic_auth(proxy_cfg: &SingleRegistryProxyConfig) -> Result<HttpAuth> {
         if proxy_cfg.username.is_none() {
             return Err(anyhow!(
                 "Registry `{}` requires Basic auth but no username was provided",
                 proxy_cfg.host
             ));
         }
-        Ok(ProxyClient {
-            cl,
-            auth: HttpAuth::Basic(
-                proxy_cfg.username.clone().unwrap(),
-                proxy_cfg.password.clone(),
-            ),
-        })
+        Ok(HttpAuth::Basic(
+            proxy_cfg.username.clone().unwrap(),
+            proxy_cfg.password.clone(),
+        ))
     }
 
-    async fn try_new_with_bearer_auth(
+    async fn get_bearer_auth(
         proxy_cfg: &SingleRegistryProxyConfig,
-        cl: reqwest::Client,
+        cl: &reqwest::Client,
         authn_header: &str,
-    ) -> Result<Self> {
-        let tok = get_bearer_auth_token(&cl, authn_header, proxy_cfg)
+    ) -> Result<(HttpAuth, Duration)> {
+        let (tok, expires_in) = get_bearer_auth_token(cl, authn_header, proxy_cfg)
             .await
             .map_err(|e| {
                 anyhow!(
@@ -123,10 +208,7 @@ impl ProxyClient {
                 )
             })?;
 
-        Ok(ProxyClient {
-            cl,
-            auth: HttpAuth::Bearer(tok),
-        })
+        Ok((HttpAuth::Bearer(tok), expires_in))
     }
 
     /// Build a request with added authentication.
@@ -139,12 +221,42 @@ impl ProxyClient {
             HttpAuth::None => req,
         }
     }
+
+    /// Sends a request to the proxied registry, recording its latency and any failure.
+    /// `kind` says what is requested (e.g. `manifest` or `blob`) for the metrics.
+    pub async fn send(
+        &self,
+        kind: &str,
+        req: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let start = Instant::now();
+        let resp = req.send().await;
+        metrics::PROXY_UPSTREAM_DURATION
+            .with_label_values(&[&self.alias, kind])
+            .observe(start.elapsed().as_secs_f64());
+        // Connection failures and timeouts have no status
+        let failure = match &resp {
+            Ok(r) if r.status().is_success() => None,
+            Ok(r) => Some(r.status().as_u16().to_string()),
+            Err(_) => Some("none".to_string()),
+        };
+        if let Some(status) = failure {
+            metrics::PROXY_UPSTREAM_ERRORS
+                .with_label_values(&[&self.alias, &status])
+                .inc();
+        }
+        // The auth may have been revoked, or the registry now requires it
+        if matches!(&resp, Ok(r) if r.status() == StatusCode::UNAUTHORIZED) {
+            self.cache.remove_auth(&self.alias, &self.scope);
+        }
+        Ok(resp?)
+    }
 }
 
-/// Fetches AWS ECR credentials.
+/// Fetches AWS ECR credentials, and how long they are valid for.
 /// We use the [rusoto ChainProvider](https://docs.rs/rusoto_credential/0.48.0/rusoto_credential/struct.ChainProvider.html)
 /// to fetch AWS credentials.
-async fn get_aws_ecr_password_from_env(ecr_host: &str) -> Result<String> {
+async fn get_aws_ecr_password_from_env(ecr_host: &str) -> Result<(String, Option<Duration>)> {
     let region = ecr_host
         .split('.')
         .nth(3)
@@ -155,20 +267,25 @@ async fn get_aws_ecr_password_from_env(ecr_host: &str) -> Result<String> {
     let token_resp = ecr_clt
         .get_authorization_token(rusoto_ecr::GetAuthorizationTokenRequest::default())
         .await;
-    let token = token_resp?
+    let authorization_data = token_resp?
         .authorization_data
-        .ok_or_else(|| anyhow!("AWS ECR get token response lacks authorization_data"))?
-        .first()
-        .unwrap()
-        .authorization_token
-        .clone()
-        .unwrap();
+        .ok_or_else(|| anyhow!("AWS ECR get token response lacks authorization_data"))?;
+    let authorization = authorization_data.first().unwrap();
+    let token = authorization.authorization_token.clone().unwrap();
+    let expires_in = authorization.expires_at.map(|expires_at| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Duration::from_secs_f64(expires_at.max(0.0)).saturating_sub(now)
+    });
     // The token is base64(username:password). Here, username is "AWS".
     // To get the password, we trim "AWS:" from the decoded token.
-    let mut auth_str = base64::decode(token)?;
+    let mut auth_str = BASE64.decode(token)?;
     auth_str.drain(0..4);
 
-    String::from_utf8(auth_str).context("Could not convert ECR token to valid password")
+    let passwd =
+        String::from_utf8(auth_str).context("Could not convert ECR token to valid password")?;
+    Ok((passwd, expires_in))
 }
 
 /// Get the WWW-Authenticate header from a registry.
@@ -178,7 +295,7 @@ async fn get_www_authenticate_header(
     image: &RemoteImage,
 ) -> Result<Option<String>> {
     let resp = cl
-        .head(&image.get_manifest_url())
+        .head(image.get_manifest_url())
         .headers(create_accept_header())
         .send()
         .await
@@ -222,11 +339,12 @@ fn get_bearer_param_map(www_authenticate_header: &str) -> HashMap<String, String
         .collect()
 }
 
+/// Fetches a bearer token, and how long it is valid for
 async fn get_bearer_auth_token(
     cl: &reqwest::Client,
     www_authenticate_header: &str,
     auth: &SingleRegistryProxyConfig,
-) -> Result<String> {
+) -> Result<(String, Duration)> {
     let mut bearer_param_map = get_bearer_param_map(www_authenticate_header);
     event!(Level::DEBUG, "bearer param map: {:?}", bearer_param_map);
     let realm = bearer_param_map
@@ -260,17 +378,24 @@ async fn get_bearer_auth_token(
         .await
         .context("Failed to deserialize auth response")?;
 
-    resp_json
+    let token = resp_json
         .get("access_token")
         .or_else(|| resp_json.get("token"))
         .and_then(|s| s.as_str())
         .map(|s| strip_dquotes(s).unwrap_or(s).to_string())
-        .ok_or_else(|| anyhow!("Failed to find auth token in auth response"))
+        .ok_or_else(|| anyhow!("Failed to find auth token in auth response"))?;
+    let expires_in = resp_json
+        .get("expires_in")
+        .and_then(|s| s.as_u64())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+    Ok((token, expires_in))
 }
 
 #[cfg(test)]
 mod tests {
     use httpmock::prelude::*;
+    use httpmock::Mock;
     use serde_json::json;
 
     use super::*;
@@ -285,6 +410,7 @@ mod tests {
             alias: "toto".to_string(),
             username: None,
             password: None,
+            tag_ttl: 0,
         };
 
         let proxy_image = RemoteImage::new(&proxy_cfg.host, "hello_world".into(), "latest".into());
@@ -302,10 +428,56 @@ mod tests {
             then.status(200);
         });
 
-        ProxyClient::try_new(proxy_cfg, &proxy_image).await.unwrap();
+        ProxyClient::try_new(proxy_cfg, &proxy_image, &Arc::default())
+            .await
+            .unwrap();
         mock_server.assert();
     }
 
+    #[tokio::test]
+    async fn test_send_records_errors() {
+        let (server, mut proxy_cfg, proxy_image) = get_basic_setup();
+        proxy_cfg.alias = "send-errors".to_string();
+        server.mock(|when, then| {
+            when.method("HEAD").path("/v2/hello_world/manifests/latest");
+            then.status(200);
+        });
+        server.mock(|when, then| {
+            when.method("GET").path("/v2/hello_world/manifests/latest");
+            then.status(404);
+        });
+
+        let cl = ProxyClient::try_new(proxy_cfg, &proxy_image, &Arc::default())
+            .await
+            .unwrap();
+        let url = proxy_image.get_manifest_url();
+        let resp = cl
+            .send("head", cl.authenticated_request(Method::HEAD, &url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = cl
+            .send("manifest", cl.authenticated_request(Method::GET, &url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let errors = |status| {
+            metrics::PROXY_UPSTREAM_ERRORS
+                .with_label_values(&["send-errors", status])
+                .get()
+        };
+        assert_eq!(errors("404"), 1);
+        assert_eq!(errors("200"), 0);
+        let requests = |kind| {
+            metrics::PROXY_UPSTREAM_DURATION
+                .with_label_values(&["send-errors", kind])
+                .get_sample_count()
+        };
+        assert_eq!(requests("head"), 1);
+        assert_eq!(requests("manifest"), 1);
+    }
+
     #[tokio::test]
     async fn test_basic_auth() {
         let (server, mut cfg, image) = get_basic_setup();
@@ -320,7 +492,9 @@ mod tests {
         let username = "lucifer";
         cfg.username = Some(username.to_string());
 
-        let clt = ProxyClient::try_new(cfg, &image).await.unwrap();
+        let clt = ProxyClient::try_new(cfg, &image, &Arc::default())
+            .await
+            .unwrap();
 
         mock_server.assert();
         assert!(matches!(clt.auth, HttpAuth::Basic(u, None) if u == username));
@@ -354,7 +528,9 @@ mod tests {
             }));
         });
 
-        let cl = ProxyClient::try_new(cfg, &image).await.unwrap();
+        let cl = ProxyClient::try_new(cfg, &image, &Arc::default())
+            .await
+            .unwrap();
 
         mock_head_req.assert();
         mock_auth_tok.assert();
@@ -389,17 +565,126 @@ mod tests {
                 .query_param("scope", "repository:nvidia/cuda:pull,push")
                 .header(
                     AUTHZ_HEADER,
-                    format!("Basic {}", base64::encode("like-this:reign-of-the-septims")),
+                    format!("Basic {}", BASE64.encode("like-this:reign-of-the-septims")),
                 );
             then.status(200).json_body(json!({
                 "token": token,
             }));
         });
 
-        let cl = ProxyClient::try_new(cfg, &image).await.unwrap();
+        let cl = ProxyClient::try_new(cfg, &image, &Arc::default())
+            .await
+            .unwrap();
 
         mock_head_req.assert();
         mock_auth_tok.assert();
         assert!(matches!(cl.auth, HttpAuth::Bearer(tok) if tok == token));
     }
+
+    /// Mocks a registry requiring bearer tokens valid for `expires_in` seconds
+    fn mock_bearer_registry(server: &MockServer, expires_in: u64) -> (Mock<'_>, Mock<'_>) {
+        let head = server.mock(|when, then| {
+            when.method("HEAD");
+            then.status(401).header(
+                AUTHN_HEADER,
+                format!(
+                    "Bearer realm=\"{}/token\",service=\"trow_registry\"",
+                    server.base_url()
+                ),
+            );
+        });
+        let token = server.mock(|when, then| {
+            when.method("GET").path("/token");
+            then.status(200).json_body(json!({
+                "token": "cached-token",
+                "expires_in": expires_in,
+            }));
+        });
+        (head, token)
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_is_cached_per_scope() {
+        let (server, cfg, image) = get_basic_setup();
+        let (head, token) = mock_bearer_registry(&server, 300);
+        let cache = Arc::default();
+
+        for _ in 0..3 {
+            let cl = ProxyClient::try_new(cfg.clone(), &image, &cache)
+                .await
+                .unwrap();
+            assert!(matches!(cl.auth, HttpAuth::Bearer(tok) if tok == "cached-token"));
+        }
+        head.assert_hits(1);
+        token.assert_hits(1);
+
+        let other_image = RemoteImage::new(&cfg.host, "other".into(), "latest".into());
+        ProxyClient::try_new(cfg, &other_image, &cache)
+            .await
+            .unwrap();
+        token.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_bearer_token_is_refreshed() {
+        let (server, cfg, image) = get_basic_setup();
+        let (_, token) = mock_bearer_registry(&server, 0);
+        let cache = Arc::default();
+
+        ProxyClient::try_new(cfg.clone(), &image, &cache)
+            .await
+            .unwrap();
+        ProxyClient::try_new(cfg, &image, &cache).await.unwrap();
+        token.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_auth_is_dropped() {
+        let (server, cfg, image) = get_basic_setup();
+        let head = server.mock(|when, then| {
+            when.method("HEAD").path("/v2/hello_world/manifests/latest");
+            then.status(200);
+        });
+        server.mock(|when, then| {
+            when.method("GET").path("/v2/hello_world/manifests/latest");
+            then.status(401);
+        });
+        let cache = Arc::default();
+
+        let cl = ProxyClient::try_new(cfg.clone(), &image, &cache)
+            .await
+            .unwrap();
+        ProxyClient::try_new(cfg.clone(), &image, &cache)
+            .await
+            .unwrap();
+        head.assert_hits(1);
+        let url = image.get_manifest_url();
+        cl.send("manifest", cl.authenticated_request(Method::GET, &url))
+            .await
+            .unwrap();
+        ProxyClient::try_new(cfg, &image, &cache).await.unwrap();
+        head.assert_hits(2);
+    }
+
+    #[test]
+    fn test_expired_auth_is_evicted() {
+        let cache = ProxyClientCache::default();
+        cache.insert_auth(
+            "toto",
+            "repository:a:pull",
+            HttpAuth::None,
+            Some(Duration::ZERO),
+        );
+        cache.insert_auth("toto", "repository:b:pull", HttpAuth::None, None);
+        cache.insert_auth(
+            "toto",
+            "repository:c:pull",
+            HttpAuth::None,
+            Some(Duration::from_secs(300)),
+        );
+
+        let auth = cache.auth.lock().unwrap();
+        assert_eq!(auth.len(), 2);
+        assert!(!auth.contains_key(&("toto".to_string(), "repository:a:pull".to_string())));
+    }
 }
@@ -0,0 +1,518 @@
+//! On-demand fetching of the blobs of proxied images.
+//!
+//! Pulling a proxied image only downloads its manifests. Each blob is fetched from upstream the
+//! first time a client asks for it: the upstream response is streamed to the client and written to
+//! a temporary file at the same time, and the file is moved to the blob store once its digest has
+//! been verified. If the client goes away, the download carries on so the blob is still cached.
+//!
+//! Manifests and blobs that don't match their digest are never cached. A mismatching manifest is
+//! downloaded again before giving up. The contents of a mismatching blob have already been sent, so
+//! its stream ends with an error for the client to retry, while the blob is downloaded again to be
+//! cached.
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::StreamExt;
+use reqwest::Method;
+use tokio::sync::mpsc;
+use tonic::Status;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::digest::{tag_digest, DigestHasher};
+use crate::image::RemoteImage;
+use crate::metrics;
+use crate::proxy_auth::ProxyClient;
+use crate::server::trow_server::ProxiedBlobChunk;
+use crate::server::{create_accept_header, split_digest, TrowServer};
+use crate::temporary_file::TemporaryFile;
+
+/// Number of chunks buffered between the upstream download and the client
+const CHUNK_BUFFER: usize = 16;
+
+/// Number of times a manifest or blob is downloaded before giving up on a digest mismatch
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+pub(crate) type ChunkSender = mpsc::Sender<Result<ProxiedBlobChunk, Status>>;
+
+fn record_digest_mismatch(alias: &str, kind: &str, digest: &str, actual_digest: &str) {
+    event!(
+        Level::ERROR,
+        "Proxied {} {} from {} has digest {}",
+        kind,
+        digest,
+        alias,
+        actual_digest
+    );
+    metrics::PROXY_DIGEST_MISMATCHES
+        .with_label_values(&[alias, kind])
+        .inc();
+}
+
+impl TrowServer {
+    /// Whether `digest` belongs to a proxied image in `repo_name` but hasn't been fetched yet
+    pub(crate) fn is_blob_fetchable(&self, repo_name: &str, digest: &str) -> bool {
+        match &self.proxy_registry_config {
+            Some(cfg) if !cfg.offline => {}
+            _ => return false,
+        }
+        self.get_remote_image_and_cfg(repo_name, digest).is_some()
+            && self.is_blob_linked(repo_name, digest)
+    }
+
+    /// The size of a proxied blob, as declared by the cached manifests referencing it
+    pub(crate) fn proxied_blob_size(&self, repo_name: &str, digest: &str) -> Option<u64> {
+        let referrers = self.reference_index.read().unwrap().referrers(digest);
+        referrers
+            .iter()
+            .filter(|m| m.repo_name == repo_name)
+            .filter_map(|m| self.read_manifest(&m.digest).ok())
+            .find_map(|manifest| {
+                manifest
+                    .get_local_asset_descriptors()
+                    .into_iter()
+                    .find(|d| d.digest == digest)
+                    .and_then(|d| d.size)
+            })
+    }
+
+    /// Downloads the manifest of `remote_image`, which should have digest `digest`, to a temporary
+    /// file. Returns the file and the manifest once it has been verified.
+    pub(crate) async fn fetch_proxied_manifest(
+        &self,
+        cl: &ProxyClient,
+        remote_image: &RemoteImage,
+        digest: &str,
+    ) -> Result<(TemporaryFile, Bytes)> {
+        let (alg, _) = split_digest(digest)?;
+        // By digest, as the tag may have moved upstream since it was resolved
+        let addr = format!("{}/manifests/{}", remote_image.get_base_uri(), digest);
+        for _ in 0..DOWNLOAD_ATTEMPTS {
+            let resp = cl
+                .send(
+                    "manifest",
+                    cl.authenticated_request(Method::GET, &addr)
+                        .headers(create_accept_header()),
+                )
+                .await?;
+            if !resp.status().is_success() {
+                return Err(anyhow!(
+                    "GET {} returned unexpected {}",
+                    addr,
+                    resp.status()
+                ));
+            }
+            let bytes = resp.bytes().await?;
+            metrics::PROXY_UPSTREAM_BYTES
+                .with_label_values(&[&cl.alias])
+                .inc_by(bytes.len() as u64);
+
+            let actual_digest = tag_digest(alg, &bytes[..])?;
+            if actual_digest != digest {
+                record_digest_mismatch(&cl.alias, "manifest", digest, &actual_digest);
+                continue;
+            }
+            let mut file =
+                TemporaryFile::open_for_writing(self.scratch_path.join(Uuid::new_v4().to_string()))
+                    .await?
+                    .unwrap();
+            file.write_all(&bytes).await?;
+            return Ok((file, bytes));
+        }
+        Err(anyhow!(
+            "GET {} didn't match digest {} after {} attempts",
+            addr,
+            digest,
+            DOWNLOAD_ATTEMPTS
+        ))
+    }
+
+    /// Starts fetching a blob of a proxied image from upstream. Its contents are sent on the
+    /// returned channel, starting with a chunk that only holds its size.
+    pub(crate) async fn fetch_proxied_blob(
+        &self,
+        repo_name: &str,
+        digest: &str,
+    ) -> Result<mpsc::Receiver<Result<ProxiedBlobChunk, Status>>> {
+        let (remote_image, proxy_cfg) = self
+            .get_remote_image_and_cfg(repo_name, digest)
+            .ok_or_else(|| anyhow!("{} is not a proxied repository", repo_name))?;
+        let (alg, _) = split_digest(digest)?;
+        let hasher = DigestHasher::new(alg)?;
+        self.check_free_space()?;
+
+        let cl = ProxyClient::try_new(proxy_cfg, &remote_image, &self.proxy_clients).await?;
+        metrics::PROXY_CACHE_MISSES
+            .with_label_values(&[&cl.alias, "blob"])
+            .inc();
+        let addr = format!("{}/blobs/{}", remote_image.get_base_uri(), digest);
+        event!(Level::INFO, "Fetching blob {}", addr);
+        let resp = self.request_proxied_blob(&cl, &addr).await?;
+
+        // Another request may already be fetching the blob, in which case this copy isn't cached
+        let file = TemporaryFile::open_for_writing(self.scratch_path.join(digest)).await?;
+        if file.is_none() {
+            event!(Level::DEBUG, "Blob {} is already being fetched", digest);
+        }
+        let (tx, rx) = mpsc::channel(CHUNK_BUFFER);
+        let server = self.clone();
+        let digest = digest.to_string();
+        tokio::spawn(async move {
+            let cached = file.is_some();
+            let actual_digest = server
+                .tee_proxied_blob(resp, file, hasher, &tx, &cl.alias, &digest)
+                .await;
+            if let Some(actual_digest) = actual_digest.filter(|d| *d != digest) {
+                record_digest_mismatch(&cl.alias, "blob", &digest, &actual_digest);
+                let _ = tx
+                    .send(Err(Status::data_loss(
+                        "Blob from upstream has the wrong digest",
+                    )))
+                    .await;
+                if cached {
+                    server.retry_proxied_blob(&cl, &addr, &digest).await;
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    async fn request_proxied_blob(
+        &self,
+        cl: &ProxyClient,
+        addr: &str,
+    ) -> Result<reqwest::Response> {
+        let resp = cl
+            .send("blob", cl.authenticated_request(Method::GET, addr))
+            .await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "GET {} returned unexpected {}",
+                addr,
+                resp.status()
+            ));
+        }
+        Ok(resp)
+    }
+
+    /// Streams `resp` to `tx` and to `file`, then saves the file if it has the expected digest.
+    /// Returns the digest of the contents, or `None` if the download didn't complete.
+    async fn tee_proxied_blob(
+        &self,
+        resp: reqwest::Response,
+        mut file: Option<TemporaryFile>,
+        mut hasher: DigestHasher,
+        tx: &ChunkSender,
+        alias: &str,
+        digest: &str,
+    ) -> Option<String> {
+        let header = ProxiedBlobChunk {
+            size: resp.content_length().unwrap_or(0),
+            data: vec![],
+        };
+        let mut client_connected = tx.send(Ok(header)).await.is_ok();
+        let mut stream = resp.bytes_stream();
+        let mut received = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    event!(Level::WARN, "Failed to fetch blob {}: {}", digest, e);
+                    let _ = tx
+                        .send(Err(Status::unavailable("Error reading blob from upstream")))
+                        .await;
+                    return None;
+                }
+            };
+            received += chunk.len() as u64;
+            hasher.update(&chunk);
+            if let Some(f) = file.as_mut() {
+                if let Err(e) = f.write_all(&chunk).await {
+                    event!(Level::WARN, "Failed to cache blob {}: {}", digest, e);
+                    file = None;
+                }
+            }
+            if client_connected {
+                let chunk = ProxiedBlobChunk {
+                    size: 0,
+                    data: chunk.to_vec(),
+                };
+                client_connected = tx.send(Ok(chunk)).await.is_ok();
+            }
+            if !client_connected && file.is_none() {
+                return None;
+            }
+        }
+        metrics::PROXY_UPSTREAM_BYTES
+            .with_label_values(&[alias])
+            .inc_by(received);
+
+        let actual_digest = hasher.finish();
+        if let Some(file) = file.filter(|_| actual_digest == digest) {
+            if let Err(e) = self.save_blob(file.path(), digest) {
+                event!(Level::ERROR, "Failed to save blob {}: {:?}", digest, e);
+            }
+        }
+        Some(actual_digest)
+    }
+
+    /// Downloads a blob whose first download didn't match its digest again, only to cache it
+    async fn retry_proxied_blob(&self, cl: &ProxyClient, addr: &str, digest: &str) {
+        for _ in 1..DOWNLOAD_ATTEMPTS {
+            let mut file =
+                match TemporaryFile::open_for_writing(self.scratch_path.join(digest)).await {
+                    Ok(Some(file)) => file,
+                    // Another request has started fetching the blob
+                    Ok(None) => return,
+                    Err(e) => {
+                        event!(Level::WARN, "Failed to cache blob {}: {}", digest, e);
+                        return;
+                    }
+                };
+            let actual_digest = match self
+                .download_proxied_blob(cl, addr, digest, &mut file)
+                .await
+            {
+                Ok(actual_digest) => actual_digest,
+                Err(e) => {
+                    event!(Level::WARN, "Failed to fetch blob {}: {:?}", digest, e);
+                    return;
+                }
+            };
+            if actual_digest == digest {
+                if let Err(e) = self.save_blob(file.path(), digest) {
+                    event!(Level::ERROR, "Failed to save blob {}: {:?}", digest, e);
+                }
+                return;
+            }
+            record_digest_mismatch(&cl.alias, "blob", digest, &actual_digest);
+        }
+        event!(
+            Level::ERROR,
+            "Giving up on caching blob {} after {} attempts",
+            digest,
+            DOWNLOAD_ATTEMPTS
+        );
+    }
+
+    /// Downloads a blob to `file` and returns the digest of what was received, computed with the
+    /// algorithm of `digest`
+    async fn download_proxied_blob(
+        &self,
+        cl: &ProxyClient,
+        addr: &str,
+        digest: &str,
+        file: &mut TemporaryFile,
+    ) -> Result<String> {
+        let (alg, _) = split_digest(digest)?;
+        let resp = self.request_proxied_blob(cl, addr).await?;
+        let size = file.write_stream(resp.bytes_stream()).await?;
+        metrics::PROXY_UPSTREAM_BYTES
+            .with_label_values(&[&cl.alias])
+            .inc_by(size);
+        tag_digest(
+            alg,
+            std::io::BufReader::new(std::fs::File::open(file.path())?),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::prelude::*;
+    use httpmock::Method::HEAD;
+    use httpmock::Mock;
+
+    use super::DOWNLOAD_ATTEMPTS;
+    use crate::digest::sha256_tag_digest;
+    use crate::image::RemoteImage;
+    use crate::proxy_auth::ProxyClient;
+    use crate::server::TrowServer;
+    use crate::{RegistryProxiesConfig, SingleRegistryProxyConfig};
+
+    const REPO: &str = "f/mock/library/img";
+
+    fn proxy_config(upstream: &MockServer) -> SingleRegistryProxyConfig {
+        SingleRegistryProxyConfig {
+            alias: "mock".to_string(),
+            host: format!("http://{}", upstream.address()),
+            username: None,
+            password: None,
+            tag_ttl: 0,
+        }
+    }
+
+    fn proxy_server(upstream: &MockServer, dir: &tempfile::TempDir) -> TrowServer {
+        let config = RegistryProxiesConfig {
+            registries: vec![proxy_config(upstream)],
+            offline: false,
+        };
+        TrowServer::new(
+            dir.path().to_str().unwrap(),
+            Some(config),
+            None,
+            Default::default(),
+        )
+        .unwrap()
+    }
+
+    /// Serves `content` as the manifest `reference`, which is also how clients check for auth
+    fn mock_manifest<'a>(upstream: &'a MockServer, reference: &str, content: &[u8]) -> Mock<'a> {
+        upstream.mock(|when, then| {
+            when.method(HEAD)
+                .path(format!("/v2/library/img/manifests/{}", reference));
+            then.status(200);
+        });
+        upstream.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/v2/library/img/manifests/{}", reference));
+            then.status(200).body(content);
+        })
+    }
+
+    /// Fetches `digest` from an upstream serving `content`. Returns what the client received and
+    /// whether the stream ended with an error.
+    async fn fetch(
+        server: &TrowServer,
+        upstream: &MockServer,
+        digest: &str,
+        content: &[u8],
+    ) -> (Vec<u8>, bool) {
+        mock_manifest(upstream, digest, b"");
+        server.link_blob(REPO, digest).unwrap();
+        assert!(server.is_blob_fetchable(REPO, digest));
+
+        let mut rx = server.fetch_proxied_blob(REPO, digest).await.unwrap();
+        let header = rx.recv().await.unwrap().unwrap();
+        assert_eq!(header.size, content.len() as u64);
+        let mut received = vec![];
+        let mut failed = false;
+        // The channel is closed once the blob has been cached or given up on
+        while let Some(chunk) = rx.recv().await {
+            match chunk {
+                Ok(chunk) => received.extend(chunk.data),
+                Err(_) => failed = true,
+            }
+        }
+        (received, failed)
+    }
+
+    fn mock_blob<'a>(upstream: &'a MockServer, digest: &str, content: &[u8]) -> Mock<'a> {
+        upstream.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/v2/library/img/blobs/{}", digest));
+            then.status(200).body(content);
+        })
+    }
+
+    #[tokio::test]
+    async fn caches_verified_blobs() {
+        let upstream = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let server = proxy_server(&upstream, &dir);
+        let content = b"some layer";
+        let digest = sha256_tag_digest(&content[..]).unwrap();
+        let blob = mock_blob(&upstream, &digest, content);
+
+        let (received, failed) = fetch(&server, &upstream, &digest, content).await;
+        assert_eq!(received, content);
+        assert!(!failed);
+        blob.assert_hits(1);
+        let path = server.get_catalog_path_for_blob(&digest).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), content);
+        assert!(!server.scratch_path.join(&digest).exists());
+    }
+
+    #[tokio::test]
+    async fn retries_blobs_with_wrong_digest() {
+        let upstream = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let server = proxy_server(&upstream, &dir);
+        let digest = sha256_tag_digest(&b"expected"[..]).unwrap();
+        let blob = mock_blob(&upstream, &digest, b"tampered");
+
+        // The data has already been sent when the mismatch is found, so the stream fails
+        let (received, failed) = fetch(&server, &upstream, &digest, b"tampered").await;
+        assert_eq!(received, b"tampered");
+        assert!(failed);
+        blob.assert_hits(DOWNLOAD_ATTEMPTS as usize);
+        assert!(!server.get_catalog_path_for_blob(&digest).unwrap().exists());
+        assert!(!server.scratch_path.join(&digest).exists());
+    }
+
+    #[tokio::test]
+    async fn verifies_manifests() {
+        let upstream = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let server = proxy_server(&upstream, &dir);
+        let content = br#"{"schemaVersion": 2}"#;
+        let digest = sha256_tag_digest(&content[..]).unwrap();
+        let image = RemoteImage::new(
+            &proxy_config(&upstream).host,
+            "library/img".into(),
+            "tag".into(),
+        );
+        // The tag could point to another manifest by now, so it isn't used
+        let by_tag = mock_manifest(&upstream, "tag", b"moved");
+        let manifest = mock_manifest(&upstream, &digest, content);
+        let cl = ProxyClient::try_new(proxy_config(&upstream), &image, &server.proxy_clients)
+            .await
+            .unwrap();
+
+        let (file, bytes) = server
+            .fetch_proxied_manifest(&cl, &image, &digest)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], content);
+        assert_eq!(std::fs::read(file.path()).unwrap(), content);
+        manifest.assert_hits(1);
+
+        let wrong_digest = sha256_tag_digest(&b"other"[..]).unwrap();
+        let wrong = mock_manifest(&upstream, &wrong_digest, content);
+        assert!(server
+            .fetch_proxied_manifest(&cl, &image, &wrong_digest)
+            .await
+            .is_err());
+        wrong.assert_hits(DOWNLOAD_ATTEMPTS as usize);
+        by_tag.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn reports_sizes_of_uncached_blobs() {
+        let upstream = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let server = proxy_server(&upstream, &dir);
+        let layer = sha256_tag_digest(&b"some layer"[..]).unwrap();
+        let blob = mock_blob(&upstream, &layer, b"some layer");
+        let content = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "size": 2,
+                "digest": sha256_tag_digest(&b"{}"[..]).unwrap(),
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "size": 10,
+                "digest": layer,
+            }],
+        })
+        .to_string();
+        let digest = sha256_tag_digest(content.as_bytes()).unwrap();
+        mock_manifest(&upstream, &digest, content.as_bytes());
+        let image = RemoteImage::new(
+            &proxy_config(&upstream).host,
+            "library/img".into(),
+            digest.clone(),
+        );
+        server
+            .download_remote_image(image, proxy_config(&upstream))
+            .await
+            .unwrap();
+
+        assert!(server.is_blob_fetchable(REPO, &layer));
+        assert_eq!(server.proxied_blob_size(REPO, &layer), Some(10));
+        blob.assert_hits(0);
+    }
+}
@@ -0,0 +1,331 @@
+//! Storage quotas per namespace.
+//!
+//! A namespace is a repository name prefix made of whole path components: the namespace `team-a`
+//! covers `team-a` and `team-a/web`, but not `team-ab`. Its usage is the size of the unique blobs
+//! (layers, configs and manifests) referenced by the tag histories of its repositories, so a layer
+//! shared by several of its images is only counted once. When several quotas cover a repository,
+//! all of them apply.
+//!
+//! Usage is computed from the reference index the first time it is needed and cached. Pushed
+//! manifests are added to the cached usage, while deleting tags drops it so it is computed again.
+
+use std::collections::HashSet;
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tonic::Status;
+use tracing::{event, Level};
+
+use crate::free_space::parse_size;
+use crate::manifest::{FromJson, Manifest};
+use crate::metrics;
+use crate::server::TrowServer;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Quota {
+    pub namespace: String,
+    pub max_bytes: u64,
+}
+
+/// The unique blobs used by a namespace and their total size
+#[derive(Default, Debug)]
+pub(crate) struct NamespaceUsage {
+    blobs: HashSet<String>,
+    bytes: u64,
+}
+
+impl NamespaceUsage {
+    fn add(&mut self, digest: &str, size: u64) {
+        if self.blobs.insert(digest.to_string()) {
+            self.bytes += size;
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Namespace {namespace} would use {usage} bytes, over its quota of {max_bytes} bytes")]
+pub struct QuotaExceededError {
+    namespace: String,
+    usage: u64,
+    max_bytes: u64,
+}
+
+impl Quota {
+    /// Parses `<namespace>=<size>`, where the size is in bytes or has a `K`, `M`, `G` or `T`
+    /// suffix (powers of 1024)
+    pub fn parse(quota: &str) -> Result<Quota> {
+        let (namespace, size) = quota
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Quota `{}` should be `<namespace>=<size>`", quota))?;
+        let namespace = namespace.trim_end_matches('/');
+        if namespace.is_empty()
+            || namespace.starts_with('/')
+            || namespace
+                .split('/')
+                .any(|c| c.is_empty() || c == "." || c == "..")
+        {
+            return Err(anyhow!("Invalid quota namespace `{}`", namespace));
+        }
+        Ok(Quota {
+            namespace: namespace.to_string(),
+            max_bytes: parse_size(size)?,
+        })
+    }
+
+    fn covers(&self, repo_name: &str) -> bool {
+        repo_name
+            .strip_prefix(&self.namespace)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Maps errors from quota checks to gRPC statuses
+pub(crate) fn quota_status(e: anyhow::Error) -> Status {
+    match e.downcast::<QuotaExceededError>() {
+        Ok(e) => Status::resource_exhausted(e.to_string()),
+        Err(e) => {
+            event!(Level::ERROR, "Failed to check quotas: {:?}", e);
+            Status::internal("Internal error checking quotas")
+        }
+    }
+}
+
+impl TrowServer {
+    /// Computes the usage of the namespace of `quota` from the reference index
+    fn compute_namespace_usage(&self, quota: &Quota) -> NamespaceUsage {
+        let digests: HashSet<String> = self
+            .reference_index
+            .read()
+            .unwrap()
+            .manifests()
+            .filter(|(manifest, _)| quota.covers(&manifest.repo_name))
+            .flat_map(|(manifest, blobs)| blobs.iter().chain([&manifest.digest]))
+            .cloned()
+            .collect();
+        let mut usage = NamespaceUsage::default();
+        for digest in digests {
+            match self.get_blob_size(&digest) {
+                Ok(size) => usage.add(&digest, size),
+                // Proxied images may not have all their blobs cached
+                Err(e) => event!(Level::DEBUG, "No size for {}: {:?}", digest, e),
+            }
+        }
+        usage
+    }
+
+    /// Runs `f` on the usage of the namespace of `quota`, computing it if it isn't cached
+    fn with_namespace_usage<T>(&self, quota: &Quota, f: impl FnOnce(&NamespaceUsage) -> T) -> T {
+        let mut cache = self.quota_usage.lock().unwrap();
+        let usage = cache
+            .entry(quota.namespace.clone())
+            .or_insert_with(|| self.compute_namespace_usage(quota));
+        f(usage)
+    }
+
+    /// Adds a manifest that was just indexed, and the blobs it references, to the cached usage of
+    /// the namespaces covering `repo_name`
+    pub(crate) fn add_quota_usage(&self, repo_name: &str, digest: &str, blobs: &[String]) {
+        let mut cache = self.quota_usage.lock().unwrap();
+        for quota in self
+            .quota_config
+            .quotas
+            .iter()
+            .filter(|q| q.covers(repo_name))
+        {
+            if let Some(usage) = cache.get_mut(&quota.namespace) {
+                for blob in blobs.iter().map(String::as_str).chain([digest]) {
+                    if let Ok(size) = self.get_blob_size(blob) {
+                        usage.add(blob, size);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops the cached usage of the namespaces covering `repo_name`, after tags were removed
+    pub(crate) fn invalidate_quota_usage(&self, repo_name: &str) {
+        let mut cache = self.quota_usage.lock().unwrap();
+        for quota in self
+            .quota_config
+            .quotas
+            .iter()
+            .filter(|q| q.covers(repo_name))
+        {
+            cache.remove(&quota.namespace);
+        }
+    }
+
+    /// Checks that adding `blobs` (digests and sizes) to `repo_name` keeps it within its quotas.
+    /// With no blobs, checks that there is any space left.
+    pub(crate) fn check_quotas(&self, repo_name: &str, blobs: &[(&str, u64)]) -> Result<()> {
+        for quota in self
+            .quota_config
+            .quotas
+            .iter()
+            .filter(|q| q.covers(repo_name))
+        {
+            let (usage, full) = self.with_namespace_usage(quota, |existing| {
+                let mut usage = existing.bytes;
+                if blobs.is_empty() {
+                    return (usage, usage >= quota.max_bytes);
+                }
+                let mut added: Vec<&str> = vec![];
+                for (digest, size) in blobs {
+                    if !existing.blobs.contains(*digest) && !added.contains(digest) {
+                        added.push(digest);
+                        usage += size;
+                    }
+                }
+                (usage, usage > quota.max_bytes)
+            });
+            if full {
+                return Err(QuotaExceededError {
+                    namespace: quota.namespace.clone(),
+                    usage,
+                    max_bytes: quota.max_bytes,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the manifest uploaded to `uuid` and the blobs it references fit in the quotas of
+    /// `repo_name`
+    pub(crate) fn check_manifest_quotas(
+        &self,
+        repo_name: &str,
+        uuid: &str,
+        digest: &str,
+    ) -> Result<()> {
+        if !self.quota_config.quotas.iter().any(|q| q.covers(repo_name)) {
+            return Ok(());
+        }
+        let bytes = fs::read(self.get_upload_path_for_blob(uuid))?;
+        let manifest = Manifest::from_json(&serde_json::from_slice(&bytes)?)?;
+        let mut blobs = vec![(digest, bytes.len() as u64)];
+        for asset in manifest.get_local_asset_digests() {
+            blobs.push((asset, self.get_blob_size(asset)?));
+        }
+        self.check_quotas(repo_name, &blobs)
+    }
+
+    /// Exports the usage and size of each quota
+    pub(crate) fn update_quota_metrics(&self) {
+        for quota in &self.quota_config.quotas {
+            let usage = self.with_namespace_usage(quota, |usage| usage.bytes);
+            metrics::QUOTA_USAGE_BYTES
+                .with_label_values(&[&quota.namespace])
+                .set(usage as i64);
+            metrics::QUOTA_LIMIT_BYTES
+                .with_label_values(&[&quota.namespace])
+                .set(quota.max_bytes as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{Quota, QuotaConfig};
+    use crate::digest::sha256_tag_digest;
+    use crate::references::ManifestReference;
+    use crate::server::{ServerPolicies, TrowServer};
+
+    #[test]
+    fn parses_quotas() {
+        assert_eq!(
+            Quota::parse("team-a/=10G").unwrap(),
+            Quota {
+                namespace: "team-a".to_string(),
+                max_bytes: 10 << 30
+            }
+        );
+        assert_eq!(Quota::parse("a/b=512").unwrap().max_bytes, 512);
+        assert_eq!(Quota::parse("a=3K").unwrap().max_bytes, 3072);
+        for invalid in [
+            "team-a",
+            "=1G",
+            "a=",
+            "a=1X",
+            "../a=1",
+            "a//b=1",
+            "a=99999999T",
+        ] {
+            assert!(Quota::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn counts_unique_blobs_per_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let policies = ServerPolicies {
+            quota_config: QuotaConfig {
+                quotas: vec![Quota::parse("team=10").unwrap()],
+            },
+            ..Default::default()
+        };
+        let server = TrowServer::new(dir.path().to_str().unwrap(), None, None, policies).unwrap();
+
+        let add_blob = |content: &[u8]| {
+            let digest = sha256_tag_digest(content).unwrap();
+            let path = server.get_catalog_path_for_blob(&digest).unwrap();
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+            digest
+        };
+        // Indexes a manifest like pushing a tag does
+        let add_manifest = |repo: &str, content: &[u8], blobs: &[&str]| {
+            let digest = add_blob(content);
+            let blobs: Vec<String> = blobs.iter().map(|b| b.to_string()).collect();
+            server.reference_index.write().unwrap().insert(
+                ManifestReference {
+                    repo_name: repo.to_string(),
+                    digest: digest.clone(),
+                },
+                blobs.clone(),
+                None,
+            );
+            server.add_quota_usage(repo, &digest, &blobs);
+        };
+        let shared = add_blob(b"12345");
+        let big = add_blob(b"1234567890");
+        add_manifest("team/one", b"m1", &[&shared]);
+        add_manifest("team/two", b"m2", &[&shared]);
+        add_manifest("teammate", b"mate", &[&big]);
+
+        assert!(server.check_quotas("team/two", &[]).is_ok());
+        // Blobs already in the namespace are free
+        assert!(server.check_quotas("team", &[(&shared, 5)]).is_ok());
+        assert!(server
+            .check_quotas("team/one", &[("sha256:new", 1)])
+            .is_ok());
+        let err = server
+            .check_quotas("team/one", &[("sha256:new", 2)])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Namespace team would use 11 bytes, over its quota of 10 bytes"
+        );
+        // Not covered by the quota
+        assert!(server
+            .check_quotas("teammate", &[("sha256:new", 6)])
+            .is_ok());
+
+        // Added to the cached usage
+        add_manifest("team", b"m3", &[]);
+        assert!(server.check_quotas("team/one", &[]).is_err());
+        // The repository has no tag files, so reindexing it frees the space again
+        server.reindex_repo("team").unwrap();
+        assert!(server.check_quotas("team/one", &[]).is_ok());
+    }
+}
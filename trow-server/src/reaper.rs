@@ -0,0 +1,167 @@
+//! Periodic cleanup of the scratch directory.
+//!
+//! Upload sessions that haven't received data for longer than the TTL are considered abandoned
+//! and forgotten, along with their data. Any other file in scratch that hasn't been touched for the
+//! TTL is left over from a failed manifest push or an interrupted proxy download, and is deleted as
+//! well.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use tracing::{event, Level};
+
+use crate::metrics;
+use crate::server::TrowServer;
+use crate::uploads::Upload;
+
+/// Uploads and scratch files untouched for this long are deleted, unless configured otherwise.
+pub const DEFAULT_UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Shorter TTLs would cut off clients that pause between chunks.
+pub const MIN_UPLOAD_TTL: Duration = Duration::from_secs(5 * 60);
+/// How often the scratch directory is checked, at most.
+const REAPER_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Reclaimed {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Runs forever, cleaning up the scratch directory of `server`. The TTL is at least
+/// `MIN_UPLOAD_TTL`.
+pub(crate) async fn reap_periodically(server: TrowServer, ttl: Duration) {
+    let ttl = ttl.max(MIN_UPLOAD_TTL);
+    let mut interval = tokio::time::interval(REAPER_INTERVAL.min(ttl));
+    loop {
+        interval.tick().await;
+        let server = server.clone();
+        match tokio::task::spawn_blocking(move || server.reap_scratch(ttl)).await {
+            Ok(Ok(reclaimed)) => {
+                if reclaimed.files > 0 {
+                    event!(
+                        Level::INFO,
+                        "Reclaimed {} scratch files ({} bytes)",
+                        reclaimed.files,
+                        reclaimed.bytes
+                    );
+                }
+            }
+            Ok(Err(e)) => event!(Level::WARN, "Failed to clean up scratch directory: {:?}", e),
+            Err(e) => event!(Level::ERROR, "Scratch cleanup task failed: {:?}", e),
+        }
+    }
+}
+
+/// Time since the file was last modified, if it exists
+fn idle_time(path: &Path, now: SystemTime) -> Option<Duration> {
+    fs::metadata(path)
+        .and_then(|md| md.modified())
+        .map(|modified| now.duration_since(modified).unwrap_or_default())
+        .ok()
+}
+
+impl TrowServer {
+    /// Deletes upload sessions and scratch files that haven't been written to for `ttl`.
+    pub(crate) fn reap_scratch(&self, ttl: Duration) -> Result<Reclaimed> {
+        let now = SystemTime::now();
+        let mut reclaimed = Reclaimed::default();
+
+        let uploads: Vec<Upload> = self
+            .active_uploads
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        let mut live_files = HashSet::new();
+        for upload in uploads {
+            let data_path = self.get_upload_path_for_blob(&upload.uuid);
+            let metadata_path = self.get_upload_metadata_path(&upload.uuid);
+            // The data file only exists once the first chunk has been received
+            let idle = [&data_path, &metadata_path]
+                .into_iter()
+                .filter_map(|p| idle_time(p, now))
+                .min()
+                .unwrap_or_default();
+            if idle < ttl {
+                live_files.insert(data_path);
+                live_files.insert(metadata_path);
+                continue;
+            }
+            event!(
+                Level::INFO,
+                "Expiring upload {} to {} (idle for {}s)",
+                upload.uuid,
+                upload.repo_name,
+                idle.as_secs()
+            );
+            // Its files are deleted with the other stale ones below
+            self.active_uploads.write().unwrap().remove(&upload);
+        }
+
+        for entry in fs::read_dir(&self.scratch_path)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || live_files.contains(&path) {
+                continue;
+            }
+            if idle_time(&path, now).unwrap_or_default() < ttl {
+                continue;
+            }
+            event!(Level::DEBUG, "Removing stale scratch file {:?}", path);
+            if let Err(e) = fs::remove_file(&path) {
+                event!(Level::WARN, "Failed to remove {:?}: {:?}", path, e);
+                continue;
+            }
+            reclaimed.files += 1;
+            reclaimed.bytes += metadata.len();
+        }
+
+        metrics::RECLAIMED_SCRATCH_FILES.inc_by(reclaimed.files);
+        metrics::RECLAIMED_SCRATCH_BYTES.inc_by(reclaimed.bytes);
+        Ok(reclaimed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::{Reclaimed, DEFAULT_UPLOAD_TTL};
+    use crate::server::TrowServer;
+    use crate::uploads::Upload;
+
+    #[test]
+    fn reaps_abandoned_uploads_and_stale_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+
+        let upload = Upload {
+            repo_name: "repo".to_string(),
+            uuid: "abandoned".to_string(),
+        };
+        server.add_upload(upload.clone()).unwrap();
+        fs::write(server.get_upload_path_for_blob(&upload.uuid), b"12345").unwrap();
+        fs::write(server.get_upload_path_for_blob("failed-manifest"), b"{}").unwrap();
+
+        let sidecar_size = fs::metadata(server.get_upload_metadata_path(&upload.uuid))
+            .unwrap()
+            .len();
+
+        let reclaimed = server.reap_scratch(DEFAULT_UPLOAD_TTL).unwrap();
+        assert_eq!(reclaimed, Reclaimed::default());
+        assert!(server.has_upload(&upload));
+
+        let reclaimed = server.reap_scratch(Duration::ZERO).unwrap();
+        assert_eq!(reclaimed.files, 3);
+        assert_eq!(reclaimed.bytes, 5 + 2 + sidecar_size);
+        assert!(!server.has_upload(&upload));
+        assert_eq!(fs::read_dir(&server.scratch_path).unwrap().count(), 0);
+    }
+}
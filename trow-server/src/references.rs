@@ -0,0 +1,183 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A manifest, as stored in a given repository.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ManifestReference {
+    pub repo_name: String,
+    pub digest: String,
+}
+
+impl fmt::Display for ManifestReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.repo_name, self.digest)
+    }
+}
+
+/// In-memory index of which manifests reference which blobs (config, layers and child
+/// manifests for lists), and of which manifests declare another one as their `subject`.
+///
+/// Only manifests that appear in a tag history are indexed, so a blob with no referrers can be
+/// deleted without breaking any image.
+#[derive(Default, Debug)]
+pub struct ReferenceIndex {
+    /// blob digest -> manifests referencing it
+    referrers: HashMap<String, HashSet<ManifestReference>>,
+    /// manifest -> blob digests it references
+    references: HashMap<ManifestReference, Vec<String>>,
+    /// subject manifest -> manifests declaring it as their subject, in the same repository
+    subjects: HashMap<ManifestReference, HashSet<ManifestReference>>,
+}
+
+impl ReferenceIndex {
+    pub fn insert(
+        &mut self,
+        manifest: ManifestReference,
+        blobs: Vec<String>,
+        subject: Option<String>,
+    ) {
+        if self.references.contains_key(&manifest) {
+            return;
+        }
+        if let Some(subject) = subject {
+            let subject = ManifestReference {
+                repo_name: manifest.repo_name.clone(),
+                digest: subject,
+            };
+            self.subjects
+                .entry(subject)
+                .or_default()
+                .insert(manifest.clone());
+        }
+        for blob in &blobs {
+            self.referrers
+                .entry(blob.clone())
+                .or_default()
+                .insert(manifest.clone());
+        }
+        self.references.insert(manifest, blobs);
+    }
+
+    /// Forgets about all manifests in `repo_name`.
+    pub fn remove_repo(&mut self, repo_name: &str) {
+        self.subjects.retain(|s, _| s.repo_name != repo_name);
+        let manifests: Vec<ManifestReference> = self
+            .references
+            .keys()
+            .filter(|m| m.repo_name == repo_name)
+            .cloned()
+            .collect();
+        for manifest in manifests {
+            for blob in self.references.remove(&manifest).unwrap_or_default() {
+                if let Some(referrers) = self.referrers.get_mut(&blob) {
+                    referrers.remove(&manifest);
+                    if referrers.is_empty() {
+                        self.referrers.remove(&blob);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Iterates over all indexed manifests and the blobs they reference.
+    pub fn manifests(&self) -> impl Iterator<Item = (&ManifestReference, &Vec<String>)> {
+        self.references.iter()
+    }
+
+    /// Returns the manifests referencing `blob`, sorted.
+    pub fn referrers(&self, blob: &str) -> Vec<ManifestReference> {
+        let mut referrers: Vec<ManifestReference> = self
+            .referrers
+            .get(blob)
+            .map(|r| r.iter().cloned().collect())
+            .unwrap_or_default();
+        referrers.sort();
+        referrers
+    }
+
+    /// Returns the manifests that declare `subject` as their subject, sorted.
+    pub fn subject_referrers(&self, subject: &ManifestReference) -> Vec<ManifestReference> {
+        let mut referrers: Vec<ManifestReference> = self
+            .subjects
+            .get(subject)
+            .map(|r| r.iter().cloned().collect())
+            .unwrap_or_default();
+        referrers.sort();
+        referrers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ManifestReference, ReferenceIndex};
+
+    fn manifest(repo_name: &str, digest: &str) -> ManifestReference {
+        ManifestReference {
+            repo_name: repo_name.to_string(),
+            digest: digest.to_string(),
+        }
+    }
+
+    #[test]
+    fn tracks_referrers_per_repo() {
+        let mut index = ReferenceIndex::default();
+        index.insert(
+            manifest("one", "sha256:m1"),
+            vec!["sha256:config".to_string(), "sha256:layer".to_string()],
+            None,
+        );
+        index.insert(
+            manifest("two", "sha256:m2"),
+            vec!["sha256:layer".to_string()],
+            None,
+        );
+
+        assert_eq!(
+            index.referrers("sha256:layer"),
+            vec![manifest("one", "sha256:m1"), manifest("two", "sha256:m2")]
+        );
+        assert_eq!(index.referrers("sha256:unknown"), vec![]);
+        assert_eq!(
+            format!("{}", index.referrers("sha256:config")[0]),
+            "one@sha256:m1"
+        );
+
+        index.remove_repo("one");
+        assert_eq!(index.referrers("sha256:config"), vec![]);
+        assert_eq!(
+            index.referrers("sha256:layer"),
+            vec![manifest("two", "sha256:m2")]
+        );
+    }
+
+    #[test]
+    fn tracks_subjects_per_repo() {
+        let mut index = ReferenceIndex::default();
+        index.insert(manifest("one", "sha256:image"), vec![], None);
+        index.insert(
+            manifest("one", "sha256:sig"),
+            vec![],
+            Some("sha256:image".to_string()),
+        );
+        index.insert(
+            manifest("two", "sha256:sbom"),
+            vec![],
+            Some("sha256:image".to_string()),
+        );
+
+        assert_eq!(
+            index.subject_referrers(&manifest("one", "sha256:image")),
+            vec![manifest("one", "sha256:sig")]
+        );
+        assert_eq!(
+            index.subject_referrers(&manifest("two", "sha256:image")),
+            vec![manifest("two", "sha256:sbom")]
+        );
+
+        index.remove_repo("one");
+        assert_eq!(
+            index.subject_referrers(&manifest("one", "sha256:image")),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,451 @@
+//! Tag retention policies.
+//!
+//! Each policy applies to the repositories whose name matches `repositories` and to their tags
+//! matching `tags`. Of those, a tag expires if it isn't one of the `keep_last` most recently pushed,
+//! or if it hasn't been pushed for `older_than_days`. Tags matching a `protect` pattern of any
+//! policy that applies to the repository are never deleted. All patterns are regular expressions.
+//!
+//! When a tag was last pushed is read from the last line of its tag history file. Manifests pushed
+//! by digest aren't tags and are left alone. Immutable tags are never deleted either; when a policy
+//! expires one, it is reported as protected instead.
+//!
+//! Deleting a tag doesn't free any space by itself; the blobs it referenced are only deleted by the
+//! garbage collector, which can be run after each enforcement.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::gc::GC_GRACE_PERIOD;
+use crate::metrics;
+use crate::server::trow_server::{ExpiredTag, RetentionReport};
+use crate::server::{get_digest_from_manifest_path, is_digest, RepoIterator, TrowServer};
+
+/// How often policies are enforced, unless configured otherwise
+const DEFAULT_RETENTION_INTERVAL: u64 = 24 * 60 * 60;
+
+fn default_interval() -> u64 {
+    DEFAULT_RETENTION_INTERVAL
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionConfig {
+    /// Seconds between two enforcements
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Only report what would be deleted
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Run the garbage collector after deleting tags
+    #[serde(default)]
+    pub collect_garbage: bool,
+    pub policies: Vec<RetentionPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionPolicy {
+    /// Repositories the policy applies to, all of them if unset
+    pub repositories: Option<String>,
+    /// Tags the policy applies to, all of them if unset
+    pub tags: Option<String>,
+    /// Number of most recently pushed tags to keep
+    pub keep_last: Option<usize>,
+    /// Delete tags that haven't been pushed for this many days
+    pub older_than_days: Option<u32>,
+    /// Tags that are never deleted
+    #[serde(default)]
+    pub protect: Vec<String>,
+}
+
+/// A policy with its patterns compiled
+struct CompiledPolicy<'a> {
+    policy: &'a RetentionPolicy,
+    repositories: Option<Regex>,
+    tags: Option<Regex>,
+    protect: Vec<Regex>,
+}
+
+impl RetentionConfig {
+    /// Checks that all patterns are valid regular expressions
+    pub fn validate(&self) -> Result<()> {
+        self.compile().map(|_| ())
+    }
+
+    fn compile(&self) -> Result<Vec<CompiledPolicy<'_>>> {
+        let compile = |pattern: &str| {
+            Regex::new(pattern).with_context(|| format!("Invalid pattern `{}`", pattern))
+        };
+        self.policies
+            .iter()
+            .map(|policy| {
+                Ok(CompiledPolicy {
+                    policy,
+                    repositories: policy.repositories.as_deref().map(compile).transpose()?,
+                    tags: policy.tags.as_deref().map(compile).transpose()?,
+                    protect: policy
+                        .protect
+                        .iter()
+                        .map(|p| compile(p))
+                        .collect::<Result<_>>()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The current state of a tag
+struct TagInfo {
+    tag: String,
+    digest: String,
+    pushed: DateTime<FixedOffset>,
+}
+
+/// Returns the tags that `policies` expire, from the tags of a single repository
+fn expired_tags<'a>(
+    repo_name: &str,
+    tags: &'a [TagInfo],
+    policies: &[CompiledPolicy],
+    now: DateTime<Utc>,
+) -> Vec<&'a TagInfo> {
+    let policies: Vec<&CompiledPolicy> = policies
+        .iter()
+        .filter(|p| {
+            p.repositories
+                .as_ref()
+                .is_none_or(|r| r.is_match(repo_name))
+        })
+        .collect();
+    let protected = |tag: &str| {
+        policies
+            .iter()
+            .any(|p| p.protect.iter().any(|r| r.is_match(tag)))
+    };
+
+    let mut expired = BTreeSet::new();
+    for policy in &policies {
+        let mut candidates: Vec<(usize, &TagInfo)> = tags
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| policy.tags.as_ref().is_none_or(|r| r.is_match(&t.tag)))
+            .filter(|(_, t)| !protected(&t.tag))
+            .collect();
+        // Most recently pushed first
+        candidates.sort_by(|(_, a), (_, b)| b.pushed.cmp(&a.pushed).then(a.tag.cmp(&b.tag)));
+        for (rank, (i, tag)) in candidates.into_iter().enumerate() {
+            let too_many = policy.policy.keep_last.is_some_and(|n| rank >= n);
+            let too_old = policy
+                .policy
+                .older_than_days
+                .is_some_and(|d| tag.pushed < now - chrono::Duration::days(d.into()));
+            if too_many || too_old {
+                expired.insert(i);
+            }
+        }
+    }
+    expired.into_iter().map(|i| &tags[i]).collect()
+}
+
+/// Runs forever, enforcing the retention policies of `server`.
+pub(crate) async fn enforce_periodically(server: TrowServer, config: RetentionConfig) {
+    let period = Duration::from_secs(config.interval.max(1));
+    // The first enforcement happens one interval after startup, not straight away
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let server = server.clone();
+        let config = config.clone();
+        let res =
+            tokio::task::spawn_blocking(move || server.enforce_retention(&config, config.dry_run))
+                .await;
+        match res {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => event!(Level::WARN, "Failed to enforce retention policies: {:?}", e),
+            Err(e) => event!(Level::ERROR, "Retention task failed: {:?}", e),
+        }
+    }
+}
+
+impl TrowServer {
+    /// Deletes the tags expired by the retention policies in `config`, then collects garbage if
+    /// configured. If `dry_run` is set, nothing is deleted but the report is still produced.
+    pub(crate) fn enforce_retention(
+        &self,
+        config: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<RetentionReport> {
+        event!(
+            Level::INFO,
+            "Enforcing retention policies (dry run: {})",
+            dry_run
+        );
+        let policies = config.compile()?;
+        let now = Utc::now();
+
+        let mut report = RetentionReport {
+            tags: vec![],
+            dry_run,
+            garbage: None,
+            protected: vec![],
+        };
+        for (repo_name, tags) in self.get_tags_by_repo()? {
+            let expired = expired_tags(&repo_name, &tags, &policies, now);
+            if expired.is_empty() {
+                continue;
+            }
+            for tag in expired {
+                let expired_tag = ExpiredTag {
+                    repo_name: repo_name.clone(),
+                    tag: tag.tag.clone(),
+                    digest: tag.digest.clone(),
+                    last_pushed: tag.pushed.to_rfc3339(),
+                };
+                if self.immutable_tags.is_immutable(&repo_name, &tag.tag) {
+                    event!(
+                        Level::INFO,
+                        "Tag {}:{} expired but is immutable, keeping it",
+                        repo_name,
+                        tag.tag
+                    );
+                    report.protected.push(expired_tag);
+                    continue;
+                }
+                event!(
+                    Level::INFO,
+                    "Tag {}:{} ({}) expired, last pushed {}",
+                    repo_name,
+                    tag.tag,
+                    tag.digest,
+                    tag.pushed.to_rfc3339()
+                );
+                if !dry_run {
+                    let path = self.manifests_path.join(&repo_name).join(&tag.tag);
+                    // The tag may have been pushed again since it was read
+                    match get_digest_from_manifest_path(&path) {
+                        Ok(digest) if digest == tag.digest => (),
+                        Ok(_) => {
+                            event!(
+                                Level::INFO,
+                                "Tag {}:{} was pushed again, keeping it",
+                                repo_name,
+                                tag.tag
+                            );
+                            continue;
+                        }
+                        Err(e) => {
+                            event!(Level::WARN, "Failed to read tag {:?}: {:?}", path, e);
+                            continue;
+                        }
+                    }
+                    if let Err(e) = fs::remove_file(&path) {
+                        event!(Level::WARN, "Failed to delete tag {:?}: {:?}", path, e);
+                        continue;
+                    }
+                    metrics::RETENTION_DELETED_TAGS.inc();
+                }
+                report.tags.push(expired_tag);
+            }
+            if !dry_run {
+                self.reindex_repo(&repo_name)?;
+            }
+        }
+        event!(
+            Level::INFO,
+            "Retention policies expired {} tags",
+            report.tags.len()
+        );
+
+        // In a dry run no tag was deleted, so there is nothing new to collect
+        if config.collect_garbage && !dry_run {
+            report.garbage = Some(self.collect_garbage(false, GC_GRACE_PERIOD)?);
+        }
+        Ok(report)
+    }
+
+    /// Reads the current digest and push date of every tag, grouped by repository
+    fn get_tags_by_repo(&self) -> Result<BTreeMap<String, Vec<TagInfo>>> {
+        let mut repos: BTreeMap<String, Vec<TagInfo>> = BTreeMap::new();
+        for tag_file in RepoIterator::new(&self.manifests_path)? {
+            let path = tag_file.path();
+            let (repo_name, tag) = match (
+                path.parent()
+                    .and_then(|p| p.strip_prefix(&self.manifests_path).ok()),
+                path.file_name(),
+            ) {
+                (Some(r), Some(t)) => (
+                    r.to_string_lossy().to_string(),
+                    t.to_string_lossy().to_string(),
+                ),
+                _ => continue,
+            };
+            if is_digest(&tag) {
+                continue;
+            }
+            let history = match fs::read_to_string(&path) {
+                Ok(h) => h,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Skipping unreadable tag file {:?}: {:?}",
+                        path,
+                        e
+                    );
+                    continue;
+                }
+            };
+            // Each line is `{digest} {date}`, the last one is the current digest
+            let parsed = history.lines().last().and_then(|line| {
+                let (digest, date) = line.split_once(' ')?;
+                let pushed = DateTime::parse_from_rfc3339(date.trim()).ok()?;
+                Some((digest.to_string(), pushed))
+            });
+            match parsed {
+                Some((digest, pushed)) => repos.entry(repo_name).or_default().push(TagInfo {
+                    tag,
+                    digest,
+                    pushed,
+                }),
+                None => event!(Level::WARN, "Can't read push date of tag {:?}", path),
+            }
+        }
+        for tags in repos.values_mut() {
+            tags.sort_by(|a, b| a.tag.cmp(&b.tag));
+        }
+        Ok(repos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+
+    use chrono::{Duration, Utc};
+
+    use super::{RetentionConfig, RetentionPolicy};
+    use crate::immutable_tags::ImmutableTagsConfig;
+    use crate::server::{ServerPolicies, TrowServer};
+
+    fn add_tag(server: &TrowServer, repo: &str, tag: &str, days_ago: i64) {
+        let repo_dir = server.manifests_path.join(repo);
+        fs::create_dir_all(&repo_dir).unwrap();
+        let mut file = fs::File::create(repo_dir.join(tag)).unwrap();
+        let date = Utc::now() - Duration::days(days_ago);
+        writeln!(
+            file,
+            "sha256:{:064} {}",
+            days_ago,
+            date.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+        )
+        .unwrap();
+    }
+
+    fn config() -> RetentionConfig {
+        RetentionConfig {
+            interval: 60,
+            dry_run: false,
+            collect_garbage: false,
+            policies: vec![
+                RetentionPolicy {
+                    repositories: Some("^app".to_string()),
+                    tags: Some("^pr-".to_string()),
+                    keep_last: Some(2),
+                    older_than_days: None,
+                    protect: vec![],
+                },
+                RetentionPolicy {
+                    repositories: None,
+                    tags: None,
+                    keep_last: None,
+                    older_than_days: Some(30),
+                    protect: vec![r"^v\d+".to_string()],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn enforces_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+        for (tag, days_ago) in [("pr-1", 4), ("pr-2", 3), ("pr-3", 2), ("pr-4", 1)] {
+            add_tag(&server, "app/web", tag, days_ago);
+            add_tag(&server, "other", tag, days_ago);
+        }
+        add_tag(&server, "app/web", "v1", 100);
+        add_tag(&server, "app/web", "latest", 40);
+        add_tag(&server, "other", "latest", 10);
+
+        let expired = |dry_run| {
+            let report = server.enforce_retention(&config(), dry_run).unwrap();
+            assert_eq!(report.dry_run, dry_run);
+            report
+                .tags
+                .into_iter()
+                .map(|t| format!("{}:{}", t.repo_name, t.tag))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec!["app/web:latest", "app/web:pr-1", "app/web:pr-2"];
+        assert_eq!(expired(true), expected);
+        assert!(server.manifests_path.join("app/web/pr-1").exists());
+
+        assert_eq!(expired(false), expected);
+        assert!(!server.manifests_path.join("app/web/pr-1").exists());
+        assert!(server.manifests_path.join("app/web/pr-3").exists());
+        assert!(server.manifests_path.join("app/web/v1").exists());
+        assert!(server.manifests_path.join("other/pr-1").exists());
+
+        assert!(expired(false).is_empty());
+    }
+
+    #[test]
+    fn keeps_immutable_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let policies = ServerPolicies {
+            immutable_tags: ImmutableTagsConfig {
+                patterns: vec!["app/*:pr-1".to_string()],
+            },
+            ..Default::default()
+        };
+        let server = TrowServer::new(dir.path().to_str().unwrap(), None, None, policies).unwrap();
+        for (tag, days_ago) in [("pr-1", 4), ("pr-2", 3), ("pr-3", 2), ("pr-4", 1)] {
+            add_tag(&server, "app/web", tag, days_ago);
+        }
+
+        let report = server.enforce_retention(&config(), false).unwrap();
+        let tags: Vec<&str> = report.tags.iter().map(|t| t.tag.as_str()).collect();
+        assert_eq!(tags, vec!["pr-2"]);
+        let protected: Vec<&str> = report.protected.iter().map(|t| t.tag.as_str()).collect();
+        assert_eq!(protected, vec!["pr-1"]);
+        assert!(server.manifests_path.join("app/web/pr-1").exists());
+        assert!(!server.manifests_path.join("app/web/pr-2").exists());
+    }
+
+    #[test]
+    fn skips_unreadable_tag_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+        add_tag(&server, "app/web", "latest", 40);
+        fs::create_dir_all(server.manifests_path.join("broken")).unwrap();
+        fs::write(server.manifests_path.join("broken/latest"), [0xff, 0xfe]).unwrap();
+
+        let report = server.enforce_retention(&config(), false).unwrap();
+        assert_eq!(report.tags.len(), 1);
+        assert!(!server.manifests_path.join("app/web/latest").exists());
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let mut config = config();
+        assert!(config.validate().is_ok());
+        config.policies[0].protect.push("(".to_string());
+        assert!(config.validate().is_err());
+    }
+}
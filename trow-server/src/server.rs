@@ -1,77 +1,131 @@
-use std::collections::HashSet;
+#[cfg(not(feature = "sqlite"))]
+use std::collections::BTreeSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, DirEntry, File};
-use std::io::{BufRead, BufReader};
+#[cfg(not(feature = "sqlite"))]
+use std::io::BufRead;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use std::{io, str};
 
 use anyhow::{anyhow, Result};
 use async_recursion::async_recursion;
 use chrono::prelude::*;
 use futures::future::try_join_all;
+use lazy_static::lazy_static;
 use prost_types::Timestamp;
-use reqwest::header::{HeaderMap, HeaderValue};
+use regex::Regex;
+use reqwest::header::{self, HeaderMap, HeaderValue};
 use reqwest::{self, Method};
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
-use tokio::time::Duration;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 use tracing::{event, Level};
 use uuid::Uuid;
 
 use self::trow_server::*;
-use crate::digest::sha256_tag_digest;
+use crate::digest::tag_digest;
+use crate::free_space::{free_space_status, LowFreeSpaceError};
+use crate::gc::GC_GRACE_PERIOD;
 use crate::image::RemoteImage;
-use crate::manifest::{manifest_media_type, FromJson, Manifest};
-use crate::proxy_auth::{ProxyClient, SingleRegistryProxyConfig};
+use crate::immutable_tags::ImmutableTagsConfig;
+use crate::manifest::{
+    is_valid_media_type, manifest_media_type, FromJson, Manifest, ManifestListEntry, Object,
+};
+use crate::media_types::MediaTypeConfig;
+#[cfg(feature = "sqlite")]
+use crate::metadata::{MetadataStore, METADATA_DB};
+use crate::proxy_auth::{ProxyClient, ProxyClientCache, SingleRegistryProxyConfig};
+use crate::quotas::{quota_status, NamespaceUsage, QuotaConfig, QuotaExceededError};
+use crate::references::{ManifestReference, ReferenceIndex};
+use crate::retention::RetentionConfig;
 use crate::server::trow_server::registry_server::Registry;
-use crate::temporary_file::TemporaryFile;
+use crate::uploads::Upload;
 use crate::{metrics, ImageValidationConfig, RegistryProxiesConfig};
 
 pub mod trow_server {
     include!("../../trow-protobuf/out/trow.rs");
 }
 
-static SUPPORTED_DIGESTS: [&str; 1] = ["sha256"];
+pub(crate) static SUPPORTED_DIGESTS: [&str; 2] = ["sha256", "sha512"];
 static MANIFESTS_DIR: &str = "manifests";
 static BLOBS_DIR: &str = "blobs";
 static UPLOADS_DIR: &str = "scratch";
+static LINKS_DIR: &str = "links";
+// Repository name components can't start with '_', so this can't clash with a nested repo
+static BLOB_LINKS_DIR: &str = "_blobs";
 
-static PROXY_DIR: &str = "f/"; //Repositories starting with this are considered proxies
+pub(crate) static PROXY_DIR: &str = "f/"; //Repositories starting with this are considered proxies
 static DIGEST_HEADER: &str = "Docker-Content-Digest";
 
+/// An indexed manifest, the blobs it references and its subject
+pub(crate) type IndexEntry = (ManifestReference, Vec<String>, Option<String>);
+
+/// Held while an immutable tag is checked and saved
+type TagLock = Arc<tokio::sync::Mutex<()>>;
+
 /* Struct implementing callbacks for the Frontend
  *
- * _active_uploads_: a HashSet of all uuids that are currently being tracked
+ * _active_uploads_: a HashSet of all uuids that are currently being tracked, mirrored to disk
+ *   so that uploads can be resumed after a restart
  * _manifests_path_: path to where the manifests are
  * _layers_path_: path to where blobs are stored
  * _scratch_path_: path to temporary storage for uploads
+ * _links_path_: path to the records of which blobs belong to which repository
+ * _reference_index_: which manifests reference which blobs, and which declare a subject
+ * _proxy_clients_: connection pools and auth tokens of the proxied registries
+ * _proxied_tag_checks_: when each proxied tag was last found to match upstream
+ * _immutable_tag_locks_: serialize the pushes of each immutable tag
+ * _gc_lock_: held for reading while blobs are added or referenced, and for writing by GC
+ * _media_type_config_: which config and layer media types can be pushed
+ * _immutable_tags_: which tags can't be moved once pushed
+ * _quota_config_: how much storage namespaces can use
+ * _quota_usage_: cached usage of the namespaces with a quota
+ * _min_free_space_: uploads are refused below this many bytes of available disk space (0 disables)
+ * _retention_config_: which tags are deleted by retention policies, if any
+ * _metadata_: SQLite index of repositories, tags and blobs (with the `sqlite` feature)
  *
  * Each "route" gets a clone of this struct.
  * The Arc makes sure they all point to the same data.
  */
 #[derive(Clone)]
 pub struct TrowServer {
-    active_uploads: Arc<RwLock<HashSet<Upload>>>,
-    manifests_path: PathBuf,
-    blobs_path: PathBuf,
-    scratch_path: PathBuf,
+    pub(crate) active_uploads: Arc<RwLock<HashSet<Upload>>>,
+    pub(crate) manifests_path: PathBuf,
+    pub(crate) blobs_path: PathBuf,
+    pub(crate) scratch_path: PathBuf,
+    pub(crate) links_path: PathBuf,
+    pub(crate) reference_index: Arc<RwLock<ReferenceIndex>>,
     pub proxy_registry_config: Option<RegistryProxiesConfig>,
+    pub(crate) proxy_clients: Arc<ProxyClientCache>,
+    proxied_tag_checks: Arc<RwLock<HashMap<(String, String), Instant>>>,
+    immutable_tag_locks: Arc<Mutex<HashMap<(String, String), TagLock>>>,
+    pub(crate) gc_lock: Arc<tokio::sync::RwLock<()>>,
     pub image_validation_config: Option<ImageValidationConfig>,
+    pub(crate) media_type_config: MediaTypeConfig,
+    pub(crate) immutable_tags: ImmutableTagsConfig,
+    pub(crate) quota_config: QuotaConfig,
+    pub(crate) quota_usage: Arc<Mutex<HashMap<String, NamespaceUsage>>>,
+    pub(crate) min_free_space: u64,
+    pub(crate) retention_config: Option<RetentionConfig>,
+    #[cfg(feature = "sqlite")]
+    pub(crate) metadata: Arc<MetadataStore>,
 }
 
-#[derive(Eq, PartialEq, Hash, Debug, Clone)]
-struct Upload {
-    repo_name: String,
-    uuid: String,
-}
-
-#[derive(Error, Debug)]
-#[error("Error getting proxied repo {msg:?}")]
-pub struct ProxyError {
-    msg: String,
+/// Restrictions on what can be pushed and how long it is kept. They are given when the server is
+/// created and don't change afterwards.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ServerPolicies {
+    pub media_type_config: MediaTypeConfig,
+    pub immutable_tags: ImmutableTagsConfig,
+    pub quota_config: QuotaConfig,
+    /// Bytes of disk space to keep free, 0 disables the check
+    pub min_free_space: u64,
+    pub retention_config: Option<RetentionConfig>,
 }
 
 #[derive(Error, Debug)]
@@ -81,6 +135,15 @@ pub struct DigestValidationError {
     actual_digest: String,
 }
 
+/// Reasons a pushed manifest is rejected that the client should be told about
+#[derive(Error, Debug)]
+pub enum ManifestVerificationError {
+    #[error("{0}")]
+    BlobUnknown(String),
+    #[error("{0}")]
+    Invalid(String),
+}
+
 pub fn create_accept_header() -> HeaderMap {
     const ACCEPT: [&str; 4] = [
         manifest_media_type::OCI_V1,
@@ -131,12 +194,12 @@ fn does_manifest_match_digest(manifest: &DirEntry, digest: &str) -> bool {
         }
 }
 
-struct RepoIterator {
+pub(crate) struct RepoIterator {
     paths: Vec<Result<DirEntry, std::io::Error>>,
 }
 
 impl RepoIterator {
-    fn new(base_dir: &Path) -> Result<RepoIterator> {
+    pub(crate) fn new(base_dir: &Path) -> Result<RepoIterator> {
         let paths = fs::read_dir(base_dir)?.collect();
         Ok(RepoIterator { paths })
     }
@@ -167,16 +230,16 @@ impl Iterator for RepoIterator {
 }
 
 /**
- * Checks a file matches the given digest.
+ * Checks a file matches the given digest, using the algorithm of the digest.
  *
- * TODO: should be able to use range of hashes.
  * TODO: check if using a static for the hasher speeds things up.
  */
 fn validate_digest(file: &PathBuf, digest: &str) -> Result<()> {
+    let (alg, _) = split_digest(digest)?;
     let f = File::open(file)?;
     let reader = BufReader::new(f);
 
-    let calculated_digest = sha256_tag_digest(reader)?;
+    let calculated_digest = tag_digest(alg, reader)?;
 
     if calculated_digest != digest {
         event!(
@@ -194,6 +257,24 @@ fn validate_digest(file: &PathBuf, digest: &str) -> Result<()> {
     Ok(())
 }
 
+/// Whether `tag` is a valid tag name, as defined by the OCI distribution spec
+fn is_valid_tag(tag: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$").unwrap();
+    }
+    RE.is_match(tag)
+}
+
+/// Whether `name` is a valid repository name, as defined by the OCI distribution spec
+fn is_valid_repo_name(name: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r"^[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*$")
+                .unwrap();
+    }
+    RE.is_match(name)
+}
+
 pub fn is_digest(maybe_digest: &str) -> bool {
     for alg in &SUPPORTED_DIGESTS {
         if maybe_digest.starts_with(&format!("{}:", alg)) {
@@ -204,6 +285,22 @@ pub fn is_digest(maybe_digest: &str) -> bool {
     false
 }
 
+/// Splits a digest into its algorithm and value components
+pub(crate) fn split_digest(digest: &str) -> Result<(&str, &str)> {
+    let mut iter = digest.split(':');
+    let alg = iter
+        .next()
+        .ok_or_else(|| anyhow!("Digest {} did not contain alg component", digest))?;
+    if !SUPPORTED_DIGESTS.contains(&alg) {
+        return Err(anyhow!("Hash algorithm {} not supported", alg));
+    }
+    let val = iter
+        .next()
+        .ok_or_else(|| anyhow!("Digest {} did not contain value component", digest))?;
+    assert_eq!(None, iter.next());
+    Ok((alg, val))
+}
+
 fn is_path_writable(path: &PathBuf) -> io::Result<bool> {
     let file = File::open(path)?;
     let metadata = file.metadata()?;
@@ -211,7 +308,7 @@ fn is_path_writable(path: &PathBuf) -> io::Result<bool> {
     Ok(!permissions.readonly())
 }
 
-fn get_digest_from_manifest_path<P: AsRef<Path>>(path: P) -> Result<String> {
+pub(crate) fn get_digest_from_manifest_path<P: AsRef<Path>>(path: P) -> Result<String> {
     let manifest = fs::read_to_string(path)?;
     let latest_digest_line = manifest
         .lines()
@@ -227,49 +324,254 @@ fn get_digest_from_manifest_path<P: AsRef<Path>>(path: P) -> Result<String> {
 }
 
 impl TrowServer {
-    pub fn new(
+    pub(crate) fn new(
         data_path: &str,
         proxy_registry_config: Option<RegistryProxiesConfig>,
         image_validation_config: Option<ImageValidationConfig>,
+        policies: ServerPolicies,
     ) -> Result<Self> {
         let manifests_path = create_path(data_path, MANIFESTS_DIR)?;
         let scratch_path = create_path(data_path, UPLOADS_DIR)?;
         let blobs_path = create_path(data_path, BLOBS_DIR)?;
+        // Data directories created before blob links existed need them generated
+        let migrate_links = !Path::new(data_path).join(LINKS_DIR).exists();
+        let links_path = create_path(data_path, LINKS_DIR)?;
+        #[cfg(feature = "sqlite")]
+        let (metadata, import_metadata) =
+            MetadataStore::open(&Path::new(data_path).join(METADATA_DB))?;
 
         let svc = TrowServer {
             active_uploads: Arc::new(RwLock::new(HashSet::new())),
             manifests_path,
             blobs_path,
             scratch_path,
+            links_path,
+            reference_index: Arc::new(RwLock::new(ReferenceIndex::default())),
             proxy_registry_config,
+            proxy_clients: Arc::default(),
+            proxied_tag_checks: Arc::default(),
+            immutable_tag_locks: Arc::default(),
+            gc_lock: Arc::default(),
             image_validation_config,
+            media_type_config: policies.media_type_config,
+            immutable_tags: policies.immutable_tags,
+            quota_config: policies.quota_config,
+            quota_usage: Arc::default(),
+            min_free_space: policies.min_free_space,
+            retention_config: policies.retention_config,
+            #[cfg(feature = "sqlite")]
+            metadata: Arc::new(metadata),
         };
+        #[cfg(feature = "sqlite")]
+        if import_metadata {
+            svc.import_metadata()?;
+            svc.metadata.mark_imported()?;
+        }
+        svc.build_reference_index()?;
+        svc.restore_uploads()?;
+        if migrate_links {
+            svc.link_indexed_manifests()?;
+        }
         Ok(svc)
     }
 
-    fn get_upload_path_for_blob(&self, uuid: &str) -> PathBuf {
-        self.scratch_path.join(uuid)
+    pub(crate) fn get_catalog_path_for_blob(&self, digest: &str) -> Result<PathBuf> {
+        let (alg, val) = split_digest(digest)?;
+        Ok(self.blobs_path.join(alg).join(val))
+    }
+
+    fn get_link_path_for_blob(&self, repo_name: &str, digest: &str) -> Result<PathBuf> {
+        let (alg, val) = split_digest(digest)?;
+        Ok(self
+            .links_path
+            .join(repo_name)
+            .join(BLOB_LINKS_DIR)
+            .join(alg)
+            .join(val))
     }
 
-    fn get_catalog_path_for_blob(&self, digest: &str) -> Result<PathBuf> {
-        let mut iter = digest.split(':');
-        let alg = iter
-            .next()
-            .ok_or_else(|| anyhow!("Digest {} did not contain alg component", digest))?;
-        if !SUPPORTED_DIGESTS.contains(&alg) {
-            return Err(anyhow!("Hash algorithm {} not supported", alg));
+    /// Records that `digest` can be read from `repo_name`
+    pub(crate) fn link_blob(&self, repo_name: &str, digest: &str) -> Result<()> {
+        let path = self.get_link_path_for_blob(repo_name, digest)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
         }
-        let val = iter
-            .next()
-            .ok_or_else(|| anyhow!("Digest {} did not contain value component", digest))?;
-        assert_eq!(None, iter.next());
-        Ok(self.blobs_path.join(alg).join(val))
+        File::create(path)?;
+        Ok(())
+    }
+
+    pub(crate) fn is_blob_linked(&self, repo_name: &str, digest: &str) -> bool {
+        self.get_link_path_for_blob(repo_name, digest)
+            .map(|p| p.exists())
+            .unwrap_or(false)
+    }
+
+    /// Looks for a link to `digest` in every repository. Only repository directories are
+    /// walked; the blob links inside them are checked by path rather than listed.
+    fn is_blob_linked_to_any_repo(&self, digest: &str) -> Result<bool> {
+        let (alg, val) = split_digest(digest)?;
+        let link = Path::new(BLOB_LINKS_DIR).join(alg).join(val);
+        let mut dirs = vec![self.links_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            if dir.join(&link).exists() {
+                return Ok(true);
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() && entry.file_name() != BLOB_LINKS_DIR {
+                    dirs.push(entry.path());
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Links a manifest and all the blobs it references to `repo_name`
+    fn link_manifest(&self, repo_name: &str, digest: &str) -> Result<()> {
+        self.link_blob(repo_name, digest)?;
+        for asset in self.read_manifest(digest)?.get_local_asset_digests() {
+            self.link_blob(repo_name, asset)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_manifest(&self, digest: &str) -> Result<Manifest> {
+        let bytes = fs::read(self.get_catalog_path_for_blob(digest)?)?;
+        let json: serde_json::Value = serde_json::from_slice(&bytes)?;
+        Manifest::from_json(&json)
+    }
+
+    /// Returns the blobs referenced by manifest `digest` in `repo_name`, and its subject if any.
+    fn get_manifest_references(&self, repo_name: &str, digest: &str) -> Result<IndexEntry> {
+        let manifest = self.read_manifest(digest)?;
+        let blobs = manifest
+            .get_local_asset_digests()
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        let subject = manifest.get_subject().map(|s| s.to_string());
+        let manifest = ManifestReference {
+            repo_name: repo_name.to_string(),
+            digest: digest.to_string(),
+        };
+        Ok((manifest, blobs, subject))
+    }
+
+    /// Reads the blobs referenced by every manifest in the history of the tag file at `path`.
+    fn get_references_for_tag(&self, repo_name: &str, path: &Path) -> Result<Vec<IndexEntry>> {
+        let history = fs::read_to_string(path)?;
+        let mut references = vec![];
+        // Each line is `{digest} {date}`
+        for digest in history.lines().filter_map(|l| l.split(' ').next()) {
+            match self.get_manifest_references(repo_name, digest) {
+                Ok(r) => references.push(r),
+                Err(e) => event!(Level::DEBUG, "Not indexing manifest {}: {:?}", digest, e),
+            }
+        }
+        Ok(references)
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn build_reference_index(&self) -> Result<()> {
+        let mut index = self.reference_index.write().unwrap();
+        for (manifest, blobs, subject) in self.metadata.indexed_manifests()? {
+            index.insert(manifest, blobs, subject);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn build_reference_index(&self) -> Result<()> {
+        let mut index = self.reference_index.write().unwrap();
+        for tag_file in RepoIterator::new(&self.manifests_path)? {
+            let path = tag_file.path();
+            let repo_name = match path
+                .parent()
+                .and_then(|p| p.strip_prefix(&self.manifests_path).ok())
+            {
+                Some(r) => r.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let references = match self.get_references_for_tag(&repo_name, &path) {
+                Ok(r) => r,
+                Err(e) => {
+                    event!(Level::WARN, "Not indexing tag file {:?}: {:?}", path, e);
+                    continue;
+                }
+            };
+            for (manifest, blobs, subject) in references {
+                index.insert(manifest, blobs, subject);
+            }
+        }
+        Ok(())
     }
 
+    fn link_indexed_manifests(&self) -> Result<()> {
+        event!(Level::INFO, "Creating blob links for existing manifests");
+        let index = self.reference_index.read().unwrap();
+        for (manifest, blobs) in index.manifests() {
+            self.link_blob(&manifest.repo_name, &manifest.digest)?;
+            for blob in blobs {
+                self.link_blob(&manifest.repo_name, blob)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the index entries for `repo_name` from its tag files
+    pub(crate) fn reindex_repo(&self, repo_name: &str) -> Result<()> {
+        #[cfg(feature = "sqlite")]
+        self.import_repo_metadata(repo_name)?;
+
+        let mut references = vec![];
+        let repo_dir = self.manifests_path.join(repo_name);
+        if repo_dir.exists() {
+            for entry in fs::read_dir(&repo_dir)? {
+                let entry = entry?;
+                // Subdirectories are other repositories
+                if entry.file_type()?.is_file() {
+                    references.extend(self.get_references_for_tag(repo_name, &entry.path())?);
+                }
+            }
+        }
+
+        {
+            let mut index = self.reference_index.write().unwrap();
+            index.remove_repo(repo_name);
+            for (manifest, blobs, subject) in references {
+                index.insert(manifest, blobs, subject);
+            }
+        }
+        self.invalidate_quota_usage(repo_name);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn get_digest_from_manifest(&self, repo_name: &str, reference: &str) -> Result<String> {
+        self.metadata
+            .current_digest(repo_name, reference)?
+            .ok_or_else(|| anyhow!("Unknown tag {} in {}", reference, repo_name))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
     fn get_digest_from_manifest(&self, repo_name: &str, reference: &str) -> Result<String> {
         get_digest_from_manifest_path(self.manifests_path.join(repo_name).join(reference))
     }
 
+    /// Size of a stored blob
+    pub(crate) fn get_blob_size(&self, digest: &str) -> Result<u64> {
+        #[cfg(feature = "sqlite")]
+        if let Some(size) = self.metadata.blob_size(digest)? {
+            return Ok(size);
+        }
+        Ok(fs::metadata(self.get_catalog_path_for_blob(digest)?)?.len())
+    }
+
     async fn save_tag(&self, digest: &str, repo_name: &str, tag: &str) -> Result<()> {
         // Tag files should contain list of digests with timestamp
         // Last line should always be the current digest
@@ -287,23 +589,93 @@ impl TrowServer {
             .await?;
         file.write_all(&contents).await?;
 
+        #[cfg(feature = "sqlite")]
+        {
+            self.metadata.add_tag(repo_name, tag, digest, &ts)?;
+            self.import_manifest_metadata(digest);
+        }
+
+        let (manifest, blobs, subject) = self.get_manifest_references(repo_name, digest)?;
+        self.reference_index
+            .write()
+            .unwrap()
+            .insert(manifest, blobs.clone(), subject);
+        self.add_quota_usage(repo_name, digest, &blobs);
+
         Ok(())
     }
 
-    fn get_path_for_manifest(&self, repo_name: &str, reference: &str) -> Result<PathBuf> {
-        let digest = if is_digest(reference) {
-            reference.to_string()
-        } else {
-            self.get_digest_from_manifest(repo_name, reference)?
-        };
+    /// Whether `reference` is a tag that can't be moved or deleted
+    fn is_immutable_tag(&self, repo_name: &str, reference: &str) -> bool {
+        !is_digest(reference) && self.immutable_tags.is_immutable(repo_name, reference)
+    }
+
+    /// Waits until no other push of the immutable tag `reference` is in progress.
+    /// Returns `None` for mutable tags and digests, which don't need to be checked before saving.
+    async fn lock_immutable_tag(
+        &self,
+        repo_name: &str,
+        reference: &str,
+    ) -> Option<tokio::sync::OwnedMutexGuard<()>> {
+        if !self.is_immutable_tag(repo_name, reference) {
+            return None;
+        }
+        let lock = self
+            .immutable_tag_locks
+            .lock()
+            .unwrap()
+            .entry((repo_name.to_string(), reference.to_string()))
+            .or_default()
+            .clone();
+        Some(lock.lock_owned().await)
+    }
+
+    /// Whether pushing `digest` as `reference` would move an immutable tag
+    fn is_tag_move_denied(&self, repo_name: &str, reference: &str, digest: &str) -> Result<bool> {
+        if !self.is_immutable_tag(repo_name, reference) {
+            return Ok(false);
+        }
+        // Without a current digest, this is the first push of the tag
+        Ok(self
+            .get_current_digest(repo_name, reference)?
+            .is_some_and(|current| current != digest))
+    }
+
+    /// The digest `tag` points to, or `None` if it doesn't exist
+    #[cfg(feature = "sqlite")]
+    fn get_current_digest(&self, repo_name: &str, tag: &str) -> Result<Option<String>> {
+        self.metadata.current_digest(repo_name, tag)
+    }
+
+    /// The digest `tag` points to, or `None` if it doesn't exist
+    #[cfg(not(feature = "sqlite"))]
+    fn get_current_digest(&self, repo_name: &str, tag: &str) -> Result<Option<String>> {
+        match get_digest_from_manifest_path(self.manifests_path.join(repo_name).join(tag)) {
+            Ok(digest) => Ok(Some(digest)),
+            Err(e)
+                if e.downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        self.get_catalog_path_for_blob(&digest)
+    fn get_digest_for_manifest(&self, repo_name: &str, reference: &str) -> Result<String> {
+        if is_digest(reference) {
+            Ok(reference.to_string())
+        } else {
+            self.get_digest_from_manifest(repo_name, reference)
+        }
     }
 
+    /// Checks the manifest and computes its digest with the given algorithm
     fn create_verified_manifest(
         &self,
         manifest_path: &PathBuf,
         verify_assets_exist: bool,
+        algorithm: &str,
     ) -> Result<VerifiedManifest> {
         let manifest_bytes = std::fs::read(manifest_path)?;
         let manifest_json: serde_json::Value = serde_json::from_slice(&manifest_bytes)?;
@@ -319,30 +691,131 @@ impl TrowServer {
             }
         }
 
-        // Calculate the digest: sha256:...
+        // Calculate the digest: <algorithm>:...
         let reader = BufReader::new(manifest_bytes.as_slice());
-        let digest = sha256_tag_digest(reader)?;
+        let digest = tag_digest(algorithm, reader)?;
 
         // For performance, could generate only if verification is on, otherwise copy from somewhere
         Ok(VerifiedManifest {
             digest,
             content_type: manifest.get_media_type(),
+            subject: manifest.get_subject().unwrap_or_default().to_string(),
         })
     }
 
+    /// Checks a manifest being pushed: its media types must be allowed and everything it
+    /// references must exist in `repo_name`. The children of image indexes are verified
+    /// recursively.
+    fn validate_pushed_manifest(&self, repo_name: &str, manifest_path: &Path) -> Result<()> {
+        let manifest_json: serde_json::Value = serde_json::from_slice(&fs::read(manifest_path)?)?;
+        let manifest = Manifest::from_json(&manifest_json)?;
+        self.media_type_config.check(&manifest)?;
+        self.verify_manifest_assets(repo_name, &manifest)
+    }
+
+    fn verify_manifest_assets(&self, repo_name: &str, manifest: &Manifest) -> Result<()> {
+        if let Manifest::List(list) = manifest {
+            for entry in &list.manifests {
+                self.verify_child_manifest(repo_name, entry)?;
+            }
+            return Ok(());
+        }
+        for descriptor in manifest.get_local_asset_descriptors() {
+            self.verify_descriptor(repo_name, descriptor)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a config or layer descriptor matches the blob stored for it in `repo_name`: the blob
+    /// has to exist with the declared size. Only the syntax of the media type can be checked.
+    fn verify_descriptor(&self, repo_name: &str, descriptor: &Object) -> Result<()> {
+        if !is_valid_media_type(&descriptor.media_type) {
+            return Err(ManifestVerificationError::Invalid(format!(
+                "Blob {} has invalid media type {:?}",
+                descriptor.digest, descriptor.media_type
+            ))
+            .into());
+        }
+        if !self.is_blob_linked(repo_name, &descriptor.digest) {
+            return Err(ManifestVerificationError::BlobUnknown(descriptor.digest.clone()).into());
+        }
+        let path = self.get_catalog_path_for_blob(&descriptor.digest)?;
+        let stored_size = match fs::metadata(path) {
+            Ok(md) => md.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(
+                    ManifestVerificationError::BlobUnknown(descriptor.digest.clone()).into(),
+                )
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match descriptor.size {
+            Some(size) if size != stored_size => Err(ManifestVerificationError::Invalid(format!(
+                "Blob {} is {} bytes but the manifest declares {}",
+                descriptor.digest, stored_size, size
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks an entry of an image index matches the manifest it points to in `repo_name`
+    fn verify_child_manifest(&self, repo_name: &str, entry: &ManifestListEntry) -> Result<()> {
+        let invalid = |reason: String| -> anyhow::Error {
+            ManifestVerificationError::Invalid(format!(
+                "Child manifest {} {}",
+                entry.digest, reason
+            ))
+            .into()
+        };
+        let path = self
+            .get_catalog_path_for_blob(&entry.digest)
+            .map_err(|e| invalid(format!("has an invalid digest: {}", e)))?;
+        if !self.is_blob_linked(repo_name, &entry.digest) {
+            return Err(ManifestVerificationError::BlobUnknown(entry.digest.clone()).into());
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ManifestVerificationError::BlobUnknown(entry.digest.clone()).into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if bytes.len() as u64 != entry.size as u64 {
+            return Err(invalid(format!(
+                "is {} bytes but the index declares {}",
+                bytes.len(),
+                entry.size
+            )));
+        }
+        let child = serde_json::from_slice(&bytes)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Manifest::from_json(&json))
+            .map_err(|e| invalid(format!("is not a valid manifest: {}", e)))?;
+        if child.get_media_type() != entry.media_type {
+            return Err(invalid(format!(
+                "has media type {} but the index declares {}",
+                child.get_media_type(),
+                entry.media_type
+            )));
+        }
+        self.verify_manifest_assets(repo_name, &child)
+    }
+
     /**
     If repo is proxied to another registry, this will return the details of the remote image.
     If the repo isn't proxied None is returned
     **/
-    fn get_remote_image_and_cfg(
+    pub(crate) fn get_remote_image_and_cfg(
         &self,
         repo_name: &str,
         reference: &str,
     ) -> Option<(RemoteImage, SingleRegistryProxyConfig)> {
         // All proxies are under "f_"
-        if repo_name.starts_with(PROXY_DIR) && self.proxy_registry_config.is_some() {
-            let proxy_config = self.proxy_registry_config.as_ref().unwrap();
-
+        if !repo_name.starts_with(PROXY_DIR) {
+            return None;
+        }
+        if let Some(proxy_config) = self.proxy_registry_config.as_ref() {
             let segments = repo_name.splitn(3, '/').collect::<Vec<_>>();
             debug_assert_eq!(segments[0], "f");
             let proxy_alias = segments[1].to_string();
@@ -358,49 +831,14 @@ impl TrowServer {
         None
     }
 
-    /// Download a blob that is part of `remote_image`.
-    async fn download_blob(
-        &self,
-        cl: &ProxyClient,
-        remote_image: &RemoteImage,
-        digest: &str,
-    ) -> Result<()> {
-        if self.get_catalog_path_for_blob(digest)?.exists() {
-            event!(Level::DEBUG, "Already have blob {}", digest);
-            return Ok(());
-        }
-        let path = self.scratch_path.join(digest);
-        let mut file = match TemporaryFile::open_for_writing(path.clone()).await? {
-            Some(f) => f,
-            None => {
-                event!(
-                    Level::DEBUG,
-                    "Waiting for concurrently fetched blob {}",
-                    digest
-                );
-                while path.exists() {
-                    // wait for download to be done (temp file to be moved)
-                    // TODO: use notify crate instead
-                    tokio::time::sleep(Duration::from_millis(200)).await;
-                }
-                return Ok(());
-            }
-        };
-
-        let addr = format!("{}/blobs/{}", remote_image.get_base_uri(), digest);
-        event!(Level::INFO, "Downloading blob {}", addr);
-        let resp = cl.authenticated_request(Method::GET, &addr).send().await?;
-
-        file.write_stream(resp.bytes_stream()).await?;
-        self.save_blob(file.path(), digest)?;
-        Ok(())
-    }
-
+    /// Downloads the manifest of `remote_image`, which should have digest `digest`, and the
+    /// manifests it references
     #[async_recursion]
     async fn download_manifest_and_layers(
         &self,
         cl: &ProxyClient,
         remote_image: &RemoteImage,
+        digest: &str,
         local_repo_name: &str,
     ) -> Result<()> {
         event!(
@@ -408,27 +846,11 @@ impl TrowServer {
             "Downloading manifest + layers for {}",
             remote_image
         );
-        let resp = cl
-            .authenticated_request(Method::GET, &remote_image.get_manifest_url())
-            .headers(create_accept_header())
-            .send()
+        self.check_free_space()?;
+        let (buf, bytes) = self
+            .fetch_proxied_manifest(cl, remote_image, digest)
             .await?;
 
-        if !resp.status().is_success() {
-            return Err(anyhow!(
-                "GET {} returned unexpected {}",
-                &remote_image.get_manifest_url(),
-                resp.status()
-            ));
-        }
-
-        let mut buf =
-            TemporaryFile::open_for_writing(self.scratch_path.join(Uuid::new_v4().to_string()))
-                .await?
-                .unwrap();
-        let bytes = resp.bytes().await?;
-        buf.write_all(&bytes).await?;
-
         let mani: Manifest = serde_json::from_slice(&bytes)?;
         match mani {
             Manifest::List(_) => {
@@ -441,28 +863,19 @@ impl TrowServer {
                         image
                     })
                     .collect::<Vec<_>>();
-                let futures = images_to_dl
-                    .iter()
-                    .map(|img| self.download_manifest_and_layers(cl, img, local_repo_name));
-                try_join_all(futures).await?;
-            }
-            Manifest::V2(_) => {
-                let futures = mani
-                    .get_local_asset_digests()
-                    .into_iter()
-                    .map(|digest| self.download_blob(cl, remote_image, digest));
+                let futures = images_to_dl.iter().map(|img| {
+                    self.download_manifest_and_layers(cl, img, &img.reference, local_repo_name)
+                });
                 try_join_all(futures).await?;
             }
+            // Blobs are fetched when they are first requested
+            Manifest::V2(_) => {}
         }
 
-        //Save out manifest
-        let f = File::open(buf.path())?;
-        let reader = BufReader::new(f);
-        let calculated_digest = sha256_tag_digest(reader)?;
-
-        self.save_blob(buf.path(), &calculated_digest)?;
-        self.save_tag(&calculated_digest, local_repo_name, &remote_image.reference)
+        self.save_blob(buf.path(), digest)?;
+        self.save_tag(digest, local_repo_name, &remote_image.reference)
             .await?;
+        self.link_manifest(local_repo_name, digest)?;
 
         Ok(())
     }
@@ -472,10 +885,24 @@ impl TrowServer {
         cl: &ProxyClient,
         image: &RemoteImage,
     ) -> Option<String> {
+        self.head_remote_manifest(cl, image)
+            .await
+            .map(|location| location.digest)
+    }
+
+    /// Asks the proxied registry for the digest, media type and size of a manifest, without
+    /// downloading it. The returned location has no path.
+    async fn head_remote_manifest(
+        &self,
+        cl: &ProxyClient,
+        image: &RemoteImage,
+    ) -> Option<ManifestReadLocation> {
         let resp = cl
-            .authenticated_request(Method::HEAD, &image.get_manifest_url())
-            .headers(create_accept_header())
-            .send()
+            .send(
+                "head",
+                cl.authenticated_request(Method::HEAD, &image.get_manifest_url())
+                    .headers(create_accept_header()),
+            )
             .await;
 
         match resp {
@@ -487,35 +914,97 @@ impl TrowServer {
                 );
                 None
             }
-            Ok(resp) => resp.headers().get(DIGEST_HEADER).map(|digest| {
-                let digest = format!("{:?}", digest);
-                digest.trim_matches('"').to_string()
-            }),
+            Ok(resp) => {
+                let headers = resp.headers();
+                let digest = headers.get(DIGEST_HEADER).map(|digest| {
+                    let digest = format!("{:?}", digest);
+                    digest.trim_matches('"').to_string()
+                })?;
+                let content_type = headers
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|ct| ct.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let size = headers
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|len| len.to_str().ok()?.parse().ok())
+                    .unwrap_or(0);
+                Some(ManifestReadLocation {
+                    digest,
+                    path: String::new(),
+                    content_type,
+                    size,
+                })
+            }
+        }
+    }
+
+    /// Whether `tag` of the proxied `repo_name` was checked against upstream less than `ttl`
+    /// seconds ago
+    fn is_proxied_tag_fresh(&self, repo_name: &str, tag: &str, ttl: u64) -> bool {
+        if ttl == 0 {
+            return false;
         }
+        let checks = self.proxied_tag_checks.read().unwrap();
+        checks
+            .get(&(repo_name.to_string(), tag.to_string()))
+            .is_some_and(|checked| checked.elapsed() < Duration::from_secs(ttl))
+    }
+
+    /// Records that the cached `tag` of the proxied `repo_name` matches upstream
+    fn record_proxied_tag_check(&self, repo_name: &str, tag: &str) {
+        self.proxied_tag_checks
+            .write()
+            .unwrap()
+            .insert((repo_name.to_string(), tag.to_string()), Instant::now());
     }
 
     /// returns the downloaded digest
-    async fn download_remote_image(
+    pub(crate) async fn download_remote_image(
         &self,
         remote_image: RemoteImage,
         proxy_cfg: SingleRegistryProxyConfig,
     ) -> Result<String> {
         // Replace eg f/docker/alpine by f/docker/library/alpine
         let repo_name = format!("f/{}/{}", proxy_cfg.alias, remote_image.get_repo());
+        let ref_is_digest = is_digest(&remote_image.reference);
 
-        let try_cl = match ProxyClient::try_new(proxy_cfg.clone(), &remote_image).await {
-            Ok(cl) => Some(cl),
-            Err(e) => {
-                event!(
-                    Level::ERROR,
-                    "Could not create client for proxied registry {}: {}",
-                    proxy_cfg.host,
-                    e
-                );
-                None
-            }
+        // The manifest of a digest never changes, and tags are trusted for `tag_ttl`
+        let cached_digest = if ref_is_digest {
+            Some(remote_image.reference.clone())
+        } else if self.is_proxied_tag_fresh(&repo_name, &remote_image.reference, proxy_cfg.tag_ttl)
+        {
+            self.get_digest_from_manifest(&repo_name, &remote_image.reference)
+                .ok()
+        } else {
+            None
         };
-        let ref_is_digest = is_digest(&remote_image.reference);
+        if let Some(digest) = cached_digest {
+            if self.get_catalog_path_for_blob(&digest)?.exists() {
+                metrics::PROXY_CACHE_HITS
+                    .with_label_values(&[&proxy_cfg.alias, "manifest"])
+                    .inc();
+                self.link_manifest(&repo_name, &digest)?;
+                return Ok(digest);
+            }
+        }
+
+        let try_cl =
+            match ProxyClient::try_new(proxy_cfg.clone(), &remote_image, &self.proxy_clients).await
+            {
+                Ok(cl) => Some(cl),
+                Err(e) => {
+                    event!(
+                        Level::ERROR,
+                        "Could not create client for proxied registry {}: {}",
+                        proxy_cfg.host,
+                        e
+                    );
+                    None
+                }
+            };
+        // The digest the upstream registry has for the tag, if it could be checked
+        let mut checked_digest = None;
 
         let (local_digest, latest_digest) = if ref_is_digest {
             (Some(remote_image.reference.clone()), None)
@@ -527,6 +1016,7 @@ impl TrowServer {
                 Some(cl) => self.get_digest_from_header(cl, &remote_image).await,
                 _ => None,
             };
+            checked_digest = latest_digest.clone();
             if latest_digest == local_digest {
                 if local_digest.is_none() {
                     anyhow::bail!(
@@ -542,17 +1032,32 @@ impl TrowServer {
         };
 
         let digests = [latest_digest, local_digest].into_iter().flatten();
+        let mut low_free_space = None;
 
         for digest in digests {
             // if let Some(latest_digest) = latest_digest {
             let have_manifest = self.get_catalog_path_for_blob(&digest)?.exists();
             match have_manifest {
-                true => return Ok(digest),
+                true => {
+                    metrics::PROXY_CACHE_HITS
+                        .with_label_values(&[&proxy_cfg.alias, "manifest"])
+                        .inc();
+                    // The manifest may have been fetched through another repository
+                    self.link_manifest(&repo_name, &digest)?;
+                    if checked_digest.as_ref() == Some(&digest) {
+                        self.record_proxied_tag_check(&repo_name, &remote_image.reference);
+                    }
+                    return Ok(digest);
+                }
                 false if try_cl.is_some() => {
+                    metrics::PROXY_CACHE_MISSES
+                        .with_label_values(&[&proxy_cfg.alias, "manifest"])
+                        .inc();
                     match self
                         .download_manifest_and_layers(
                             try_cl.as_ref().unwrap(),
                             &remote_image,
+                            &digest,
                             &repo_name,
                         )
                         .await
@@ -561,7 +1066,15 @@ impl TrowServer {
                             .save_tag(&digest, &repo_name, &remote_image.reference)
                             .await
                         {
-                            Ok(_) => return Ok(digest),
+                            Ok(_) => {
+                                if checked_digest.as_ref() == Some(&digest) {
+                                    self.record_proxied_tag_check(
+                                        &repo_name,
+                                        &remote_image.reference,
+                                    );
+                                }
+                                return Ok(digest);
+                            }
                             Err(e) => {
                                 event!(
                                     Level::DEBUG,
@@ -571,6 +1084,8 @@ impl TrowServer {
                             }
                         },
                         Ok(_) => return Ok(digest),
+                        // A cached digest may still be served, otherwise this is the error to report
+                        Err(e) if e.is::<LowFreeSpaceError>() => low_free_space = Some(e),
                         Err(e) => event!(Level::WARN, "Failed to download proxied image: {}", e),
                     };
                 }
@@ -581,6 +1096,9 @@ impl TrowServer {
             }
         }
 
+        if let Some(e) = low_free_space {
+            return Err(e);
+        }
         Err(anyhow!(
             "Could not fetch manifest for proxied image {}:{}",
             repo_name,
@@ -594,9 +1112,9 @@ impl TrowServer {
         reference: String,
         do_verification: bool,
     ) -> Result<ManifestReadLocation> {
-        let path = if let Some((remote_image, proxy_cfg)) =
-            self.get_remote_image_and_cfg(&repo_name, &reference)
-        {
+        let proxied_image = self.get_remote_image_and_cfg(&repo_name, &reference);
+        let proxied = proxied_image.is_some();
+        let digest = if let Some((remote_image, proxy_cfg)) = proxied_image {
             event!(
                 Level::INFO,
                 "Request for proxied repo {}:{} maps to {}",
@@ -609,25 +1127,113 @@ impl TrowServer {
             drop(reference);
             if self.proxy_registry_config.as_ref().unwrap().offline {
                 let repo_name = format!("f/{}/{}", proxy_cfg.alias, remote_image.get_repo());
-                self.get_path_for_manifest(&repo_name, &remote_image.reference)?
+                let digest = self.get_digest_for_manifest(&repo_name, &remote_image.reference);
+                let counter = match digest {
+                    Ok(_) => &*metrics::PROXY_CACHE_HITS,
+                    Err(_) => &*metrics::PROXY_CACHE_MISSES,
+                };
+                counter
+                    .with_label_values(&[&proxy_cfg.alias, "manifest"])
+                    .inc();
+                digest?
             } else {
-                let digest = self.download_remote_image(remote_image, proxy_cfg).await?;
-                self.get_catalog_path_for_blob(&digest)?
+                self.download_remote_image(remote_image, proxy_cfg).await?
             }
         } else {
-            self.get_path_for_manifest(&repo_name, &reference)?
+            self.get_digest_for_manifest(&repo_name, &reference)?
         };
+        let path = self.get_catalog_path_for_blob(&digest)?;
+        let (alg, _) = split_digest(&digest)?;
 
-        let vm = self.create_verified_manifest(&path, do_verification)?;
+        // The blobs of proxied images are only fetched on demand
+        let vm = self.create_verified_manifest(&path, do_verification && !proxied, alg)?;
         Ok(ManifestReadLocation {
             content_type: vm.content_type.to_owned(),
             digest: vm.digest,
+            size: fs::metadata(&path)?.len(),
+            path: path.to_string_lossy().to_string(),
+        })
+    }
+
+    /// Location of a manifest of the catalog, without verifying it
+    fn create_local_manifest_location(&self, digest: &str) -> Result<ManifestReadLocation> {
+        let path = self.get_catalog_path_for_blob(digest)?;
+        let manifest = self.read_manifest(digest)?;
+        Ok(ManifestReadLocation {
+            content_type: manifest.get_media_type(),
+            digest: digest.to_string(),
+            size: fs::metadata(&path)?.len(),
             path: path.to_string_lossy().to_string(),
         })
     }
 
+    /// Like `create_manifest_read_location`, but proxied manifests that aren't cached are only
+    /// looked up upstream, not downloaded.
+    async fn create_manifest_head_location(
+        &self,
+        repo_name: &str,
+        reference: &str,
+    ) -> Result<ManifestReadLocation> {
+        let (remote_image, proxy_cfg) = match self.get_remote_image_and_cfg(repo_name, reference) {
+            Some(proxied_image) => proxied_image,
+            None => {
+                let digest = self.get_digest_for_manifest(repo_name, reference)?;
+                return self.create_local_manifest_location(&digest);
+            }
+        };
+        let repo_name = format!("f/{}/{}", proxy_cfg.alias, remote_image.get_repo());
+        let is_cached = |digest: &String| {
+            self.get_catalog_path_for_blob(digest)
+                .is_ok_and(|path| path.exists())
+        };
+        let local_digest = self
+            .get_digest_for_manifest(&repo_name, &remote_image.reference)
+            .ok()
+            .filter(is_cached);
+
+        let offline = self.proxy_registry_config.as_ref().unwrap().offline;
+        let trust_cache = offline
+            || is_digest(&remote_image.reference)
+            || self.is_proxied_tag_fresh(&repo_name, &remote_image.reference, proxy_cfg.tag_ttl);
+        if trust_cache {
+            if let Some(digest) = &local_digest {
+                return self.create_local_manifest_location(digest);
+            }
+        }
+
+        if !offline {
+            match ProxyClient::try_new(proxy_cfg.clone(), &remote_image, &self.proxy_clients).await
+            {
+                Ok(cl) => {
+                    if let Some(location) = self.head_remote_manifest(&cl, &remote_image).await {
+                        if is_cached(&location.digest) {
+                            return self.create_local_manifest_location(&location.digest);
+                        }
+                        return Ok(location);
+                    }
+                }
+                Err(e) => event!(
+                    Level::ERROR,
+                    "Could not create client for proxied registry {}: {}",
+                    proxy_cfg.host,
+                    e
+                ),
+            }
+        }
+
+        // Upstream is unavailable, the cached manifest is better than nothing
+        match local_digest {
+            Some(digest) => self.create_local_manifest_location(&digest),
+            None => Err(anyhow!(
+                "Could not find manifest for proxied image {}:{}",
+                repo_name,
+                remote_image.reference
+            )),
+        }
+    }
+
     /// Moves blob from scratch to blob catalog
-    fn save_blob(&self, scratch_path: &Path, digest: &str) -> Result<()> {
+    pub(crate) fn save_blob(&self, scratch_path: &Path, digest: &str) -> Result<()> {
         let digest_path = self.get_catalog_path_for_blob(digest)?;
         let repo_path = digest_path
             .parent()
@@ -637,6 +1243,9 @@ impl TrowServer {
             fs::create_dir_all(repo_path)?;
         }
         fs::rename(scratch_path, &digest_path)?;
+        #[cfg(feature = "sqlite")]
+        self.metadata
+            .add_blob(digest, fs::metadata(&digest_path)?.len())?;
         Ok(())
     }
 
@@ -670,13 +1279,15 @@ impl Registry for TrowServer {
     ) -> Result<Response<UploadDetails>, Status> {
         let repo_name = request.into_inner().repo_name;
         if self.is_writable_repo(&repo_name) {
+            self.check_free_space().map_err(free_space_status)?;
+            self.check_quotas(&repo_name, &[]).map_err(quota_status)?;
             let uuid = Uuid::new_v4().to_string();
             let reply = UploadDetails { uuid: uuid.clone() };
             let upload = Upload { repo_name, uuid };
-            {
-                self.active_uploads.write().unwrap().insert(upload);
-                event!(Level::DEBUG, "Upload Table: {:?}", self.active_uploads);
-            }
+            self.add_upload(upload).map_err(|e| {
+                event!(Level::ERROR, "Failed to start upload: {:?}", e);
+                Status::internal("Internal error starting upload")
+            })?;
             Ok(Response::new(reply))
         } else {
             Err(Status::invalid_argument(format!(
@@ -696,12 +1307,7 @@ impl Registry for TrowServer {
             uuid: br.uuid.clone(),
         };
 
-        // Apparently unwrap() is correct here. From the docs:
-        // "We unwrap() the return value to assert that we are not expecting
-        // threads to ever fail while holding the lock."
-
-        let set = self.active_uploads.read().unwrap();
-        if set.contains(&upload) {
+        if self.has_upload(&upload) {
             let path = self.get_upload_path_for_blob(&br.uuid);
             Ok(Response::new(WriteLocation {
                 path: path.to_string_lossy().to_string(),
@@ -714,51 +1320,250 @@ impl Registry for TrowServer {
         }
     }
 
-    async fn get_read_location_for_blob(
+    async fn get_upload_status(
         &self,
-        req: Request<BlobRef>,
-    ) -> Result<Response<BlobReadLocation>, Status> {
-        metrics::TOTAL_BLOB_REQUESTS.inc();
-        let br = req.into_inner();
+        req: Request<UploadRef>,
+    ) -> Result<Response<UploadStatus>, Status> {
+        let ur = req.into_inner();
+        let upload = Upload {
+            repo_name: ur.repo_name.clone(),
+            uuid: ur.uuid.clone(),
+        };
+
+        if !self.has_upload(&upload) {
+            return Err(Status::not_found(format!(
+                "No current upload matching {:?}",
+                ur
+            )));
+        }
+        let size = self.get_upload_size(&upload).map_err(|e| {
+            event!(Level::ERROR, "Failed to read upload {:?}: {:?}", upload, e);
+            Status::internal("Internal error reading upload")
+        })?;
+        Ok(Response::new(UploadStatus { size }))
+    }
+
+    async fn record_upload_progress(
+        &self,
+        req: Request<UploadProgress>,
+    ) -> Result<Response<UploadProgressRecorded>, Status> {
+        let up = req.into_inner();
+        let upload = Upload {
+            repo_name: up.repo_name.clone(),
+            uuid: up.uuid.clone(),
+        };
+
+        if !self.has_upload(&upload) {
+            return Err(Status::not_found(format!(
+                "No current upload matching {:?}",
+                up
+            )));
+        }
+        self.record_upload_size(&upload, up.size).map_err(|e| {
+            event!(
+                Level::ERROR,
+                "Failed to record upload {:?}: {:?}",
+                upload,
+                e
+            );
+            Status::internal("Internal error recording upload")
+        })?;
+        Ok(Response::new(UploadProgressRecorded {}))
+    }
+
+    async fn cancel_upload(
+        &self,
+        req: Request<UploadRef>,
+    ) -> Result<Response<UploadCancelled>, Status> {
+        let ur = req.into_inner();
+        let upload = Upload {
+            repo_name: ur.repo_name.clone(),
+            uuid: ur.uuid.clone(),
+        };
+
+        if !self.remove_upload(&upload) {
+            return Err(Status::not_found(format!(
+                "No current upload matching {:?}",
+                ur
+            )));
+        }
+        let path = self.get_upload_path_for_blob(&ur.uuid);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                event!(Level::WARN, "Failed to remove {:?}: {:?}", path, e);
+            }
+        }
+        Ok(Response::new(UploadCancelled {}))
+    }
+
+    async fn get_read_location_for_blob(
+        &self,
+        req: Request<BlobRef>,
+    ) -> Result<Response<BlobReadLocation>, Status> {
+        metrics::TOTAL_BLOB_REQUESTS.inc();
+        let br = req.into_inner();
         let path = self
             .get_catalog_path_for_blob(&br.digest)
             .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
 
-        if !path.exists() {
-            event!(Level::WARN, "Request for unknown blob: {:?}", path);
+        if !path.exists() && self.is_blob_fetchable(&br.repo_name, &br.digest) {
+            return Ok(Response::new(BlobReadLocation {
+                path: String::new(),
+                fetch_upstream: true,
+                size: self
+                    .proxied_blob_size(&br.repo_name, &br.digest)
+                    .unwrap_or(0),
+            }));
+        }
+        if !path.exists() || !self.is_blob_linked(&br.repo_name, &br.digest) {
+            event!(
+                Level::WARN,
+                "Request for unknown blob: {:?} in {}",
+                path,
+                br.repo_name
+            );
             Err(Status::not_found(format!(
                 "No blob found matching {:?}",
                 br
             )))
         } else {
+            if let Some((_, proxy_cfg)) = self.get_remote_image_and_cfg(&br.repo_name, &br.digest) {
+                metrics::PROXY_CACHE_HITS
+                    .with_label_values(&[&proxy_cfg.alias, "blob"])
+                    .inc();
+            }
             Ok(Response::new(BlobReadLocation {
+                size: fs::metadata(&path).map(|md| md.len()).unwrap_or(0),
                 path: path.to_string_lossy().to_string(),
+                fetch_upstream: false,
             }))
         }
     }
 
+    type StreamProxiedBlobStream = ReceiverStream<Result<ProxiedBlobChunk, Status>>;
+
+    async fn stream_proxied_blob(
+        &self,
+        req: Request<BlobRef>,
+    ) -> Result<Response<Self::StreamProxiedBlobStream>, Status> {
+        let br = req.into_inner();
+        if !self.is_blob_fetchable(&br.repo_name, &br.digest) {
+            return Err(Status::not_found(format!(
+                "No proxied blob found matching {:?}",
+                br
+            )));
+        }
+        match self.fetch_proxied_blob(&br.repo_name, &br.digest).await {
+            Ok(rx) => Ok(Response::new(ReceiverStream::new(rx))),
+            Err(e) if e.is::<LowFreeSpaceError>() => Err(free_space_status(e)),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to fetch proxied blob {:?}: {:?}",
+                    br,
+                    e
+                );
+                Err(Status::unavailable("Error fetching blob from upstream"))
+            }
+        }
+    }
+
     /**
-     * TODO: check if blob referenced by manifests. If so, refuse to delete.
+     * Removes the blob from the repository. Blobs that are referenced by a manifest in the
+     * repository can't be deleted.
+     *
+     * The blob itself is only removed from disk once no repository links to it and no manifest
+     * references it.
      */
     async fn delete_blob(&self, req: Request<BlobRef>) -> Result<Response<BlobDeleted>, Status> {
         let br = req.into_inner();
         let path = self
             .get_catalog_path_for_blob(&br.digest)
             .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
-        if !path.exists() {
-            event!(Level::WARN, "Request for unknown blob: {:?}", path);
-            Err(Status::not_found(format!(
+        if !path.exists() || !self.is_blob_linked(&br.repo_name, &br.digest) {
+            event!(
+                Level::WARN,
+                "Request for unknown blob: {:?} in {}",
+                path,
+                br.repo_name
+            );
+            return Err(Status::not_found(format!(
                 "No blob found matching {:?}",
                 br
-            )))
-        } else {
-            fs::remove_file(&path)
-                .map_err(|e| {
-                    event!(Level::ERROR, "Failed to delete blob {:?} {:?}", br, e);
-                    Status::internal("Internal error deleting blob")
-                })
-                .and(Ok(Response::new(BlobDeleted {})))
+            )));
+        }
+
+        let referrers = self.reference_index.read().unwrap().referrers(&br.digest);
+        let repo_referrers: Vec<String> = referrers
+            .iter()
+            .filter(|m| m.repo_name == br.repo_name)
+            .map(|m| m.to_string())
+            .collect();
+        if !repo_referrers.is_empty() {
+            return Err(Status::failed_precondition(format!(
+                "Blob {} is referenced by manifests: {}",
+                br.digest,
+                repo_referrers.join(", ")
+            )));
         }
+
+        let delete = || -> Result<()> {
+            fs::remove_file(self.get_link_path_for_blob(&br.repo_name, &br.digest)?)?;
+            if referrers.is_empty() && !self.is_blob_linked_to_any_repo(&br.digest)? {
+                fs::remove_file(&path)?;
+                #[cfg(feature = "sqlite")]
+                self.metadata.remove_blob(&br.digest)?;
+            }
+            Ok(())
+        };
+        delete()
+            .map_err(|e| {
+                event!(Level::ERROR, "Failed to delete blob {:?} {:?}", br, e);
+                Status::internal("Internal error deleting blob")
+            })
+            .and(Ok(Response::new(BlobDeleted {})))
+    }
+
+    async fn mount_blob(
+        &self,
+        req: Request<MountRequest>,
+    ) -> Result<Response<BlobMounted>, Status> {
+        let mr = req.into_inner();
+        if !self.is_writable_repo(&mr.repo_name) {
+            return Err(Status::invalid_argument(format!(
+                "Repository {} is not writable",
+                mr.repo_name
+            )));
+        }
+        if !is_valid_repo_name(&mr.from_repo) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid repository name {}",
+                mr.from_repo
+            )));
+        }
+        let path = self
+            .get_catalog_path_for_blob(&mr.digest)
+            .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
+        let _gc_guard = self.gc_lock.read().await;
+        if !path.exists() || !self.is_blob_linked(&mr.from_repo, &mr.digest) {
+            return Err(Status::not_found(format!(
+                "No blob found matching {} in {}",
+                mr.digest, mr.from_repo
+            )));
+        }
+
+        // The manifest referencing the blob is pushed next, so give it the GC grace period again
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.link_blob(&mr.repo_name, &mr.digest))
+            .map_err(|e| {
+                event!(Level::ERROR, "Failed to mount blob {:?} {:?}", mr, e);
+                Status::internal("Internal error mounting blob")
+            })?;
+        Ok(Response::new(BlobMounted {}))
     }
 
     async fn delete_manifest(
@@ -767,27 +1572,94 @@ impl Registry for TrowServer {
     ) -> Result<Response<ManifestDeleted>, Status> {
         let mr = req.into_inner();
         if !is_digest(&mr.reference) {
-            return Err(Status::invalid_argument(format!(
-                "Manifests can only be deleted by digest. Got {}",
-                mr.reference
-            )));
+            // Only remove this tag and its history, other tags may point to the same manifests
+            let tag = mr.reference;
+            if !is_valid_tag(&tag) {
+                return Err(Status::invalid_argument(format!("Invalid tag {}", tag)));
+            }
+            if self.is_immutable_tag(&mr.repo_name, &tag) {
+                event!(
+                    Level::WARN,
+                    "Refusing to delete immutable tag {}:{}",
+                    mr.repo_name,
+                    tag
+                );
+                return Err(Status::permission_denied(format!(
+                    "Tag {} is immutable in {}",
+                    tag, mr.repo_name
+                )));
+            }
+            let tag_path = self.manifests_path.join(&mr.repo_name).join(&tag);
+            if !tag_path.is_file() {
+                return Err(Status::not_found(format!(
+                    "Tag {} not found in {}",
+                    tag, mr.repo_name
+                )));
+            }
+            fs::remove_file(&tag_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| self.reindex_repo(&mr.repo_name))
+                .map_err(|e| {
+                    event!(Level::ERROR, "Failed to delete tag {:?}: {:?}", tag_path, e);
+                    Status::internal("Internal error deleting tag")
+                })?;
+            return Ok(Response::new(ManifestDeleted {}));
         }
         let digest = mr.reference;
         //For the repo, go through all tags and see if they reference the digest. Delete them.
         //Can only delete manifest if no other tags in any repo reference it
 
-        let ri = RepoIterator::new(&self.manifests_path.join(&mr.repo_name)).map_err(|e| {
+        let entries = fs::read_dir(self.manifests_path.join(&mr.repo_name)).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                return Status::not_found(format!("Repository {} not found", mr.repo_name));
+            }
             event!(Level::ERROR, "Problem reading manifest catalog {:?}", e);
             Status::failed_precondition("Repository not found")
         })?;
 
-        //TODO: error if no manifest matches?
-        ri.filter(|de| does_manifest_match_digest(de, &digest))
+        // Subdirectories are other repositories, their tags are left alone
+        let matching: Vec<DirEntry> = entries
+            .filter_map(|de| de.ok())
+            .filter(|de| de.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .filter(|de| does_manifest_match_digest(de, &digest))
+            .collect();
+        if matching.is_empty() {
+            return Err(Status::not_found(format!(
+                "No tag of {} points to {}",
+                mr.repo_name, digest
+            )));
+        }
+
+        // Deleting the manifest would delete the tags pointing to it, so refuse if one is immutable
+        for man in &matching {
+            let tag = man.file_name().to_string_lossy().to_string();
+            if self.is_immutable_tag(&mr.repo_name, &tag) {
+                event!(
+                    Level::WARN,
+                    "Refusing to delete {}, immutable tag {}:{} points to it",
+                    digest,
+                    mr.repo_name,
+                    tag
+                );
+                return Err(Status::permission_denied(format!(
+                    "Manifest {} is tagged with immutable tag {} in {}",
+                    digest, tag, mr.repo_name
+                )));
+            }
+        }
+
+        matching
+            .iter()
             .for_each(|man| match fs::remove_file(man.path()) {
                 Ok(_) => (),
                 Err(e) => event!(Level::DEBUG, "Failed to delete manifest {:?} {:?}", &man, e),
             });
 
+        self.reindex_repo(&mr.repo_name).map_err(|e| {
+            event!(Level::ERROR, "Failed to update reference index {:?}", e);
+            Status::internal("Internal error deleting manifest")
+        })?;
+
         Ok(Response::new(ManifestDeleted {}))
     }
 
@@ -826,6 +1698,7 @@ impl Registry for TrowServer {
             .await
         {
             Ok(vm) => Ok(Response::new(vm)),
+            Err(e) if e.is::<LowFreeSpaceError>() => Err(free_space_status(e)),
             Err(e) => {
                 event!(Level::WARN, "Internal error with manifest: {:?}", e);
                 Err(Status::internal("Internal error finding manifest"))
@@ -833,6 +1706,24 @@ impl Registry for TrowServer {
         }
     }
 
+    async fn head_manifest(
+        &self,
+        req: Request<ManifestRef>,
+    ) -> Result<Response<ManifestReadLocation>, Status> {
+        let mr = req.into_inner();
+        metrics::TOTAL_MANIFEST_REQUESTS.inc();
+        match self
+            .create_manifest_head_location(&mr.repo_name, &mr.reference)
+            .await
+        {
+            Ok(location) => Ok(Response::new(location)),
+            Err(e) => {
+                event!(Level::DEBUG, "Could not find manifest: {:?}", e);
+                Err(Status::not_found("Manifest not found"))
+            }
+        }
+    }
+
     /**
      * Take uploaded manifest (which should be uuid in uploads), check it, put in catalog and
      * by blob digest
@@ -845,29 +1736,97 @@ impl Registry for TrowServer {
         let mr = req.manifest.unwrap(); // Pissed off that the manifest is optional!
         let uploaded_manifest = self.get_upload_path_for_blob(&req.uuid);
 
-        match self.create_verified_manifest(&uploaded_manifest, true) {
-            Ok(vm) => {
-                // copy manifest to blobs and add tag
-                let digest = vm.digest.clone();
-                self.save_blob(&uploaded_manifest, &digest)
-                    .and(self.save_tag(&digest, &mr.repo_name, &mr.reference).await)
-                    .map(|_| Response::new(vm))
-                    .map_err(|e| {
-                        event!(
-                            Level::ERROR,
-                            "Failure cataloguing manifest {}/{} {:?}",
-                            &mr.repo_name,
-                            &mr.reference,
-                            e
-                        );
-                        Status::internal("Internal error copying manifest")
-                    })
-            }
+        // Held until the manifest is indexed, so GC can't remove the blobs it references meanwhile
+        let _gc_guard = self.gc_lock.read().await;
+        if let Err(e) = self.validate_pushed_manifest(&mr.repo_name, &uploaded_manifest) {
+            event!(
+                Level::ERROR,
+                "Rejecting manifest {}/{}: {:?}",
+                mr.repo_name,
+                mr.reference,
+                e
+            );
+            return match e.downcast_ref::<ManifestVerificationError>() {
+                Some(ManifestVerificationError::BlobUnknown(digest)) => {
+                    Err(Status::not_found(digest))
+                }
+                _ => Err(Status::invalid_argument(e.to_string())),
+            };
+        }
+
+        // Manifests pushed by digest keep the client's algorithm, tags get the default one
+        let alg = split_digest(&mr.reference)
+            .map(|(alg, _)| alg)
+            .unwrap_or(SUPPORTED_DIGESTS[0]);
+        // The assets were verified above
+        let vm = match self.create_verified_manifest(&uploaded_manifest, false, alg) {
+            Ok(vm) => vm,
             Err(e) => {
                 event!(Level::ERROR, "Error verifying manifest {:?}", e);
-                Err(Status::invalid_argument("Failed to verify manifest"))
+                return Err(Status::invalid_argument("Failed to verify manifest"));
             }
+        };
+        if is_digest(&mr.reference) && vm.digest != mr.reference {
+            event!(
+                Level::ERROR,
+                "Manifest pushed as {} has digest {}",
+                mr.reference,
+                vm.digest
+            );
+            return Err(Status::invalid_argument("Manifest does not match digest"));
         }
+
+        // Held until the tag is saved, so two pushes can't both see the tag as unset
+        let _tag_lock = self.lock_immutable_tag(&mr.repo_name, &mr.reference).await;
+        let move_denied = self
+            .is_tag_move_denied(&mr.repo_name, &mr.reference, &vm.digest)
+            .map_err(|e| {
+                event!(
+                    Level::ERROR,
+                    "Failed to read tag {}:{}: {:?}",
+                    mr.repo_name,
+                    mr.reference,
+                    e
+                );
+                Status::internal("Internal error reading tag")
+            })?;
+        if move_denied {
+            event!(
+                Level::WARN,
+                "Refusing to move immutable tag {}:{} to {}",
+                mr.repo_name,
+                mr.reference,
+                vm.digest
+            );
+            return Err(Status::permission_denied(format!(
+                "Tag {} is immutable in {}",
+                mr.reference, mr.repo_name
+            )));
+        }
+
+        self.check_manifest_quotas(&mr.repo_name, &req.uuid, &vm.digest)
+            .map_err(quota_status)?;
+        // copy manifest to blobs and add tag
+        let digest = vm.digest.clone();
+        if let Ok(md) = fs::metadata(&uploaded_manifest) {
+            metrics::BYTES_UPLOADED
+                .with_label_values(&[&metrics::repo_label(&mr.repo_name)])
+                .inc_by(md.len());
+        }
+        self.save_blob(&uploaded_manifest, &digest)
+            .and(self.save_tag(&digest, &mr.repo_name, &mr.reference).await)
+            .and_then(|_| self.link_manifest(&mr.repo_name, &digest))
+            .map(|_| Response::new(vm))
+            .map_err(|e| {
+                event!(
+                    Level::ERROR,
+                    "Failure cataloguing manifest {}/{} {:?}",
+                    &mr.repo_name,
+                    &mr.reference,
+                    e
+                );
+                Status::internal("Internal error copying manifest")
+            })
     }
 
     async fn complete_upload(
@@ -875,10 +1834,35 @@ impl Registry for TrowServer {
         req: Request<CompleteRequest>,
     ) -> Result<Response<CompletedUpload>, Status> {
         let cr = req.into_inner();
-        let ret = match self.validate_and_save_blob(&cr.user_digest, &cr.uuid) {
-            Ok(_) => Ok(Response::new(CompletedUpload {
-                digest: cr.user_digest.clone(),
-            })),
+        let upload = Upload {
+            repo_name: cr.repo_name.clone(),
+            uuid: cr.uuid.clone(),
+        };
+        let scratch_path = self.get_upload_path_for_blob(&cr.uuid);
+        let _gc_guard = self.gc_lock.read().await;
+        let ret = match fs::metadata(&scratch_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|md| {
+                self.check_quotas(&cr.repo_name, &[(&cr.user_digest, md.len())])?;
+                self.validate_and_save_blob(&cr.user_digest, &cr.uuid)?;
+                self.link_blob(&cr.repo_name, &cr.user_digest)?;
+                Ok(md.len())
+            }) {
+            Ok(size) => {
+                self.record_completed_upload(&upload, size);
+                Ok(Response::new(CompletedUpload {
+                    digest: cr.user_digest.clone(),
+                }))
+            }
+            Err(e) if e.is::<QuotaExceededError>() => {
+                // The upload can't be completed later, so drop its data and session now
+                event!(Level::WARN, "Rejecting upload {}: {}", cr.uuid, e);
+                if let Err(e) = fs::remove_file(&scratch_path) {
+                    event!(Level::WARN, "Failed to remove {:?}: {:?}", scratch_path, e);
+                }
+                self.remove_upload(&upload);
+                return Err(quota_status(e));
+            }
             Err(e) => match e.downcast::<DigestValidationError>() {
                 Ok(v_e) => Err(Status::invalid_argument(v_e.to_string())),
                 Err(e) => {
@@ -889,13 +1873,7 @@ impl Registry for TrowServer {
         };
 
         //delete uuid from uploads tracking
-        let upload = Upload {
-            repo_name: cr.repo_name.clone(),
-            uuid: cr.uuid,
-        };
-
-        let mut set = self.active_uploads.write().unwrap();
-        if !set.remove(&upload) {
+        if !self.remove_upload(&upload) {
             event!(Level::WARN, "Upload {:?} not found when deleting", upload);
         }
         ret
@@ -911,7 +1889,13 @@ impl Registry for TrowServer {
         let limit = cr.limit as usize;
 
         let (tx, rx) = mpsc::channel(4);
-        let catalog: HashSet<String> = RepoIterator::new(&self.manifests_path)
+        #[cfg(feature = "sqlite")]
+        let catalog = self.metadata.repositories().map_err(|e| {
+            event!(Level::ERROR, "Error accessing catalog {:?}", e);
+            Status::internal("Internal error streaming catalog")
+        })?;
+        #[cfg(not(feature = "sqlite"))]
+        let catalog: BTreeSet<String> = RepoIterator::new(&self.manifests_path)
             .map_err(|e| {
                 event!(Level::ERROR, "Error accessing catalog {:?}", e);
                 Status::internal("Internal error streaming catalog")
@@ -925,16 +1909,12 @@ impl Registry for TrowServer {
             })
             .map(|p| p.to_string_lossy().to_string())
             .collect();
-        let partial_catalog: Vec<String> = if cr.last_repo.is_empty() {
-            catalog.into_iter().take(limit).collect()
-        } else {
-            catalog
-                .into_iter()
-                .skip_while(|t| t != &cr.last_repo)
-                .skip(1)
-                .take(limit)
-                .collect()
-        };
+        // The catalog is sorted, so this also works if `last_repo` doesn't exist
+        let partial_catalog: Vec<String> = catalog
+            .into_iter()
+            .skip_while(|r| r <= &cr.last_repo)
+            .take(limit)
+            .collect();
 
         tokio::spawn(async move {
             for repo_name in partial_catalog {
@@ -953,31 +1933,33 @@ impl Registry for TrowServer {
         request: Request<ListTagsRequest>,
     ) -> Result<Response<Self::ListTagsStream>, Status> {
         let (tx, rx) = mpsc::channel(4);
-        let mut path = PathBuf::from(&self.manifests_path);
-
         let ltr = request.into_inner();
-
         let limit = ltr.limit as usize;
-        path.push(&ltr.repo_name);
 
-        let mut catalog: Vec<String> = RepoIterator::new(&path)
-            .map_err(|e| {
-                event!(Level::ERROR, "Error accessing catalog {:?}", e);
-                Status::internal("Internal error streaming catalog")
-            })?
-            .map(|de| de.path().file_name().unwrap().to_string_lossy().to_string())
-            .collect();
-        catalog.sort();
-        let partial_catalog: Vec<String> = if ltr.last_tag.is_empty() {
-            catalog.into_iter().take(limit).collect()
-        } else {
+        #[cfg(feature = "sqlite")]
+        let catalog = self.metadata.tags(&ltr.repo_name).map_err(|e| {
+            event!(Level::ERROR, "Error accessing catalog {:?}", e);
+            Status::internal("Internal error streaming catalog")
+        })?;
+        #[cfg(not(feature = "sqlite"))]
+        let catalog = {
+            let path = self.manifests_path.join(&ltr.repo_name);
+            let mut catalog: Vec<String> = RepoIterator::new(&path)
+                .map_err(|e| {
+                    event!(Level::ERROR, "Error accessing catalog {:?}", e);
+                    Status::internal("Internal error streaming catalog")
+                })?
+                .map(|de| de.path().file_name().unwrap().to_string_lossy().to_string())
+                .collect();
+            catalog.sort();
             catalog
-                .into_iter()
-                .skip_while(|t| t != &ltr.last_tag)
-                .skip(1)
-                .take(limit)
-                .collect()
         };
+        // The tags are sorted, so this also works if `last_tag` doesn't exist
+        let partial_catalog: Vec<String> = catalog
+            .into_iter()
+            .skip_while(|t| t <= &ltr.last_tag)
+            .take(limit)
+            .collect();
 
         tokio::spawn(async move {
             for tag in partial_catalog {
@@ -1004,26 +1986,46 @@ impl Registry for TrowServer {
             ));
         }
 
-        let manifest_path = self.manifests_path.join(&mr.repo_name).join(&mr.tag);
+        #[cfg(feature = "sqlite")]
+        let lines: Vec<String> = {
+            let history = self.metadata.history(&mr.repo_name, &mr.tag).map_err(|e| {
+                event!(Level::ERROR, "Error reading manifest history {:?}", e);
+                Status::internal("Internal error reading manifest history")
+            })?;
+            if history.is_empty() {
+                return Err(Status::not_found(format!(
+                    "Could not find the requested manifest {}:{}",
+                    mr.repo_name, mr.tag
+                )));
+            }
+            history
+                .into_iter()
+                .map(|(digest, date)| format!("{} {}", digest, date))
+                .collect()
+        };
+        #[cfg(not(feature = "sqlite"))]
+        let lines = {
+            let manifest_path = self.manifests_path.join(&mr.repo_name).join(&mr.tag);
 
-        let file = File::open(&manifest_path);
+            let file = File::open(&manifest_path);
 
-        if file.is_err() {
-            return Err(Status::not_found(format!(
-                "Could not find the requested manifest at: {}",
-                &manifest_path.to_str().unwrap()
-            )));
-        }
+            if file.is_err() {
+                return Err(Status::not_found(format!(
+                    "Could not find the requested manifest at: {}",
+                    &manifest_path.to_str().unwrap()
+                )));
+            }
 
-        // It's safe to unwrap here
-        let reader = BufReader::new(file.unwrap());
+            // It's safe to unwrap here
+            BufReader::new(file.unwrap()).lines().map_while(Result::ok)
+        };
 
         let (tx, rx) = mpsc::channel(4);
         tokio::spawn(async move {
             let mut searching_for_digest = !mr.last_digest.is_empty(); //Looking for a digest iff it's not empty
 
             let mut sent = 0;
-            for line in reader.lines().flatten() {
+            for line in lines {
                 let (digest, date) = match line.find(' ') {
                     Some(ind) => {
                         let (digest_str, date_str) = line.split_at(ind);
@@ -1072,12 +2074,67 @@ impl Registry for TrowServer {
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
+    async fn get_referrers(
+        &self,
+        request: Request<ReferrersRequest>,
+    ) -> Result<Response<ReferrerList>, Status> {
+        let rr = request.into_inner();
+        if !is_digest(&rr.digest) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid digest: {}",
+                rr.digest
+            )));
+        }
+        let subject = ManifestReference {
+            repo_name: rr.repo_name,
+            digest: rr.digest,
+        };
+        let manifests = self
+            .reference_index
+            .read()
+            .unwrap()
+            .subject_referrers(&subject);
+
+        let mut referrers = vec![];
+        for manifest in manifests {
+            let size = match self.get_blob_size(&manifest.digest) {
+                Ok(size) => size,
+                Err(e) => {
+                    event!(Level::WARN, "Could not find referrer {}: {:?}", manifest, e);
+                    continue;
+                }
+            };
+            let referrer = match self.read_manifest(&manifest.digest) {
+                Ok(m) => Referrer {
+                    media_type: m.get_media_type(),
+                    digest: manifest.digest.clone(),
+                    size,
+                    artifact_type: m.get_artifact_type().unwrap_or_default().to_string(),
+                    annotations: m.get_annotations().cloned().unwrap_or_default(),
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Could not read referrer {}: {:?}", manifest, e);
+                    continue;
+                }
+            };
+            if rr.artifact_type.is_empty() || rr.artifact_type == referrer.artifact_type {
+                referrers.push(referrer);
+            }
+        }
+        Ok(Response::new(ReferrerList { referrers }))
+    }
+
     // Readiness check
     async fn is_ready(
         &self,
         _request: Request<ReadinessRequest>,
     ) -> Result<Response<ReadyStatus>, Status> {
-        for path in &[&self.scratch_path, &self.manifests_path, &self.blobs_path] {
+        for path in &[
+            &self.scratch_path,
+            &self.manifests_path,
+            &self.blobs_path,
+            &self.links_path,
+        ] {
             match is_path_writable(path) {
                 Ok(true) => {}
                 Ok(false) => {
@@ -1091,8 +2148,14 @@ impl Registry for TrowServer {
                 }
             }
         }
+        if let Err(e) = self.check_free_space() {
+            if !e.is::<LowFreeSpaceError>() {
+                event!(Level::WARN, "Failed to check free disk space: {:?}", e);
+            }
+            return Err(Status::unavailable(e.to_string()));
+        }
 
-        //All paths writable
+        //All paths writable and enough space left
         let reply = trow_server::ReadyStatus {
             message: String::from("Ready"),
         };
@@ -1114,6 +2177,8 @@ impl Registry for TrowServer {
         &self,
         _request: Request<MetricsRequest>,
     ) -> Result<Response<MetricsResponse>, Status> {
+        self.update_quota_metrics();
+        metrics::ACTIVE_UPLOADS.set(self.active_uploads.read().unwrap().len() as i64);
         match metrics::gather_metrics(&self.blobs_path) {
             Ok(metrics) => {
                 let reply = trow_server::MetricsResponse { metrics };
@@ -1123,4 +2188,337 @@ impl Registry for TrowServer {
             Err(error) => Err(Status::unavailable(error.to_string())),
         }
     }
+
+    async fn collect_garbage(
+        &self,
+        request: Request<GarbageCollectionRequest>,
+    ) -> Result<Response<GarbageCollectionReport>, Status> {
+        let dry_run = request.into_inner().dry_run;
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || server.collect_garbage(dry_run, GC_GRACE_PERIOD))
+            .await
+            .map_err(|e| {
+                event!(Level::ERROR, "Garbage collection task failed {:?}", e);
+                Status::internal("Internal error collecting garbage")
+            })?
+            .map(Response::new)
+            .map_err(|e| {
+                event!(Level::ERROR, "Error collecting garbage {:?}", e);
+                Status::internal("Internal error collecting garbage")
+            })
+    }
+
+    async fn enforce_retention(
+        &self,
+        request: Request<RetentionRequest>,
+    ) -> Result<Response<RetentionReport>, Status> {
+        let config = self
+            .retention_config
+            .clone()
+            .ok_or_else(|| Status::failed_precondition("No retention policies are configured"))?;
+        // A configuration in dry run mode never deletes anything
+        let dry_run = request.into_inner().dry_run || config.dry_run;
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || server.enforce_retention(&config, dry_run))
+            .await
+            .map_err(|e| {
+                event!(Level::ERROR, "Retention task failed {:?}", e);
+                Status::internal("Internal error enforcing retention policies")
+            })?
+            .map(Response::new)
+            .map_err(|e| {
+                event!(Level::ERROR, "Error enforcing retention policies {:?}", e);
+                Status::internal("Internal error enforcing retention policies")
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use httpmock::prelude::*;
+    use httpmock::Method::HEAD;
+    use tonic::Request;
+
+    use super::trow_server::registry_server::Registry;
+    use super::trow_server::ManifestRef;
+    use super::{ServerPolicies, TrowServer, DIGEST_HEADER};
+    use crate::digest::sha256_tag_digest;
+    use crate::free_space::LowFreeSpaceError;
+    use crate::image::RemoteImage;
+    use crate::immutable_tags::ImmutableTagsConfig;
+    use crate::{RegistryProxiesConfig, SingleRegistryProxyConfig};
+
+    const MANIFEST: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "size": 2, "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a" },
+        "layers": []
+    }"#;
+
+    /// Pulls `reference` of an image from a mock upstream through a proxy with the given `tag_ttl`,
+    /// returning how many requests were sent upstream for each pull
+    async fn count_upstream_requests(tag_ttl: u64, references: &[&str]) -> Vec<usize> {
+        let upstream = MockServer::start();
+        let digest = sha256_tag_digest(MANIFEST.as_bytes()).unwrap();
+        let mut mocks = vec![];
+        for reference in ["latest", digest.as_str()] {
+            mocks.push(upstream.mock(|when, then| {
+                when.method(HEAD)
+                    .path(format!("/v2/library/img/manifests/{}", reference));
+                then.status(200).header(DIGEST_HEADER, &digest);
+            }));
+            mocks.push(upstream.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/v2/library/img/manifests/{}", reference));
+                then.status(200).body(MANIFEST);
+            }));
+        }
+        let received_requests = || mocks.iter().map(|m| m.hits()).sum::<usize>();
+        let proxy_cfg = SingleRegistryProxyConfig {
+            alias: "mock".to_string(),
+            host: format!("http://{}", upstream.address()),
+            username: None,
+            password: None,
+            tag_ttl,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = RegistryProxiesConfig {
+            registries: vec![proxy_cfg.clone()],
+            offline: false,
+        };
+        let server = TrowServer::new(
+            dir.path().to_str().unwrap(),
+            Some(config),
+            None,
+            Default::default(),
+        )
+        .unwrap();
+
+        let mut counts = vec![];
+        for reference in references {
+            let reference = reference.replace("DIGEST", &digest);
+            let image = RemoteImage::new(&proxy_cfg.host, "library/img".into(), reference);
+            let before = received_requests();
+            let pulled = server
+                .download_remote_image(image, proxy_cfg.clone())
+                .await
+                .unwrap();
+            assert_eq!(pulled, digest);
+            counts.push(received_requests() - before);
+        }
+        counts
+    }
+
+    #[tokio::test]
+    async fn reports_low_free_space_when_proxying() {
+        let upstream = MockServer::start();
+        let digest = sha256_tag_digest(MANIFEST.as_bytes()).unwrap();
+        upstream.mock(|when, then| {
+            when.method(HEAD).path("/v2/library/img/manifests/latest");
+            then.status(200).header(DIGEST_HEADER, &digest);
+        });
+        let proxy_cfg = SingleRegistryProxyConfig {
+            alias: "mock".to_string(),
+            host: format!("http://{}", upstream.address()),
+            username: None,
+            password: None,
+            tag_ttl: 0,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = RegistryProxiesConfig {
+            registries: vec![proxy_cfg.clone()],
+            offline: false,
+        };
+        let policies = ServerPolicies {
+            min_free_space: u64::MAX,
+            ..Default::default()
+        };
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), Some(config), None, policies).unwrap();
+
+        let image = RemoteImage::new(&proxy_cfg.host, "library/img".into(), "latest".into());
+        let err = server
+            .download_remote_image(image, proxy_cfg)
+            .await
+            .unwrap_err();
+        assert!(err.is::<LowFreeSpaceError>());
+    }
+
+    #[test]
+    fn skips_unreadable_tag_files_on_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path().join("manifests/broken");
+        std::fs::create_dir_all(&repo_dir).unwrap();
+        std::fs::write(repo_dir.join("latest"), [0xff, 0xfe]).unwrap();
+
+        assert!(
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).is_ok()
+        );
+    }
+
+    #[test]
+    fn only_treats_missing_immutable_tags_as_unset() {
+        let dir = tempfile::tempdir().unwrap();
+        let policies = ServerPolicies {
+            immutable_tags: ImmutableTagsConfig {
+                patterns: vec!["*".to_string()],
+            },
+            ..Default::default()
+        };
+        let server = TrowServer::new(dir.path().to_str().unwrap(), None, None, policies).unwrap();
+        assert!(!server
+            .is_tag_move_denied("repo", "v1", "sha256:one")
+            .unwrap());
+
+        #[cfg(not(feature = "sqlite"))]
+        {
+            let repo_dir = server.manifests_path.join("repo");
+            std::fs::create_dir_all(&repo_dir).unwrap();
+            std::fs::write(repo_dir.join("v1"), [0xff, 0xfe]).unwrap();
+            assert!(server
+                .is_tag_move_denied("repo", "v1", "sha256:one")
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_nested_repos_when_deleting_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+        let digest = sha256_tag_digest(MANIFEST.as_bytes()).unwrap();
+        let blob_path = server.get_catalog_path_for_blob(&digest).unwrap();
+        std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        std::fs::write(blob_path, MANIFEST).unwrap();
+        for repo in ["outer", "outer/inner"] {
+            let repo_dir = server.manifests_path.join(repo);
+            std::fs::create_dir_all(&repo_dir).unwrap();
+            std::fs::write(
+                repo_dir.join("latest"),
+                format!("{} 2023-05-30T09:21:14.081204316Z\n", digest),
+            )
+            .unwrap();
+            server.reindex_repo(repo).unwrap();
+        }
+        let config = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+        assert_eq!(
+            server
+                .reference_index
+                .read()
+                .unwrap()
+                .referrers(config)
+                .len(),
+            2
+        );
+
+        server
+            .delete_manifest(Request::new(ManifestRef {
+                repo_name: "outer".to_string(),
+                reference: digest,
+            }))
+            .await
+            .unwrap();
+        assert!(!server.manifests_path.join("outer/latest").exists());
+        assert!(server.manifests_path.join("outer/inner/latest").exists());
+        let referrers = server.reference_index.read().unwrap().referrers(config);
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].repo_name, "outer/inner");
+    }
+
+    #[tokio::test]
+    async fn refuses_deleting_untagged_digests() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+        let digest = sha256_tag_digest(MANIFEST.as_bytes()).unwrap();
+        std::fs::create_dir_all(server.manifests_path.join("repo")).unwrap();
+
+        for repo_name in ["repo", "missing"] {
+            let status = server
+                .delete_manifest(Request::new(ManifestRef {
+                    repo_name: repo_name.to_string(),
+                    reference: digest.clone(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+        }
+    }
+
+    #[tokio::test]
+    async fn heads_proxied_manifests_without_downloading() {
+        let upstream = MockServer::start();
+        let digest = sha256_tag_digest(MANIFEST.as_bytes()).unwrap();
+        upstream.mock(|when, then| {
+            when.method(HEAD).path("/v2/library/img/manifests/latest");
+            then.status(200)
+                .header(DIGEST_HEADER, &digest)
+                .header("Content-Type", "application/vnd.oci.image.manifest.v1+json")
+                .header("Content-Length", MANIFEST.len().to_string());
+        });
+        let download = upstream.mock(|when, then| {
+            when.method(GET).path("/v2/library/img/manifests/latest");
+            then.status(200).body(MANIFEST);
+        });
+        let config = RegistryProxiesConfig {
+            registries: vec![SingleRegistryProxyConfig {
+                alias: "mock".to_string(),
+                host: format!("http://{}", upstream.address()),
+                username: None,
+                password: None,
+                tag_ttl: 0,
+            }],
+            offline: false,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let server = TrowServer::new(
+            dir.path().to_str().unwrap(),
+            Some(config),
+            None,
+            Default::default(),
+        )
+        .unwrap();
+
+        let location = server
+            .head_manifest(Request::new(ManifestRef {
+                repo_name: "f/mock/library/img".to_string(),
+                reference: "latest".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(location.digest, digest);
+        assert_eq!(location.size, MANIFEST.len() as u64);
+        assert_eq!(
+            location.content_type,
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+        assert_eq!(download.hits(), 0);
+        assert!(!server.get_catalog_path_for_blob(&digest).unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn checks_tags_upstream_without_ttl() {
+        // Auth discovery, digest HEAD and manifest download, then only the digest HEAD
+        assert_eq!(
+            count_upstream_requests(0, &["latest", "latest"]).await,
+            vec![3, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn trusts_cached_tags_within_ttl() {
+        assert_eq!(
+            count_upstream_requests(3600, &["latest", "latest", "DIGEST"]).await,
+            vec![3, 0, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_cached_digests_without_upstream_requests() {
+        assert_eq!(
+            count_upstream_requests(0, &["DIGEST", "DIGEST", "latest"]).await,
+            vec![2, 0, 1]
+        );
+    }
 }
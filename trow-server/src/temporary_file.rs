@@ -41,16 +41,19 @@ impl TemporaryFile {
         self.file.flush().await
     }
 
-    pub async fn write_stream<S>(&mut self, mut stream: S) -> Result<()>
+    /// Writes the whole stream to the file and returns the number of bytes written
+    pub async fn write_stream<S>(&mut self, mut stream: S) -> Result<u64>
     where
         S: Stream<Item = Result<Bytes, reqwest::Error>> + Unpin,
     {
+        let mut written = 0;
         while let Some(chunk) = stream.next().await {
             let chunk = chunk?;
             self.file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
         }
         self.file.flush().await?;
-        Ok(())
+        Ok(written)
     }
 
     pub fn path(&self) -> &Path {
@@ -141,7 +144,8 @@ mod test {
             .unwrap()
             .unwrap();
         let dummy_stream = futures::stream::iter(DUMMY_DATA.chunks(4).map(|b| Ok(Bytes::from(b))));
-        file.write_stream(dummy_stream).await.unwrap();
+        let written = file.write_stream(dummy_stream).await.unwrap();
+        assert_eq!(written, DUMMY_DATA.len() as u64);
         assert_eq!(fs::read(file.path()).await.unwrap(), DUMMY_DATA);
         drop(file);
     }
@@ -0,0 +1,235 @@
+//! Tracking of blob upload sessions.
+//!
+//! Uploads are written to `scratch/<uuid>` by the frontend. Each session also has a sidecar
+//! metadata file, `scratch/<uuid>.json`, so that sessions survive a restart and clients can resume
+//! a large push with PATCH or query it with `GET /v2/<name>/blobs/uploads/<uuid>`.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::metrics;
+use crate::server::TrowServer;
+
+const METADATA_EXTENSION: &str = "json";
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone)]
+pub(crate) struct Upload {
+    pub repo_name: String,
+    pub uuid: String,
+}
+
+/// Contents of the sidecar file of an upload session
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct UploadMetadata {
+    pub repo_name: String,
+    pub uuid: String,
+    /// RFC 3339 timestamp
+    pub created: String,
+    /// Bytes received, as of the last chunk
+    pub size: u64,
+}
+
+impl TrowServer {
+    pub(crate) fn get_upload_path_for_blob(&self, uuid: &str) -> PathBuf {
+        self.scratch_path.join(uuid)
+    }
+
+    pub(crate) fn get_upload_metadata_path(&self, uuid: &str) -> PathBuf {
+        self.scratch_path
+            .join(format!("{}.{}", uuid, METADATA_EXTENSION))
+    }
+
+    /// Starts tracking a new upload session
+    pub(crate) fn add_upload(&self, upload: Upload) -> Result<()> {
+        let metadata = UploadMetadata {
+            repo_name: upload.repo_name.clone(),
+            uuid: upload.uuid.clone(),
+            created: Utc::now().to_rfc3339(),
+            size: 0,
+        };
+        self.write_upload_metadata(&metadata)?;
+        self.active_uploads.write().unwrap().insert(upload);
+        event!(Level::DEBUG, "Upload Table: {:?}", self.active_uploads);
+        Ok(())
+    }
+
+    pub(crate) fn has_upload(&self, upload: &Upload) -> bool {
+        self.active_uploads.read().unwrap().contains(upload)
+    }
+
+    /// Returns the number of bytes received so far for the session
+    pub(crate) fn get_upload_size(&self, upload: &Upload) -> Result<u64> {
+        // Nothing has been written yet if the scratch file doesn't exist
+        match fs::metadata(self.get_upload_path_for_blob(&upload.uuid)) {
+            Ok(md) => Ok(md.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records the number of bytes received so far in the sidecar, once a chunk has been written.
+    /// The sidecar is recreated if it went missing.
+    pub(crate) fn record_upload_size(&self, upload: &Upload, size: u64) -> Result<()> {
+        let mut metadata = match self.read_upload_metadata(&upload.uuid) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Recreating metadata of upload {}: {:?}",
+                    upload.uuid,
+                    e
+                );
+                UploadMetadata {
+                    repo_name: upload.repo_name.clone(),
+                    uuid: upload.uuid.clone(),
+                    created: Utc::now().to_rfc3339(),
+                    size,
+                }
+            }
+        };
+        metadata.size = size;
+        self.write_upload_metadata(&metadata)
+    }
+
+    /// Stops tracking the session. Returns false if it wasn't tracked.
+    ///
+    /// The uploaded data is left alone; it is either moved to the blob store or deleted by the
+    /// caller.
+    pub(crate) fn remove_upload(&self, upload: &Upload) -> bool {
+        let removed = self.active_uploads.write().unwrap().remove(upload);
+        let path = self.get_upload_metadata_path(&upload.uuid);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                event!(Level::WARN, "Failed to remove {:?}: {:?}", path, e);
+            }
+        }
+        removed
+    }
+
+    /// Reloads the upload sessions that were in progress when the server was last stopped
+    pub(crate) fn restore_uploads(&self) -> Result<()> {
+        let mut uploads = self.active_uploads.write().unwrap();
+        for entry in fs::read_dir(&self.scratch_path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(METADATA_EXTENSION) {
+                continue;
+            }
+            let metadata: UploadMetadata = match fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|b| Ok(serde_json::from_slice(&b)?))
+            {
+                Ok(m) => m,
+                Err(e) => {
+                    event!(Level::WARN, "Ignoring invalid upload {:?}: {:?}", path, e);
+                    continue;
+                }
+            };
+            event!(
+                Level::DEBUG,
+                "Restoring upload {} to {}",
+                metadata.uuid,
+                metadata.repo_name
+            );
+            uploads.insert(Upload {
+                repo_name: metadata.repo_name,
+                uuid: metadata.uuid,
+            });
+        }
+        Ok(())
+    }
+
+    /// Records the duration and size of a session whose blob was saved, before it is removed
+    pub(crate) fn record_completed_upload(&self, upload: &Upload, size: u64) {
+        metrics::BYTES_UPLOADED
+            .with_label_values(&[&metrics::repo_label(&upload.repo_name)])
+            .inc_by(size);
+        let created = self
+            .read_upload_metadata(&upload.uuid)
+            .and_then(|m| Ok(DateTime::parse_from_rfc3339(&m.created)?));
+        match created {
+            Ok(created) => {
+                let duration = Utc::now().signed_duration_since(created);
+                metrics::UPLOAD_DURATION
+                    .observe(duration.num_milliseconds().max(0) as f64 / 1000.0);
+            }
+            Err(e) => event!(
+                Level::WARN,
+                "Failed to read start of upload {}: {:?}",
+                upload.uuid,
+                e
+            ),
+        }
+    }
+
+    fn read_upload_metadata(&self, uuid: &str) -> Result<UploadMetadata> {
+        let path = self.get_upload_metadata_path(uuid);
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    fn write_upload_metadata(&self, metadata: &UploadMetadata) -> Result<()> {
+        let path = self.get_upload_metadata_path(&metadata.uuid);
+        fs::write(path, serde_json::to_vec(metadata)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::Upload;
+    use crate::server::TrowServer;
+
+    #[test]
+    fn uploads_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().to_str().unwrap();
+        let server = TrowServer::new(data_path, None, None, Default::default()).unwrap();
+
+        let upload = Upload {
+            repo_name: "my/repo".to_string(),
+            uuid: "some-uuid".to_string(),
+        };
+        server.add_upload(upload.clone()).unwrap();
+        assert_eq!(server.get_upload_size(&upload).unwrap(), 0);
+        fs::write(server.get_upload_path_for_blob(&upload.uuid), b"12345").unwrap();
+
+        let restarted = TrowServer::new(data_path, None, None, Default::default()).unwrap();
+        assert!(restarted.has_upload(&upload));
+        assert_eq!(restarted.get_upload_size(&upload).unwrap(), 5);
+
+        assert!(restarted.remove_upload(&upload));
+        assert!(!restarted.remove_upload(&upload));
+        let restarted = TrowServer::new(data_path, None, None, Default::default()).unwrap();
+        assert!(!restarted.has_upload(&upload));
+    }
+
+    #[test]
+    fn records_progress_in_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let server =
+            TrowServer::new(dir.path().to_str().unwrap(), None, None, Default::default()).unwrap();
+
+        let upload = Upload {
+            repo_name: "my/repo".to_string(),
+            uuid: "some-uuid".to_string(),
+        };
+        server.add_upload(upload.clone()).unwrap();
+        fs::write(server.get_upload_path_for_blob(&upload.uuid), b"12345").unwrap();
+
+        // The status comes from the data, without touching the sidecar
+        let sidecar = server.get_upload_metadata_path(&upload.uuid);
+        fs::remove_file(&sidecar).unwrap();
+        assert_eq!(server.get_upload_size(&upload).unwrap(), 5);
+        assert!(!sidecar.exists());
+
+        server.record_upload_size(&upload, 5).unwrap();
+        assert_eq!(server.read_upload_metadata(&upload.uuid).unwrap().size, 5);
+    }
+}